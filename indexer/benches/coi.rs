@@ -0,0 +1,119 @@
+//! Benchmarks of computing the COI of trout deep in their pedigrees. Run with `cargo bench`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use algo::Ancestors;
+use rand::Rng as _;
+
+#[allow(dead_code)]
+#[path = "../src/nftrout/algo.rs"]
+mod algo;
+
+/// Stands in for the indexer's own `TroutId`, on which the COI computation depends.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct TroutId {
+    pub chain_id: u32,
+    pub token_id: u32,
+}
+
+/// Runs `f` repeatedly for about a second and prints the mean time taken by each run.
+fn bench<T>(name: &str, mut f: impl FnMut() -> T) {
+    let start = Instant::now();
+    let mut runs = 0;
+    while runs == 0 || start.elapsed() < Duration::from_secs(1) {
+        black_box(f());
+        runs += 1;
+    }
+    println!("{name}: {:?}/iter over {runs} runs", start.elapsed() / runs);
+}
+
+fn trout(token_id: u32) -> TroutId {
+    TroutId {
+        chain_id: 31337,
+        token_id,
+    }
+}
+
+/// Makes a pedigree from `(child, left, right)` trios.
+fn pedigree(trios: impl Iterator<Item = (u32, u32, u32)>) -> Ancestors {
+    let mut g = Ancestors::default();
+    for (child, left, right) in trios {
+        g.add_edge(trout(child), trout(left));
+        g.add_edge(trout(child), trout(right));
+    }
+    g
+}
+
+/// Each generation is the offspring of the previous generation's pair of full siblings.
+fn sibling_mating_pedigree(generations: u32) -> Ancestors {
+    pedigree((1..generations).flat_map(|gen| {
+        let (left, right) = (2 * gen - 1, 2 * gen);
+        [(2 * gen + 1, left, right), (2 * gen + 2, left, right)]
+    }))
+}
+
+fn deep_sibling_mating() {
+    let generations = 10_000;
+    let g = sibling_mating_pedigree(generations);
+    bench("deep sibling mating", || {
+        let mut g = g.clone();
+        let coi = g.inbreeding(trout(2 * generations));
+        assert!(coi > 0.99);
+    });
+}
+
+fn deep_random_population() {
+    // A population of 8 where each new trout has parents among the 8 most recent.
+    let (width, generations) = (8u32, 10_000u32);
+    let mut rng = rand::thread_rng();
+    let g = pedigree(((width + 1)..=(width * generations)).map(|child| {
+        let window = (child - width)..child;
+        let left = rng.gen_range(window.clone());
+        let mut right = rng.gen_range(window.clone());
+        while right == left {
+            right = rng.gen_range(window.clone());
+        }
+        (child, left, right)
+    }));
+    bench("deep random population", || {
+        let mut g = g.clone();
+        let coi = g.inbreeding(trout(width * generations));
+        assert!((0.0..1.0).contains(&coi));
+    });
+}
+
+fn incremental() {
+    // Adding a child to a pedigree whose kinships are already cached is cheap.
+    let generations = 10_000;
+    let mut g = sibling_mating_pedigree(generations);
+    g.inbreeding(trout(2 * generations));
+    let (left, right) = (trout(2 * generations - 1), trout(2 * generations));
+    bench("incremental", || {
+        let mut g = g.clone();
+        let child = trout(2 * generations + 1);
+        g.add_edge(child, left);
+        g.add_edge(child, right);
+        g.inbreeding(child)
+    });
+}
+
+fn main() {
+    deep_sibling_mating();
+    deep_random_population();
+    incremental();
+}
@@ -1,25 +1,76 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+};
 
 use axum::{
     body::Body,
     extract::{Path, Query, State},
     http::{Method, StatusCode},
-    response::{IntoResponse, Response},
+    response::{
+        sse::{self, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Json, Router,
 };
+use futures::Stream;
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::broadcast;
 use tower_http::cors;
 
 use crate::{
+    db::{TokenCursor, TokenQuery, TokenSort},
     ipfs::Cid,
-    nftrout::{ChainId, EventForUi, TokenForUi, TokenId, TroutId},
+    nftrout::{
+        algo::{Ancestors, Coancestry, View},
+        BreedingQuote, ChainId, ContractEventForUi, ContractState, EventForUi, Generation,
+        MarketStats, NotListed, OwnerEarnings, TokenForUi, TokenId, TokenLifecycle, TroutId,
+    },
 };
 
+/// The default and maximum number of events in a page of activity.
+const ACTIVITY_PAGE_SIZE: u32 = 100;
+
+const DEFAULT_TOP_STUDS: usize = 10;
+const MAX_TOP_STUDS: usize = 100;
+
+/// The default and maximum number of breeding matches returned.
+const MATCHES_PAGE_SIZE: usize = 100;
+
 #[derive(Clone)]
 struct AppState {
     db: crate::db::Db,
     ipfs: crate::ipfs::Client,
-    nftrout: crate::nftrout::Client,
+    chains: Arc<HashMap<ChainId, ChainState>>,
+}
+
+/// The per-chain state shared with the indexer.
+#[derive(Clone)]
+pub struct ChainState {
+    pub nftrout: crate::nftrout::Client,
+    pub ancestors: Arc<RwLock<Ancestors>>,
+    /// Marketplace activity, published by the indexer as new blocks are recorded.
+    pub activity: broadcast::Sender<EventForUi>,
+    /// The contract's state as last read from the chain, along with the block at which it was
+    /// read, so that it is read at most once per indexed block.
+    pub contract_state: Arc<Mutex<Option<(u64, ContractState)>>>,
+}
+
+impl ChainState {
+    /// Runs `f` on a read-only view of the pedigree on the blocking thread pool, since computing
+    /// kinships of trout that have not been compared before can take a while.
+    async fn with_ancestors<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(View) -> T + Send + 'static,
+    ) -> T {
+        let ancestors = self.ancestors.clone();
+        tokio::task::spawn_blocking(move || f(ancestors.read().view()))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
 }
 
 #[derive(Debug)]
@@ -41,14 +92,17 @@ impl IntoResponse for Error {
 pub async fn serve(
     db: crate::db::Db,
     ipfs: crate::ipfs::Client,
-    nftrout: crate::nftrout::Client,
+    chains: HashMap<ChainId, ChainState>,
     port: u16,
 ) {
     let bind_addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
     let listener = tokio::net::TcpListener::bind(bind_addr).await.unwrap();
-    axum::serve(listener, make_router(AppState { db, ipfs, nftrout }))
-        .await
-        .unwrap();
+    let state = AppState {
+        db,
+        ipfs,
+        chains: Arc::new(chains),
+    };
+    axum::serve(listener, make_router(state)).await.unwrap();
 }
 
 fn make_router(state: AppState) -> Router {
@@ -56,10 +110,27 @@ fn make_router(state: AppState) -> Router {
         .route("/", get(root))
         .route("/ipfs/*cid", get(get_ipfs_cid))
         .route("/trout/:chain/", get(list_chain_trout))
+        .route("/trout/:chain/coi", get(get_predicted_coi))
+        .route("/trout/:chain/waiting", get(list_waiting_trout))
+        .route("/trout/:chain/quote", get(get_breeding_quote))
         .route("/trout/:chain/:id/metadata.json", get(get_trout_metadata))
         .route("/trout/:chain/:id/image.svg", get(get_trout_image))
+        .route("/trout/:chain/:id/generations", get(get_trout_generations))
+        .route(
+            "/trout/:chain/:id/v/:ord/image.svg",
+            get(get_trout_generation_image),
+        )
         .route("/trout/:chain/:id/events", get(get_trout_events))
+        .route("/trout/:chain/:id/lifecycle", get(get_trout_lifecycle))
+        .route("/trout/:chain/:id/matches", get(get_trout_matches))
         .route("/trout/:chain/:id/name", post(set_trout_name))
+        .route("/owners/:chain/:address/trout", get(get_owner_trout))
+        .route("/owners/:chain/:address/earnings", get(get_owner_earnings))
+        .route("/activity/:chain", get(get_activity))
+        .route("/activity/:chain/live", get(stream_activity))
+        .route("/stats/:chain", get(get_market_stats))
+        .route("/contract/:chain/history", get(get_contract_history))
+        .route("/contract/:chain/state", get(get_contract_state))
         .layer(tower_http::trace::TraceLayer::new_for_http())
         .layer(
             tower_http::compression::CompressionLayer::new()
@@ -101,6 +172,7 @@ async fn get_trout_metadata(
         "metadata.json",
         "application/json",
         TroutId { chain_id, token_id },
+        None,
         &db,
         &ipfs,
     )
@@ -115,20 +187,50 @@ async fn get_trout_image(
         "image/trout.svg",
         "image/svg+xml",
         TroutId { chain_id, token_id },
+        None,
+        &db,
+        &ipfs,
+    )
+    .await
+}
+
+async fn get_trout_generation_image(
+    Path((chain_id, token_id, ord)): Path<(ChainId, TokenId, u32)>,
+    State(AppState { db, ipfs, .. }): State<AppState>,
+) -> Result<Result<Response, StatusCode>, Error> {
+    get_trout_ipfs_content(
+        "image/trout.svg",
+        "image/svg+xml",
+        TroutId { chain_id, token_id },
+        Some(ord),
         &db,
         &ipfs,
     )
     .await
 }
 
+async fn get_trout_generations(
+    Path((chain_id, token_id)): Path<(ChainId, TokenId)>,
+    State(AppState { db, .. }): State<AppState>,
+) -> Result<Result<Json<TroutGenerationsResponse>, StatusCode>, Error> {
+    let result = db
+        .read(move |conn| conn.token_generations(TroutId { chain_id, token_id }))
+        .await?;
+    if result.is_empty() {
+        return Ok(Err(StatusCode::NOT_FOUND));
+    }
+    Ok(Ok(Json(TroutGenerationsResponse { result })))
+}
+
 async fn get_trout_ipfs_content(
     path: &'static str,
     content_type: &'static str,
     trout: TroutId,
+    ord: Option<u32>,
     db: &crate::db::Db,
     ipfs: &crate::ipfs::Client,
 ) -> Result<Result<Response, StatusCode>, Error> {
-    let cid = match db.with_conn(|conn| conn.token_cid(&trout, None))? {
+    let cid = match db.read(move |conn| conn.token_cid(&trout, ord)).await? {
         Some(cid) => cid,
         None => return Ok(Err(StatusCode::NOT_FOUND)),
     };
@@ -142,57 +244,785 @@ async fn get_trout_ipfs_content(
 
 async fn get_trout_events(
     Path((chain_id, token_id)): Path<(ChainId, TokenId)>,
+    Query(TroutEventsQuery { cursor, limit }): Query<TroutEventsQuery>,
     State(AppState { db, .. }): State<AppState>,
-) -> Result<Json<TroutEventsResponse>, Error> {
-    Ok(Json(TroutEventsResponse {
-        result: db.with_conn(|conn| conn.token_events(TroutId { chain_id, token_id }))?,
+) -> Result<Result<Json<TroutEventsResponse>, StatusCode>, Error> {
+    let after = match cursor.map(|cursor| cursor.parse()).transpose() {
+        Ok(after) => after,
+        Err(()) => return Ok(Err(StatusCode::BAD_REQUEST)),
+    };
+    let (result, next) = db
+        .read(move |conn| conn.token_events(TroutId { chain_id, token_id }, after, limit))
+        .await?;
+    Ok(Ok(Json(TroutEventsResponse {
+        result,
+        next: next.map(|cursor| cursor.to_string()),
+    })))
+}
+
+async fn get_trout_lifecycle(
+    Path((chain_id, token_id)): Path<(ChainId, TokenId)>,
+    State(AppState { db, .. }): State<AppState>,
+) -> Result<Result<Json<TroutLifecycleResponse>, StatusCode>, Error> {
+    let lifecycle = db
+        .read(move |conn| conn.token_lifecycle(TroutId { chain_id, token_id }))
+        .await?;
+    Ok(match lifecycle {
+        Some(result) => Ok(Json(TroutLifecycleResponse { result })),
+        None => Err(StatusCode::NOT_FOUND),
+    })
+}
+
+async fn list_waiting_trout(
+    Path(chain_id): Path<ChainId>,
+    State(AppState { db, .. }): State<AppState>,
+) -> Result<Json<WaitingTroutResponse>, Error> {
+    Ok(Json(WaitingTroutResponse {
+        result: db.read(move |conn| conn.waiting_tokens(chain_id)).await?,
     }))
 }
 
 async fn set_trout_name(
     Path((chain_id, token_id)): Path<(ChainId, TokenId)>,
-    State(AppState { db, nftrout, .. }): State<AppState>,
+    State(AppState { db, chains, .. }): State<AppState>,
     Json(SetTroutParams {
         name,
         sig: sig_bytes,
     }): Json<SetTroutParams>,
 ) -> Result<Result<StatusCode, StatusCode>, Error> {
+    let nftrout = match chains.get(&chain_id) {
+        Some(chain) => &chain.nftrout,
+        None => return Ok(Err(StatusCode::NOT_FOUND)),
+    };
     let current_owner = nftrout.owner(token_id).await?;
     let sig = sig_bytes.as_ref().try_into()?;
-    if !crate::nftrout::names::NameRequest::new(token_id, name.clone()).verify(&sig, current_owner)
-    {
+    if !crate::nftrout::names::NameRequest::new(token_id, name.clone()).verify(
+        chain_id,
+        &sig,
+        current_owner,
+    ) {
         return Ok(Err(StatusCode::FORBIDDEN));
     }
-    db.with_conn(|conn| conn.set_token_name(TroutId { chain_id, token_id }, &name))?;
+    db.write(move |tx| tx.set_token_name(TroutId { chain_id, token_id }, &name))
+        .await?;
     Ok(Ok(StatusCode::NO_CONTENT))
 }
 
 async fn list_chain_trout(
     Path(chain_id): Path<ChainId>,
-    Query(_qp): Query<ListTroutQuery>,
+    Query(qp): Query<ListTroutQuery>,
+    State(AppState { db, .. }): State<AppState>,
+) -> Result<Result<Json<ListTroutResponse>, StatusCode>, Error> {
+    let after = match qp.cursor {
+        Some(cursor) => match TokenCursor::parse(qp.sort, &cursor) {
+            Some(after) => Some(after),
+            None => return Ok(Err(StatusCode::BAD_REQUEST)),
+        },
+        None => None,
+    };
+    let query = TokenQuery {
+        owner: qp.owner,
+        listed: qp.listed,
+        min_fee: qp.min_fee,
+        max_fee: qp.max_fee,
+        min_coi: qp.min_coi,
+        max_coi: qp.max_coi,
+        genesis: qp.genesis,
+        santa: qp.santa,
+        parent: qp.parent,
+        name: qp.name,
+        sort: qp.sort,
+        after,
+        limit: qp.limit,
+    };
+    let (result, next) = db
+        .read(move |conn| conn.query_tokens_for_ui(chain_id, &query))
+        .await?;
+    Ok(Ok(Json(ListTroutResponse {
+        result,
+        next: next.map(|cursor| cursor.to_string()),
+    })))
+}
+
+async fn get_owner_trout(
+    Path((chain_id, owner)): Path<(ChainId, ethers::types::Address)>,
+    State(AppState { db, .. }): State<AppState>,
+) -> Result<Json<OwnerTroutResponse>, Error> {
+    let query = TokenQuery {
+        owner: Some(owner),
+        ..Default::default()
+    };
+    let (trout, earnings) = db
+        .read(move |conn| {
+            let (trout, _) = conn.query_tokens_for_ui(chain_id, &query)?;
+            Ok((trout, conn.stud_earnings(chain_id, &owner)?))
+        })
+        .await?;
+    Ok(Json(OwnerTroutResponse {
+        result: OwnerTrout {
+            count: trout.len(),
+            listed: trout.iter().filter(|t| t.fee.is_some()).count(),
+            earnings,
+            trout,
+        },
+    }))
+}
+
+async fn get_owner_earnings(
+    Path((chain_id, owner)): Path<(ChainId, ethers::types::Address)>,
+    State(AppState { db, .. }): State<AppState>,
+) -> Result<Json<OwnerEarningsResponse>, Error> {
+    let result = db
+        .read(move |conn| {
+            Ok(OwnerEarnings {
+                accrued: conn.stud_earnings(chain_id, &owner)?,
+                reconciliation: conn.earnings_reconciliation(chain_id, &owner)?,
+                payments: conn.stud_payments(chain_id, &owner)?,
+            })
+        })
+        .await?;
+    Ok(Json(OwnerEarningsResponse { result }))
+}
+
+async fn get_activity(
+    Path(chain_id): Path<ChainId>,
+    Query(ActivityQuery { cursor, limit }): Query<ActivityQuery>,
+    State(AppState { db, chains, .. }): State<AppState>,
+) -> Result<Result<Json<ActivityResponse>, StatusCode>, Error> {
+    if !chains.contains_key(&chain_id) {
+        return Ok(Err(StatusCode::NOT_FOUND));
+    }
+    let before = match cursor.map(|cursor| cursor.parse()).transpose() {
+        Ok(before) => before,
+        Err(()) => return Ok(Err(StatusCode::BAD_REQUEST)),
+    };
+    let limit = limit.unwrap_or(ACTIVITY_PAGE_SIZE).min(ACTIVITY_PAGE_SIZE);
+    let (result, next) = db
+        .read(move |conn| conn.activity(chain_id, before, limit))
+        .await?;
+    Ok(Ok(Json(ActivityResponse {
+        result,
+        next: next.map(|cursor| cursor.to_string()),
+    })))
+}
+
+/// Streams new marketplace activity as server-sent events, each holding an [`EventForUi`].
+/// A `lagged` event is sent if the client fell behind and should refetch `/activity/:chain`.
+async fn stream_activity(
+    Path(chain_id): Path<ChainId>,
+    State(AppState { chains, .. }): State<AppState>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, StatusCode> {
+    let mut rx = chains
+        .get(&chain_id)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .activity
+        .subscribe();
+    let stream = async_stream::stream! {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => sse::Event::default()
+                    .json_data(event)
+                    .expect("activity is serializable"),
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    sse::Event::default().event("lagged").data("")
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            yield Ok(event);
+        }
+    };
+    Ok(Sse::new(stream).keep_alive(sse::KeepAlive::default()))
+}
+
+async fn get_market_stats(
+    Path(chain_id): Path<ChainId>,
+    Query(MarketStatsQuery { window, top }): Query<MarketStatsQuery>,
+    State(AppState { db, chains, .. }): State<AppState>,
+) -> Result<Result<Json<MarketStatsResponse>, StatusCode>, Error> {
+    if !chains.contains_key(&chain_id) {
+        return Ok(Err(StatusCode::NOT_FOUND));
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let since = match window {
+        StatsWindow::Day => Some(now - 24 * 60 * 60),
+        StatsWindow::Week => Some(now - 7 * 24 * 60 * 60),
+        StatsWindow::All => None,
+    };
+    let top = top.unwrap_or(DEFAULT_TOP_STUDS).min(MAX_TOP_STUDS);
+    Ok(Ok(Json(MarketStatsResponse {
+        result: db
+            .read(move |conn| conn.market_stats(chain_id, since, top))
+            .await?,
+    })))
+}
+
+async fn get_contract_history(
+    Path(chain_id): Path<ChainId>,
     State(AppState { db, .. }): State<AppState>,
-) -> Result<Json<ListTroutResponse>, Error> {
-    Ok(Json(ListTroutResponse {
-        result: db.with_conn(|conn| conn.list_tokens_for_ui(chain_id))?,
+) -> Result<Json<ContractHistoryResponse>, Error> {
+    Ok(Json(ContractHistoryResponse {
+        result: db.read(move |conn| conn.contract_events(chain_id)).await?,
     }))
 }
 
+/// Returns the contract's state as of the latest indexed block. It is taken from the indexed
+/// configuration changes if every part has changed since deployment, and is otherwise read from
+/// the contract.
+async fn get_contract_state(
+    Path(chain_id): Path<ChainId>,
+    State(AppState { db, chains, .. }): State<AppState>,
+) -> Result<Result<Json<ContractStateResponse>, StatusCode>, Error> {
+    let chain = match chains.get(&chain_id) {
+        Some(chain) => chain,
+        None => return Ok(Err(StatusCode::NOT_FOUND)),
+    };
+    let (block, changes) = db
+        .read(move |conn| {
+            Ok((
+                conn.latest_processed_block(chain_id)?,
+                conn.latest_contract_changes(chain_id)?,
+            ))
+        })
+        .await?;
+    if let Some(state) = ContractState::from_changes(&changes) {
+        return Ok(Ok(Json(ContractStateResponse { result: state })));
+    }
+    let cached = chain.contract_state.lock().clone();
+    let state = match cached {
+        Some((cached_block, state)) if cached_block == block => state,
+        _ => {
+            let state = chain.nftrout.at_block(block).contract_state().await?;
+            *chain.contract_state.lock() = Some((block, state.clone()));
+            state
+        }
+    };
+    Ok(Ok(Json(ContractStateResponse { result: state })))
+}
+
+async fn get_predicted_coi(
+    Path(chain_id): Path<ChainId>,
+    Query(PredictedCoiQuery { left, right }): Query<PredictedCoiQuery>,
+    State(AppState { chains, .. }): State<AppState>,
+) -> Result<Json<PredictedCoiResponse>, StatusCode> {
+    let chain = chains.get(&chain_id).ok_or(StatusCode::NOT_FOUND)?;
+    let (left, right) = (
+        TroutId {
+            chain_id,
+            token_id: left,
+        },
+        TroutId {
+            chain_id,
+            token_id: right,
+        },
+    );
+    let coancestry = chain
+        .with_ancestors(move |mut g| {
+            let known = g.contains_node(left) && g.contains_node(right);
+            known.then(|| g.coancestry(left, right))
+        })
+        .await;
+    Ok(Json(PredictedCoiResponse {
+        result: coancestry.ok_or(StatusCode::NOT_FOUND)?,
+    }))
+}
+
+async fn get_breeding_quote(
+    Path(chain_id): Path<ChainId>,
+    Query(BreedingQuoteQuery {
+        breeder,
+        left,
+        right,
+        check,
+    }): Query<BreedingQuoteQuery>,
+    State(AppState { db, chains, .. }): State<AppState>,
+) -> Result<Result<Json<BreedingQuoteResponse>, StatusCode>, Error> {
+    let nftrout = match chains.get(&chain_id) {
+        Some(chain) => &chain.nftrout,
+        None => return Ok(Err(StatusCode::NOT_FOUND)),
+    };
+    if left == right {
+        return Ok(Err(StatusCode::BAD_REQUEST));
+    }
+    let (left_id, right_id) = (
+        TroutId {
+            chain_id,
+            token_id: left,
+        },
+        TroutId {
+            chain_id,
+            token_id: right,
+        },
+    );
+    let (block, left_terms, right_terms, (mint_reward, matchmaking_bps)) = db
+        .read(move |conn| {
+            Ok((
+                conn.latest_processed_block(chain_id)?,
+                conn.stud_terms(left_id)?,
+                conn.stud_terms(right_id)?,
+                conn.breeding_rates(chain_id)?,
+            ))
+        })
+        .await?;
+    let (Some((left_owner, left_fee)), Some((right_owner, right_fee))) = (left_terms, right_terms)
+    else {
+        return Ok(Err(StatusCode::NOT_FOUND));
+    };
+    // The indexed terms are as of the processed block, so the contract is read at that block too.
+    let nftrout = nftrout.at_block(block);
+    // The rates set at deployment are not evented, so they are read from the contract instead.
+    let mint_reward = match mint_reward {
+        Some(reward) => reward,
+        None => nftrout.mint_reward().await?,
+    };
+    let matchmaking_bps = match matchmaking_bps {
+        Some(bps) => bps,
+        None => nftrout.matchmaking_bps().await?,
+    };
+    let mut quote = match BreedingQuote::new(
+        breeder,
+        (left_id, left_owner, left_fee),
+        (right_id, right_owner, right_fee),
+        mint_reward,
+        matchmaking_bps,
+    ) {
+        Ok(quote) => quote,
+        Err(NotListed(_)) => return Ok(Err(StatusCode::UNPROCESSABLE_ENTITY)),
+    };
+    if check {
+        quote.onchain_total = Some(nftrout.breeding_fee(breeder, left, right).await?);
+    }
+    Ok(Ok(Json(BreedingQuoteResponse { result: quote })))
+}
+
+async fn get_trout_matches(
+    Path((chain_id, token_id)): Path<(ChainId, TokenId)>,
+    Query(MatchesQuery {
+        max_fee,
+        max_coi,
+        limit,
+    }): Query<MatchesQuery>,
+    State(AppState { db, chains, .. }): State<AppState>,
+) -> Result<Result<Json<MatchesResponse>, StatusCode>, Error> {
+    let chain = match chains.get(&chain_id) {
+        Some(chain) => chain,
+        None => return Ok(Err(StatusCode::NOT_FOUND)),
+    };
+    let trout = TroutId { chain_id, token_id };
+    let studs: Vec<_> = db
+        .read(move |conn| {
+            let query = TokenQuery {
+                listed: Some(true),
+                max_fee,
+                ..Default::default()
+            };
+            conn.query_tokens_for_ui(chain_id, &query)
+        })
+        .await?
+        .0
+        .into_iter()
+        .filter(|t| t.id != token_id && !t.pending)
+        .collect();
+    let matches = chain
+        .with_ancestors(move |mut g| {
+            if !g.contains_node(trout) {
+                return None;
+            }
+            let matches = studs.into_iter().filter_map(|stud| {
+                let stud_id = TroutId {
+                    chain_id,
+                    token_id: stud.id,
+                };
+                if !g.contains_node(stud_id) {
+                    return None;
+                }
+                let coi = g.kinship(trout, stud_id);
+                (coi <= max_coi.unwrap_or(f64::INFINITY)).then_some(StudMatch { stud, coi })
+            });
+            Some(matches.collect::<Vec<_>>())
+        })
+        .await;
+    let Some(mut matches) = matches else {
+        return Ok(Err(StatusCode::NOT_FOUND));
+    };
+    matches.sort_by(|a, b| {
+        a.coi
+            .total_cmp(&b.coi)
+            .then_with(|| a.stud.fee.cmp(&b.stud.fee))
+            .then_with(|| a.stud.id.cmp(&b.stud.id))
+    });
+    matches.truncate(limit.unwrap_or(MATCHES_PAGE_SIZE).min(MATCHES_PAGE_SIZE));
+    Ok(Ok(Json(MatchesResponse { result: matches })))
+}
+
 #[derive(Clone, Debug, serde::Deserialize)]
 struct SetTroutParams {
     name: String,
     sig: ethers::types::Bytes,
 }
 
-#[derive(Clone, Copy, Debug, Default, serde::Deserialize)]
+#[derive(Clone, Debug, Default, serde::Deserialize)]
 #[serde(default)]
-struct ListTroutQuery {}
+struct ListTroutQuery {
+    owner: Option<ethers::types::Address>,
+    listed: Option<bool>,
+    #[serde(deserialize_with = "deserialize_fee")]
+    min_fee: Option<ethers::types::U256>,
+    #[serde(deserialize_with = "deserialize_fee")]
+    max_fee: Option<ethers::types::U256>,
+    min_coi: Option<f64>,
+    max_coi: Option<f64>,
+    genesis: Option<bool>,
+    santa: Option<bool>,
+    parent: Option<TokenId>,
+    name: Option<String>,
+    sort: TokenSort,
+    /// The `next` cursor of the previous page.
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
 
 #[derive(Clone, Debug, Default, serde::Serialize)]
 struct ListTroutResponse {
     result: Vec<TokenForUi>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct TroutGenerationsResponse {
+    result: Vec<Generation>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct TroutLifecycleResponse {
+    result: TokenLifecycle,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+struct WaitingTroutResponse {
+    result: Vec<TokenLifecycle>,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+struct ContractHistoryResponse {
+    result: Vec<ContractEventForUi>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct ContractStateResponse {
+    result: ContractState,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+struct OwnerTroutResponse {
+    result: OwnerTrout,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+struct OwnerEarningsResponse {
+    result: OwnerEarnings,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+struct OwnerTrout {
+    trout: Vec<TokenForUi>,
+    count: usize,
+    listed: usize,
+    /// The total stud fees paid to the owner by other breeders.
+    earnings: ethers::types::U256,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+struct BreedingQuoteQuery {
+    breeder: ethers::types::Address,
+    left: TokenId,
+    right: TokenId,
+    /// Also fetches the contract's quote at the indexed block, against which to check this one.
+    #[serde(default)]
+    check: bool,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct BreedingQuoteResponse {
+    result: BreedingQuote,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+struct PredictedCoiQuery {
+    left: TokenId,
+    right: TokenId,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+struct PredictedCoiResponse {
+    result: Coancestry,
+}
+
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct MatchesQuery {
+    #[serde(deserialize_with = "deserialize_fee")]
+    max_fee: Option<ethers::types::U256>,
+    max_coi: Option<f64>,
+    limit: Option<usize>,
+}
+
+/// Parses an amount of wei given as either a decimal number or `0x`-prefixed hex, which is how
+/// amounts are returned but is awkward to write by hand.
+fn deserialize_fee<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ethers::types::U256>, D::Error> {
+    use ethers::types::U256;
+    use serde::{de::Error as _, Deserialize as _};
+
+    let Some(fee) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let fee = match fee.strip_prefix("0x") {
+        Some(hex) => U256::from_str_radix(hex, 16).map_err(D::Error::custom),
+        None => U256::from_dec_str(&fee).map_err(D::Error::custom),
+    };
+    fee.map(Some)
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+struct MatchesResponse {
+    result: Vec<StudMatch>,
+}
+
+/// A listed stud and the predicted COI of its offspring with the requested trout.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+struct StudMatch {
+    stud: TokenForUi,
+    coi: f64,
 }
 
 #[derive(Clone, Debug, Default, serde::Serialize)]
 struct TroutEventsResponse {
     result: Vec<EventForUi>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct MarketStatsQuery {
+    window: StatsWindow,
+    /// The number of top earning studs to return.
+    top: Option<usize>,
+}
+
+/// The period over which breeds, volume, and earnings are counted.
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize)]
+enum StatsWindow {
+    #[serde(rename = "24h")]
+    Day,
+    #[serde(rename = "7d")]
+    Week,
+    #[default]
+    #[serde(rename = "all")]
+    All,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct MarketStatsResponse {
+    result: MarketStats,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct ActivityQuery {
+    /// The `next` cursor of the previous page.
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+struct ActivityResponse {
+    result: Vec<EventForUi>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct TroutEventsQuery {
+    /// The `next` cursor of the previous page.
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::db::Db;
+
+    #[tokio::test]
+    async fn matches_ranked_by_coi_and_fee() {
+        let config = crate::conf::Chain::Local.config();
+        let chain = config.chain_id;
+        let trout = |token_id| TroutId {
+            chain_id: chain,
+            token_id,
+        };
+        // 4 is a full sibling of 3 and 6 a half sibling, while 5 and 7 are unrelated founders.
+        // The parents 1 and 2 are not listed.
+        let pedigree = [
+            (1, None, None),
+            (2, None, None),
+            (3, Some((1, 2)), Some(10)),
+            (4, Some((1, 2)), Some(10)),
+            (5, None, Some(20)),
+            (6, Some((1, 5)), Some(10)),
+            (7, None, Some(30)),
+        ];
+        let db = tokio::task::spawn_blocking(Db::open_for_test)
+            .await
+            .unwrap()
+            .unwrap();
+        let tokens = pedigree.map(|(token_id, parents, fee)| {
+            let mut token = crate::db::tests::test_token();
+            token.meta.properties.self_id = trout(token_id);
+            token.meta.properties.left = parents.map(|(left, _)| trout(left));
+            token.meta.properties.right = parents.map(|(_, right)| trout(right));
+            token.fee = fee.map(|fee: u64| fee.into());
+            token
+        });
+        db.write(move |tx| tx.insert_tokens(tokens.iter()))
+            .await
+            .unwrap();
+
+        let state = ChainState {
+            nftrout: crate::nftrout::Client::new(&config),
+            ancestors: Arc::new(RwLock::new(
+                crate::indexer::load_ancestors(chain, &db).await,
+            )),
+            activity: broadcast::channel(1).0,
+            contract_state: Default::default(),
+        };
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = AppState {
+            db,
+            ipfs: crate::ipfs::Client::new("http://127.0.0.1:5001".parse().unwrap()),
+            chains: Arc::new(HashMap::from([(chain, state)])),
+        };
+        tokio::spawn(async move { axum::serve(listener, make_router(state)).await });
+
+        let matches = |query: &str| {
+            let url = format!("http://{addr}/trout/{chain}/3/matches{query}");
+            async move {
+                let res = reqwest::get(&url).await.unwrap();
+                assert!(res.status().is_success(), "{url}: {}", res.status());
+                let body: serde_json::Value =
+                    serde_json::from_slice(&res.bytes().await.unwrap()).unwrap();
+                body["result"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|m| {
+                        (
+                            m["stud"]["id"].as_u64().unwrap(),
+                            m["coi"].as_f64().unwrap(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            matches("").await,
+            [(5, 0.0), (7, 0.0), (6, 0.125), (4, 0.25)]
+        );
+        let cheap = [(5, 0.0), (6, 0.125), (4, 0.25)];
+        assert_eq!(matches("?max_fee=25").await, cheap);
+        assert_eq!(matches("?max_fee=0x19").await, cheap);
+        assert_eq!(matches("?max_fee=25&max_coi=0.2&limit=1").await, [(5, 0.0)]);
+    }
+
+    /// Checks that the API keeps serving reads while the indexer holds the write transaction. Both
+    /// run on this single-threaded runtime, so the reads would never complete if the write
+    /// blocked it.
+    #[tokio::test]
+    async fn reads_proceed_during_writes() {
+        let chain = 31337;
+        let path =
+            std::env::temp_dir().join(format!("nftrout-load-{}.sqlite", rand::random::<u64>()));
+        let connstr = path.to_string_lossy().into_owned();
+        let db = tokio::task::spawn_blocking(move || Db::open(connstr))
+            .await
+            .unwrap()
+            .unwrap();
+        let tokens = (1..=10)
+            .map(|token_id| {
+                let mut token = crate::db::tests::test_token();
+                token.meta.properties.self_id = TroutId {
+                    chain_id: chain,
+                    token_id,
+                };
+                token
+            })
+            .collect::<Vec<_>>();
+        let owner = tokens[0].owner;
+        db.write({
+            let tokens = tokens.clone();
+            move |tx| tx.insert_tokens(tokens.iter())
+        })
+        .await
+        .unwrap();
+
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = AppState {
+            db: db.clone(),
+            ipfs: crate::ipfs::Client::new("http://127.0.0.1:5001".parse().unwrap()),
+            chains: Default::default(),
+        };
+        tokio::spawn(async move { axum::serve(listener, make_router(state)).await });
+
+        let (started, writing) = tokio::sync::oneshot::channel();
+        let (finish, finished) = std::sync::mpsc::channel::<()>();
+        let indexer = tokio::spawn({
+            let db = db.clone();
+            async move {
+                db.write(move |tx| {
+                    started.send(()).unwrap();
+                    finished.recv().unwrap();
+                    tx.insert_tokens(tokens.iter())
+                })
+                .await
+                .unwrap()
+            }
+        });
+        writing.await.unwrap();
+
+        for url in [
+            format!("http://{addr}/trout/{chain}/?limit=100"),
+            format!("http://{addr}/trout/{chain}/1/events"),
+            format!("http://{addr}/owners/{chain}/{:#x}/trout", owner),
+        ] {
+            let res = tokio::time::timeout(Duration::from_secs(10), reqwest::get(&url))
+                .await
+                .unwrap_or_else(|_| panic!("{url} waited for the write"))
+                .unwrap();
+            assert!(res.status().is_success(), "{url}: {}", res.status());
+        }
+        finish.send(()).unwrap();
+        indexer.await.unwrap();
+
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            std::fs::remove_file(file).ok();
+        }
+    }
 }
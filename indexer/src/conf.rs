@@ -1,10 +1,13 @@
 use std::time::Duration;
 
+use ethers::types::Address;
 use serde::{
     de::{self, Deserializer},
     Deserialize,
 };
 
+use crate::nftrout::ChainId;
+
 #[derive(Clone, Deserialize)]
 pub struct Config {
     #[serde(default = "default_api_port")]
@@ -16,6 +19,8 @@ pub struct Config {
     )]
     pub ipfs_endpoint: url::Url,
 
+    /// The path of the SQLite database, or the `postgres://` URL of a PostgreSQL database
+    /// when built with the `postgres` feature.
     #[serde(default = "default_db_path")]
     pub db_path: String,
 
@@ -25,8 +30,13 @@ pub struct Config {
     )]
     pub reindex_interval: Duration,
 
-    #[serde(default = "default_chain")]
-    pub chain: Chain,
+    /// The deployments to index. May also be given as a single `chain`.
+    #[serde(
+        alias = "chain",
+        deserialize_with = "deserialize_chains",
+        default = "default_chains"
+    )]
+    pub chains: Vec<ChainConfig>,
 }
 
 impl std::fmt::Debug for Config {
@@ -36,19 +46,20 @@ impl std::fmt::Debug for Config {
             ipfs_endpoint,
             db_path,
             reindex_interval,
-            chain,
+            chains,
         } = self;
         f.debug_struct("Config")
             .field("api_port", api_port)
             .field("ipfs_endpoint", &ipfs_endpoint.to_string())
             .field("db_path", db_path)
             .field("reindex_interval", reindex_interval)
-            .field("chain", chain)
+            .field("chains", chains)
             .finish()
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+/// A well-known deployment whose settings are the defaults of a [`ChainConfig`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum Chain {
     SapphireMainnet,
@@ -56,6 +67,145 @@ pub enum Chain {
     Local,
 }
 
+impl Chain {
+    pub fn config(self) -> ChainConfig {
+        let (chain_id, contract, rpc_url, start_block) = match self {
+            Self::SapphireMainnet => (
+                0x5afe,
+                "0x998633BDF6eE32A9CcA6c9A247F428596e8e65d8",
+                "https://sapphire2.oasis.io",
+                410435,
+            ),
+            Self::SapphireTestnet => (
+                0x5aff,
+                "0xF8E3DE55D24D13607A12628E0A113B66BA578bDC",
+                "https://testnet.sapphire.oasis.dev",
+                0,
+            ),
+            Self::Local => (
+                31337,
+                "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512",
+                "http://127.0.0.1:8545",
+                0,
+            ),
+        };
+        ChainConfig {
+            chain_id,
+            contract: contract.parse().unwrap(),
+            rpc_urls: vec![rpc_url.parse().unwrap()],
+            start_block,
+            polling_interval: default_polling_interval(),
+            confirmations: 0,
+        }
+    }
+}
+
+/// The deployment to index. Configured either as the name of a [`Chain`] preset or as a table
+/// containing the settings below, which default to those of the optional `preset`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ChainConfig {
+    pub chain_id: ChainId,
+    pub contract: Address,
+    /// Gateways that are tried in order until one responds.
+    pub rpc_urls: Vec<url::Url>,
+    /// The block from which contract events are first indexed.
+    pub start_block: u64,
+    pub polling_interval: Duration,
+    /// The number of blocks by which indexing trails the chain tip.
+    pub confirmations: u64,
+}
+
+impl std::fmt::Debug for ChainConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            chain_id,
+            contract,
+            rpc_urls,
+            start_block,
+            polling_interval,
+            confirmations,
+        } = self;
+        f.debug_struct("ChainConfig")
+            .field("chain_id", chain_id)
+            .field("contract", contract)
+            .field(
+                "rpc_urls",
+                &rpc_urls.iter().map(|u| u.as_str()).collect::<Vec<_>>(),
+            )
+            .field("start_block", start_block)
+            .field("polling_interval", polling_interval)
+            .field("confirmations", confirmations)
+            .finish()
+    }
+}
+
+impl<'de> Deserialize<'de> for ChainConfig {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Preset(Chain),
+            Table(Table),
+        }
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Table {
+            preset: Option<Chain>,
+            chain_id: Option<ChainId>,
+            contract: Option<Address>,
+            rpc_urls: Option<Vec<String>>,
+            start_block: Option<u64>,
+            polling_interval_ms: Option<u64>,
+            confirmations: Option<u64>,
+        }
+
+        let table = match Repr::deserialize(d)? {
+            Repr::Preset(chain) => return Ok(chain.config()),
+            Repr::Table(table) => table,
+        };
+        let preset = table.preset.map(Chain::config);
+        macro_rules! setting {
+            ($field:ident) => {
+                match (table.$field, preset.as_ref()) {
+                    (Some(v), _) => v,
+                    (None, Some(preset)) => preset.$field.clone(),
+                    (None, None) => return Err(de::Error::missing_field(stringify!($field))),
+                }
+            };
+        }
+        let rpc_urls = match table.rpc_urls {
+            Some(urls) => urls
+                .iter()
+                .map(|url| url.parse())
+                .collect::<Result<Vec<url::Url>, _>>()
+                .map_err(de::Error::custom)?,
+            None => match &preset {
+                Some(preset) => preset.rpc_urls.clone(),
+                None => return Err(de::Error::missing_field("rpc_urls")),
+            },
+        };
+        if rpc_urls.is_empty() {
+            return Err(de::Error::invalid_length(0, &"at least one RPC URL"));
+        }
+        Ok(Self {
+            chain_id: setting!(chain_id),
+            contract: setting!(contract),
+            rpc_urls,
+            start_block: setting!(start_block),
+            polling_interval: table
+                .polling_interval_ms
+                .map(Duration::from_millis)
+                .or(preset.as_ref().map(|p| p.polling_interval))
+                .unwrap_or_else(default_polling_interval),
+            confirmations: table
+                .confirmations
+                .or(preset.as_ref().map(|p| p.confirmations))
+                .unwrap_or_default(),
+        })
+    }
+}
+
 fn deserialize_url<'de, D: Deserializer<'de>>(d: D) -> Result<url::Url, D::Error> {
     let url_str = String::deserialize(d)?;
     let url_str = if !url_str.ends_with('/') {
@@ -66,6 +216,29 @@ fn deserialize_url<'de, D: Deserializer<'de>>(d: D) -> Result<url::Url, D::Error
     url_str.parse().map_err(de::Error::custom)
 }
 
+fn deserialize_chains<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<ChainConfig>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(ChainConfig),
+        Many(Vec<ChainConfig>),
+    }
+    let chains = match OneOrMany::deserialize(d)? {
+        OneOrMany::One(chain) => vec![chain],
+        OneOrMany::Many(chains) => chains,
+    };
+    let mut chain_ids = std::collections::HashSet::new();
+    for chain in chains.iter() {
+        if !chain_ids.insert(chain.chain_id) {
+            return Err(de::Error::custom(format!(
+                "chain {} is configured more than once",
+                chain.chain_id
+            )));
+        }
+    }
+    Ok(chains)
+}
+
 fn deserialize_seconds<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
     Ok(Duration::from_secs(<u64>::deserialize(d)?))
 }
@@ -74,8 +247,12 @@ fn default_api_port() -> u16 {
     3474
 }
 
-fn default_chain() -> Chain {
-    Chain::SapphireMainnet
+fn default_chains() -> Vec<ChainConfig> {
+    vec![Chain::SapphireMainnet.config()]
+}
+
+fn default_polling_interval() -> Duration {
+    Duration::from_millis(3000)
 }
 
 fn default_ipfs_endpoint() -> url::Url {
@@ -89,3 +266,109 @@ fn default_reindex_interval() -> Duration {
 fn default_db_path() -> String {
     "nftrout.sqlite".into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<Config, config::ConfigError> {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+    }
+
+    #[test]
+    fn chain_preset() {
+        assert_eq!(
+            parse("").unwrap().chains,
+            vec![Chain::SapphireMainnet.config()]
+        );
+        assert_eq!(
+            parse(r#"chain = "local""#).unwrap().chains,
+            vec![Chain::Local.config()]
+        );
+    }
+
+    #[test]
+    fn chain_table() {
+        let chain = parse(
+            r#"
+            [chain]
+            preset = "sapphire-testnet"
+            rpc_urls = ["http://10.0.0.1:8545", "http://10.0.0.2:8545"]
+            confirmations = 2
+            "#,
+        )
+        .unwrap()
+        .chains
+        .remove(0);
+        assert_eq!(chain.chain_id, 0x5aff);
+        assert_eq!(chain.rpc_urls.len(), 2);
+        assert_eq!(chain.confirmations, 2);
+        assert_eq!(
+            chain.start_block,
+            Chain::SapphireTestnet.config().start_block
+        );
+
+        let chain = parse(
+            r#"
+            [chain]
+            chain_id = 1337
+            contract = "0x998633BDF6eE32A9CcA6c9A247F428596e8e65d8"
+            rpc_urls = ["http://127.0.0.1:8545"]
+            start_block = 12
+            polling_interval_ms = 500
+            "#,
+        )
+        .unwrap()
+        .chains
+        .remove(0);
+        assert_eq!(chain.chain_id, 1337);
+        assert_eq!(chain.start_block, 12);
+        assert_eq!(chain.polling_interval, Duration::from_millis(500));
+
+        parse(
+            r#"
+            [chain]
+            chain_id = 1337
+            "#,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn multiple_chains() {
+        let chains = parse(
+            r#"
+            chains = ["sapphire-mainnet", "sapphire-testnet"]
+            "#,
+        )
+        .unwrap()
+        .chains;
+        assert_eq!(
+            chains,
+            vec![
+                Chain::SapphireMainnet.config(),
+                Chain::SapphireTestnet.config()
+            ]
+        );
+
+        let chains = parse(
+            r#"
+            [[chains]]
+            preset = "local"
+
+            [[chains]]
+            preset = "local"
+            chain_id = 1337
+            "#,
+        )
+        .unwrap()
+        .chains;
+        assert_eq!(chains[1].chain_id, 1337);
+        assert_eq!(chains[1].contract, chains[0].contract);
+
+        parse(r#"chains = ["local", "local"]"#).unwrap_err();
+    }
+}
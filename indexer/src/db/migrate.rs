@@ -0,0 +1,188 @@
+//! Named, reversible schema migrations, recorded in the database along with a checksum of each
+//! so that a migration edited after it was applied is caught rather than silently diverging.
+
+use ethers::utils::{hex, keccak256};
+use tracing::info;
+
+use super::Error;
+
+/// A change to the schema, along with the script that undoes it.
+pub struct Migration {
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        hex::encode(keccak256(self.up))
+    }
+}
+
+/// Declares the migration whose scripts are `migrations/{backend}/{name}.sql` and
+/// `migrations/{backend}/{name}.down.sql`.
+macro_rules! migration {
+    ($backend:literal, $name:literal) => {
+        $crate::db::migrate::Migration {
+            name: $name,
+            up: include_str!(concat!("./migrations/", $backend, "/", $name, ".sql")),
+            down: include_str!(concat!("./migrations/", $backend, "/", $name, ".down.sql")),
+        }
+    };
+}
+pub(super) use migration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// A migration script that was, or in a dry run would be, run against the database.
+#[derive(Clone, Copy, Debug)]
+pub struct MigrationStep {
+    /// The position of the migration among all of the backend's migrations.
+    pub ord: usize,
+    pub name: &'static str,
+    pub direction: Direction,
+    pub sql: &'static str,
+}
+
+impl std::fmt::Display for MigrationStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let direction = match self.direction {
+            Direction::Up => "up",
+            Direction::Down => "down",
+        };
+        write!(f, "{direction} {}", self.name)
+    }
+}
+
+/// A migration as recorded in the database when it was applied.
+pub(super) struct AppliedMigration {
+    pub ord: usize,
+    pub name: String,
+    pub checksum: String,
+}
+
+/// The bookkeeping of migrations that each backend provides.
+pub(super) trait MigrationStore {
+    /// Creates the table recording applied migrations if it does not yet exist, and keeps
+    /// concurrently starting indexers from migrating at the same time.
+    fn create_migrations_table(&self) -> Result<(), Error>;
+
+    /// Returns the applied migrations in order, which is empty if none has been recorded.
+    fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, Error>;
+
+    /// Returns the number of migrations applied according to the bare version number used
+    /// before migrations were recorded individually.
+    fn legacy_version(&self) -> Result<usize, Error>;
+
+    /// Keeps the bare version number current, so that builds which predate the recording
+    /// of migrations still notice a newer schema.
+    fn set_legacy_version(&self, version: usize) -> Result<(), Error>;
+
+    fn execute_migration(&self, sql: &str) -> Result<(), Error>;
+
+    fn record_migration(&self, ord: usize, migration: &Migration) -> Result<(), Error>;
+
+    fn forget_migration(&self, ord: usize) -> Result<(), Error>;
+}
+
+/// Brings the schema to `target` applied migrations, or the latest if `None`, running the
+/// migrations' scripts forwards or backwards as needed. A dry run returns the steps without
+/// changing the database.
+pub(super) fn run(
+    store: &impl MigrationStore,
+    migrations: &'static [Migration],
+    target: Option<usize>,
+    dry_run: bool,
+) -> Result<Vec<MigrationStep>, Error> {
+    if !dry_run {
+        store.create_migrations_table()?;
+    }
+    let mut applied = store.applied_migrations()?;
+    let adopted = applied.is_empty();
+    if adopted {
+        let version = store.legacy_version()?;
+        if version > migrations.len() {
+            return Err(Error::SchemaAhead {
+                applied: version,
+                latest: None,
+                known: migrations.len(),
+            });
+        }
+        applied = migrations[..version]
+            .iter()
+            .enumerate()
+            .map(|(ord, migration)| AppliedMigration {
+                ord,
+                name: migration.name.into(),
+                checksum: migration.checksum(),
+            })
+            .collect();
+    }
+    if applied.len() > migrations.len() {
+        return Err(Error::SchemaAhead {
+            applied: applied.len(),
+            latest: applied.pop().map(|m| m.name),
+            known: migrations.len(),
+        });
+    }
+    for (ord, (record, migration)) in applied.iter().zip(migrations).enumerate() {
+        if record.ord != ord || record.name != migration.name {
+            return Err(Error::MigrationChanged(record.name.clone()));
+        }
+        if record.checksum != migration.checksum() {
+            return Err(Error::MigrationChanged(migration.name.into()));
+        }
+    }
+
+    let target = target.unwrap_or(migrations.len());
+    if target > migrations.len() {
+        return Err(Error::UnknownVersion(target));
+    }
+    let step = |ord: usize, direction| {
+        let migration = &migrations[ord];
+        MigrationStep {
+            ord,
+            name: migration.name,
+            direction,
+            sql: match direction {
+                Direction::Up => migration.up,
+                Direction::Down => migration.down,
+            },
+        }
+    };
+    let steps: Vec<_> = if target >= applied.len() {
+        (applied.len()..target)
+            .map(|ord| step(ord, Direction::Up))
+            .collect()
+    } else {
+        (target..applied.len())
+            .rev()
+            .map(|ord| step(ord, Direction::Down))
+            .collect()
+    };
+    if dry_run {
+        return Ok(steps);
+    }
+
+    if adopted {
+        for (ord, migration) in migrations[..applied.len()].iter().enumerate() {
+            store.record_migration(ord, migration)?;
+        }
+    }
+    for step in steps.iter() {
+        info!(migration = %step, "migrating database");
+        store.execute_migration(step.sql)?;
+        match step.direction {
+            Direction::Up => store.record_migration(step.ord, &migrations[step.ord])?,
+            Direction::Down => store.forget_migration(step.ord)?,
+        }
+    }
+    if !steps.is_empty() {
+        store.set_legacy_version(target)?;
+    }
+    Ok(steps)
+}
@@ -0,0 +1,16 @@
+DROP VIEW studdings;
+DROP TABLE earnings_reconciliations;
+DROP TABLE contract_events;
+DROP TABLE blocks;
+DROP TABLE progress;
+DROP TABLE breed_events;
+DROP TABLE transfer_events;
+DROP TABLE list_events;
+DROP TABLE spawn_events;
+DROP TABLE events;
+DROP TABLE event_kinds;
+DROP TABLE analysis;
+DROP TABLE generations;
+DROP TABLE metadata;
+DROP TABLE tokens;
+DROP FUNCTION unixepoch();
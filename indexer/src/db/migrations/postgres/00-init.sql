@@ -0,0 +1,230 @@
+-- The schema reached by the SQLite migrations through `11-renames.sql`, whose intermediate steps
+-- only concern databases that predate them.
+
+-- The current time as a Unix timestamp, as in SQLite.
+CREATE FUNCTION unixepoch() RETURNS BIGINT
+  LANGUAGE SQL STABLE
+  AS $$ SELECT EXTRACT(EPOCH FROM now())::BIGINT $$;
+
+CREATE TABLE tokens (
+  id BIGSERIAL PRIMARY KEY,
+
+  owner TEXT NOT NULL,
+
+  self_chain BIGINT NOT NULL,
+  self_id    BIGINT NOT NULL
+);
+
+CREATE UNIQUE INDEX ix_tokens_self ON tokens (self_chain, self_id);
+CREATE INDEX ix_tokens_owner ON tokens (owner);
+
+CREATE TABLE metadata (
+  token BIGINT PRIMARY KEY REFERENCES tokens(id),
+
+  fee TEXT DEFAULT NULL,
+  -- Fees are stored as unpadded hex, so they are zero-padded to compare as text.
+  fee_key TEXT COLLATE "C" GENERATED ALWAYS AS (lpad(substr(fee, 3), 64, '0')) STORED,
+
+  version BIGINT NOT NULL DEFAULT -1,
+
+  name TEXT NOT NULL,
+  -- Whether the name was set by the owner, and so must survive re-indexing of the metadata.
+  is_renamed BOOLEAN NOT NULL DEFAULT FALSE,
+
+  is_genesis BOOLEAN NOT NULL DEFAULT FALSE,
+  is_santa   BOOLEAN NOT NULL DEFAULT FALSE,
+
+  left_parent_chain  BIGINT,
+  left_parent_id     BIGINT,
+  right_parent_chain BIGINT,
+  right_parent_id    BIGINT,
+
+  -- The time at which the token's metadata was first indexed, as a Unix timestamp.
+  indexed_at BIGINT
+);
+
+CREATE INDEX ix_metadata_version ON metadata (version);
+CREATE INDEX ix_metadata_left_parent ON metadata (left_parent_chain, left_parent_id);
+CREATE INDEX ix_metadata_right_parent ON metadata (right_parent_chain, right_parent_id);
+CREATE INDEX ix_metadata_fee_key ON metadata (fee_key);
+
+CREATE TABLE generations (
+  token BIGINT NOT NULL REFERENCES tokens(id),
+  ord BIGINT NOT NULL DEFAULT 0 CHECK(ord >= 0),
+  cid TEXT NOT NULL UNIQUE,
+  pinned BOOLEAN NOT NULL DEFAULT FALSE,
+  pin_fails BIGINT NOT NULL DEFAULT 0,
+  PRIMARY KEY (token, ord)
+);
+
+CREATE INDEX ix_generations_pinned ON generations (pinned) WHERE NOT pinned;
+
+CREATE TABLE analysis (
+  token BIGINT PRIMARY KEY REFERENCES tokens(id),
+
+  coi DOUBLE PRECISION NOT NULL DEFAULT -1
+);
+
+CREATE INDEX ix_analysis_coi ON analysis (coi);
+
+CREATE TABLE event_kinds (
+  id BIGINT PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE
+);
+
+INSERT INTO event_kinds (id, name) VALUES
+  (1, 'spawn'),
+  (2, 'list'),
+  (3, 'transfer'),
+  (4, 'breed'),
+  (5, 'incubate');
+
+CREATE TABLE events (
+  id BIGSERIAL PRIMARY KEY,
+  chain BIGINT NOT NULL,
+  kind BIGINT NOT NULL REFERENCES event_kinds(id),
+  token BIGINT NOT NULL,
+
+  block BIGINT NOT NULL,
+  log_index BIGINT NOT NULL,
+  -- Logs describing several tokens, such as ERC-2309 `ConsecutiveTransfer`, are recorded as one
+  -- event per token, distinguished by their position within the log.
+  batch_index BIGINT NOT NULL DEFAULT 0,
+
+  -- The time at which the indexer first recorded the event, as a Unix timestamp.
+  observed_at BIGINT
+);
+
+CREATE UNIQUE INDEX ix_events_uniq ON events (chain, block, log_index, batch_index);
+CREATE INDEX ix_events_token ON events (token);
+CREATE INDEX ix_events_kind_token ON events (chain, kind, token);
+CREATE INDEX ix_events_chain_block ON events (chain, block);
+CREATE INDEX ix_events_chain_token ON events (chain, token, block, log_index);
+
+CREATE TABLE spawn_events (
+  event BIGINT PRIMARY KEY REFERENCES events(id),
+  recipient TEXT NOT NULL
+);
+
+CREATE TABLE list_events (
+  event BIGINT PRIMARY KEY REFERENCES events(id),
+  fee TEXT
+);
+
+CREATE TABLE transfer_events (
+  event BIGINT PRIMARY KEY REFERENCES events(id),
+  sender TEXT NOT NULL,
+  recipient TEXT NOT NULL
+);
+
+CREATE TABLE breed_events (
+  event BIGINT PRIMARY KEY REFERENCES events(id),
+  left_parent BIGINT NOT NULL,
+  right_parent BIGINT NOT NULL
+);
+
+CREATE INDEX ix_breed_events_left_parent ON breed_events (left_parent);
+CREATE INDEX ix_breed_events_right_parent ON breed_events (right_parent);
+
+CREATE TABLE progress (
+  chain BIGINT PRIMARY KEY,
+  block BIGINT NOT NULL
+);
+
+CREATE TABLE blocks (
+  chain BIGINT NOT NULL,
+  number BIGINT NOT NULL,
+  hash TEXT NOT NULL,
+  -- The Unix timestamp of the block, if it was fetched along with the block's header.
+  timestamp BIGINT,
+
+  PRIMARY KEY (chain, number)
+);
+
+CREATE TABLE contract_events (
+  id BIGSERIAL PRIMARY KEY,
+  chain BIGINT NOT NULL,
+  kind TEXT NOT NULL,
+
+  block BIGINT NOT NULL,
+  log_index BIGINT NOT NULL,
+
+  -- The new value of the setting: an address or a U256.
+  value TEXT NOT NULL,
+  -- The previous value of the setting, if the event includes it.
+  previous TEXT
+);
+
+CREATE UNIQUE INDEX ix_contract_events_uniq ON contract_events (chain, block, log_index);
+CREATE INDEX ix_contract_events_kind ON contract_events (chain, kind);
+
+-- The latest comparison of each stud owner's accrued fees against the contract's `earnings`.
+CREATE TABLE earnings_reconciliations (
+  chain BIGINT NOT NULL,
+  owner TEXT NOT NULL,
+  -- The block at which both amounts were taken.
+  block BIGINT NOT NULL,
+  -- The total of the stud fees paid to the owner through the block.
+  accrued TEXT NOT NULL,
+  -- The owner's unwithdrawn balance in the contract at the block.
+  withdrawable TEXT NOT NULL,
+  reconciled_at BIGINT NOT NULL,
+  PRIMARY KEY (chain, owner)
+);
+
+-- The use of each parent in a breeding, with the owner of the parent at the time
+-- and the fee paid to them, which is NULL if the breeder was the owner or it was unlisted.
+-- `side` is 0 for the left parent and 1 for the right.
+CREATE VIEW studdings AS
+WITH
+parents AS (
+  SELECT events.chain, events.block, events.log_index, events.token AS child,
+         breed_events.left_parent AS stud, breed_events.right_parent AS coparent, 0 AS side
+    FROM breed_events
+    JOIN events ON events.id = breed_events.event
+   UNION ALL
+  SELECT events.chain, events.block, events.log_index, events.token AS child,
+         breed_events.right_parent AS stud, breed_events.left_parent AS coparent, 1 AS side
+    FROM breed_events
+    JOIN events ON events.id = breed_events.event
+),
+owned AS (
+  SELECT parents.*,
+         (
+           SELECT COALESCE(spawn_events.recipient, transfer_events.recipient)
+             FROM events
+             LEFT JOIN spawn_events ON spawn_events.event = events.id
+             LEFT JOIN transfer_events ON transfer_events.event = events.id
+            WHERE events.chain = parents.chain
+              AND events.token = parents.child
+              AND events.kind IN (1, 3)
+            ORDER BY events.block ASC, events.log_index ASC
+            LIMIT 1
+         ) AS breeder,
+         (
+           SELECT COALESCE(spawn_events.recipient, transfer_events.recipient)
+             FROM events
+             LEFT JOIN spawn_events ON spawn_events.event = events.id
+             LEFT JOIN transfer_events ON transfer_events.event = events.id
+            WHERE events.chain = parents.chain
+              AND events.token = parents.stud
+              AND events.kind IN (1, 3)
+              AND (events.block, events.log_index) < (parents.block, parents.log_index)
+            ORDER BY events.block DESC, events.log_index DESC
+            LIMIT 1
+         ) AS owner,
+         (
+           SELECT list_events.fee
+             FROM events
+             JOIN list_events ON list_events.event = events.id
+            WHERE events.chain = parents.chain
+              AND events.token = parents.stud
+              AND (events.block, events.log_index) < (parents.block, parents.log_index)
+            ORDER BY events.block DESC, events.log_index DESC
+            LIMIT 1
+         ) AS listed_fee
+    FROM parents
+)
+SELECT chain, block, log_index, child, stud, coparent, side, breeder, owner,
+       CASE WHEN breeder = owner THEN NULL ELSE listed_fee END AS fee
+  FROM owned;
@@ -0,0 +1,4 @@
+DROP TABLE analysis;
+DROP TABLE generations;
+DROP TABLE metadata;
+DROP TABLE tokens;
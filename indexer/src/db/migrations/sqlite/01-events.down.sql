@@ -0,0 +1,6 @@
+DROP TABLE progress;
+DROP TABLE transfer_events;
+DROP TABLE list_events;
+DROP TABLE spawn_events;
+DROP TABLE events;
+DROP TABLE event_kinds;
@@ -0,0 +1,6 @@
+-- Fails if events at the same position were recorded on more than one chain.
+DROP INDEX ix_events_uniq;
+ALTER TABLE events DROP COLUMN chain;
+CREATE UNIQUE INDEX ix_events_uniq ON events (block, log_index);
+
+DROP TABLE blocks;
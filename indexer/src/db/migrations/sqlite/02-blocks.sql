@@ -0,0 +1,13 @@
+CREATE TABLE blocks (
+  chain INTEGER NOT NULL,
+  number INTEGER NOT NULL,
+  hash H256 NOT NULL,
+
+  PRIMARY KEY (chain, number)
+);
+
+-- All events recorded before this migration came from Sapphire mainnet.
+ALTER TABLE events ADD COLUMN chain INTEGER NOT NULL DEFAULT 23294;
+
+DROP INDEX ix_events_uniq;
+CREATE UNIQUE INDEX ix_events_uniq ON events (chain, block, log_index);
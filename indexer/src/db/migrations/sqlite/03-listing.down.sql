@@ -0,0 +1,4 @@
+-- Owners keep the `0x` prefix that was added to them.
+DROP INDEX ix_analysis_coi;
+DROP INDEX ix_metadata_fee_key;
+ALTER TABLE metadata DROP COLUMN fee_key;
@@ -0,0 +1,10 @@
+-- Owners were written without a `0x` prefix when updated from transfers.
+UPDATE tokens SET owner = '0x' || owner WHERE owner NOT LIKE '0x%';
+
+-- Fees are stored as unpadded hex, so they are zero-padded to compare as text.
+ALTER TABLE metadata ADD COLUMN fee_key TEXT GENERATED ALWAYS AS (
+  substr('0000000000000000000000000000000000000000000000000000000000000000' || substr(fee, 3), -64, 64)
+) VIRTUAL;
+
+CREATE INDEX ix_metadata_fee_key ON metadata (fee_key);
+CREATE INDEX ix_analysis_coi ON analysis (coi);
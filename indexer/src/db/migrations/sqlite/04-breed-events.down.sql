@@ -0,0 +1,5 @@
+DROP VIEW studdings;
+DROP TABLE breed_events;
+
+DELETE FROM events WHERE kind = 4;
+DELETE FROM event_kinds WHERE id = 4;
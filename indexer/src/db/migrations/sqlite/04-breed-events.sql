@@ -0,0 +1,69 @@
+INSERT INTO event_kinds (id, name) VALUES (4, 'breed');
+
+CREATE TABLE breed_events (
+  event INTEGER PRIMARY KEY REFERENCES events(id),
+  left_parent INTEGER NOT NULL,
+  right_parent INTEGER NOT NULL
+);
+
+CREATE INDEX ix_breed_events_left_parent ON breed_events (left_parent);
+CREATE INDEX ix_breed_events_right_parent ON breed_events (right_parent);
+
+-- The use of each parent in a breeding, with the owner of the parent at the time
+-- and the fee paid to them, which is NULL if the breeder was the owner or it was unlisted.
+CREATE VIEW studdings AS
+WITH
+parents AS (
+  SELECT events.chain, events.block, events.log_index, events.token AS child,
+         breed_events.left_parent AS stud, breed_events.right_parent AS coparent
+    FROM breed_events
+    JOIN events ON events.id = breed_events.event
+   UNION ALL
+  SELECT events.chain, events.block, events.log_index, events.token AS child,
+         breed_events.right_parent AS stud, breed_events.left_parent AS coparent
+    FROM breed_events
+    JOIN events ON events.id = breed_events.event
+),
+acquisitions AS (
+  SELECT events.chain, events.token, events.block, events.log_index, spawn_events.recipient
+    FROM events
+    JOIN spawn_events ON spawn_events.event = events.id
+   UNION ALL
+  SELECT events.chain, events.token, events.block, events.log_index, transfer_events.recipient
+    FROM events
+    JOIN transfer_events ON transfer_events.event = events.id
+),
+owned AS (
+  SELECT parents.*,
+         (
+           SELECT acquisitions.recipient
+             FROM acquisitions
+            WHERE acquisitions.chain = parents.chain
+              AND acquisitions.token = parents.child
+            ORDER BY acquisitions.block ASC, acquisitions.log_index ASC
+            LIMIT 1
+         ) AS breeder,
+         (
+           SELECT acquisitions.recipient
+             FROM acquisitions
+            WHERE acquisitions.chain = parents.chain
+              AND acquisitions.token = parents.stud
+              AND (acquisitions.block, acquisitions.log_index) < (parents.block, parents.log_index)
+            ORDER BY acquisitions.block DESC, acquisitions.log_index DESC
+            LIMIT 1
+         ) AS owner,
+         (
+           SELECT list_events.fee
+             FROM events
+             JOIN list_events ON list_events.event = events.id
+            WHERE events.chain = parents.chain
+              AND events.token = parents.stud
+              AND (events.block, events.log_index) < (parents.block, parents.log_index)
+            ORDER BY events.block DESC, events.log_index DESC
+            LIMIT 1
+         ) AS listed_fee
+    FROM parents
+)
+SELECT chain, block, log_index, child, stud, coparent, breeder, owner,
+       IIF(breeder = owner, NULL, listed_fee) AS fee
+  FROM owned;
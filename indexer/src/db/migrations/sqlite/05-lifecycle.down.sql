@@ -0,0 +1,7 @@
+ALTER TABLE metadata DROP COLUMN indexed_at;
+
+DROP INDEX ix_events_kind_token;
+ALTER TABLE events DROP COLUMN observed_at;
+
+DELETE FROM events WHERE kind = 5;
+DELETE FROM event_kinds WHERE id = 5;
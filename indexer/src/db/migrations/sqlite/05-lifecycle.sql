@@ -0,0 +1,8 @@
+INSERT INTO event_kinds (id, name) VALUES (5, 'incubate');
+
+-- The time at which the indexer first recorded the event, as a Unix timestamp.
+ALTER TABLE events ADD COLUMN observed_at INTEGER;
+CREATE INDEX ix_events_kind_token ON events (chain, kind, token);
+
+-- The time at which the token's metadata was first indexed, as a Unix timestamp.
+ALTER TABLE metadata ADD COLUMN indexed_at INTEGER;
@@ -0,0 +1,10 @@
+-- Only the first token of each batch can be kept in one event per log.
+DELETE FROM spawn_events WHERE event IN (SELECT id FROM events WHERE batch_index > 0);
+DELETE FROM list_events WHERE event IN (SELECT id FROM events WHERE batch_index > 0);
+DELETE FROM transfer_events WHERE event IN (SELECT id FROM events WHERE batch_index > 0);
+DELETE FROM breed_events WHERE event IN (SELECT id FROM events WHERE batch_index > 0);
+DELETE FROM events WHERE batch_index > 0;
+
+DROP INDEX ix_events_uniq;
+ALTER TABLE events DROP COLUMN batch_index;
+CREATE UNIQUE INDEX ix_events_uniq ON events (chain, block, log_index);
@@ -0,0 +1,6 @@
+-- Logs describing several tokens, such as ERC-2309 `ConsecutiveTransfer`, are recorded as one
+-- event per token, distinguished by their position within the log.
+ALTER TABLE events ADD COLUMN batch_index INTEGER NOT NULL DEFAULT 0;
+
+DROP INDEX ix_events_uniq;
+CREATE UNIQUE INDEX ix_events_uniq ON events (chain, block, log_index, batch_index);
@@ -0,0 +1,6 @@
+INSERT INTO progress (chain, block)
+SELECT chain, block FROM contract_events_rescan WHERE true
+    ON CONFLICT (chain) DO UPDATE SET block = MAX(progress.block, excluded.block);
+DROP TABLE contract_events_rescan;
+
+DROP TABLE contract_events;
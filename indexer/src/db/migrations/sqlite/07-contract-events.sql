@@ -0,0 +1,34 @@
+CREATE TABLE contract_events (
+  id INTEGER PRIMARY KEY,
+  chain INTEGER NOT NULL,
+  kind TEXT NOT NULL,
+
+  block INTEGER NOT NULL,
+  log_index INTEGER NOT NULL,
+
+  -- The new value of the setting: an address or a U256.
+  value TEXT NOT NULL,
+  -- The previous value of the setting, if the event includes it.
+  previous TEXT
+);
+
+CREATE UNIQUE INDEX ix_contract_events_uniq ON contract_events (chain, block, log_index);
+CREATE INDEX ix_contract_events_kind ON contract_events (chain, kind);
+
+-- Contract events were previously dropped, as were the `Spawned`, `Incubated` and
+-- `ConsecutiveTransfer` events recorded since 04, 05 and 06, so each chain is rescanned once from
+-- the deployment of the contract, which is no later than its first recorded event. Chains without
+-- any recorded event are rescanned from their configured start. The progress reached before the
+-- rescan is kept so that reverting restores it.
+CREATE TABLE contract_events_rescan (
+  chain INTEGER PRIMARY KEY NOT NULL,
+  block INTEGER NOT NULL
+);
+INSERT INTO contract_events_rescan (chain, block) SELECT chain, block FROM progress;
+
+UPDATE progress
+   SET block = MIN(block, IIF(chain = 23294, 410435, (
+         SELECT MIN(events.block) FROM events WHERE events.chain = progress.chain
+       )))
+ WHERE chain = 23294 OR chain IN (SELECT chain FROM events);
+DELETE FROM progress WHERE chain <> 23294 AND chain NOT IN (SELECT chain FROM events);
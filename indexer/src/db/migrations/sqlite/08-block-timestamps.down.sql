@@ -0,0 +1,3 @@
+DROP INDEX ix_events_chain_block;
+
+ALTER TABLE blocks DROP COLUMN timestamp;
@@ -0,0 +1,4 @@
+-- The Unix timestamp of the block, if it was fetched along with the block's header.
+ALTER TABLE blocks ADD COLUMN timestamp INTEGER;
+
+CREATE INDEX ix_events_chain_block ON events (chain, block);
@@ -0,0 +1 @@
+DROP TABLE earnings_reconciliations;
@@ -0,0 +1,13 @@
+-- The latest comparison of each stud owner's accrued fees against the contract's `earnings`.
+CREATE TABLE earnings_reconciliations (
+  chain INTEGER NOT NULL,
+  owner TEXT NOT NULL,
+  -- The block at which both amounts were taken.
+  block INTEGER NOT NULL,
+  -- The total of the stud fees paid to the owner through the block.
+  accrued TEXT NOT NULL,
+  -- The owner's unwithdrawn balance in the contract at the block.
+  withdrawable TEXT NOT NULL,
+  reconciled_at INTEGER NOT NULL,
+  PRIMARY KEY (chain, owner)
+);
@@ -0,0 +1,18 @@
+-- Only the latest generation of each token is kept. The rest are backfilled when re-applied.
+CREATE TABLE generations_by_token (
+  token INTEGER PRIMARY KEY NOT NULL REFERENCES tokens(id),
+  ord INTEGER NOT NULL DEFAULT 0 CHECK(ord >= 0),
+  cid TEXT NOT NULL UNIQUE,
+  pinned BOOLEAN NOT NULL DEFAULT 0 CHECK(pinned = 0 OR pinned = 1),
+  pin_fails INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO generations_by_token (token, ord, cid, pinned, pin_fails)
+SELECT token, ord, cid, pinned, pin_fails FROM generations
+ WHERE ord = (SELECT MAX(ord) FROM generations AS latest WHERE latest.token = generations.token);
+
+DROP TABLE generations;
+ALTER TABLE generations_by_token RENAME TO generations;
+
+CREATE UNIQUE INDEX ix_generations_uniq ON generations (token, ord);
+CREATE INDEX ix_generations_pinned ON generations (pinned) WHERE pinned = 0;
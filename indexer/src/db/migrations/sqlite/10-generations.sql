@@ -0,0 +1,17 @@
+-- `generations` was keyed on the token alone, so only one generation of each token was kept.
+CREATE TABLE generations_by_ord (
+  token INTEGER NOT NULL REFERENCES tokens(id),
+  ord INTEGER NOT NULL DEFAULT 0 CHECK(ord >= 0),
+  cid TEXT NOT NULL UNIQUE,
+  pinned BOOLEAN NOT NULL DEFAULT 0 CHECK(pinned = 0 OR pinned = 1),
+  pin_fails INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (token, ord)
+);
+
+INSERT INTO generations_by_ord (token, ord, cid, pinned, pin_fails)
+SELECT token, ord, cid, pinned, pin_fails FROM generations;
+
+DROP TABLE generations;
+ALTER TABLE generations_by_ord RENAME TO generations;
+
+CREATE INDEX ix_generations_pinned ON generations (pinned) WHERE pinned = 0;
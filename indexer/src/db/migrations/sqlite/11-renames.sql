@@ -0,0 +1,4 @@
+-- Whether the name was set by the owner, and so must survive re-indexing of the metadata.
+-- Names set before this migration are indistinguishable from those in the metadata.
+ALTER TABLE metadata ADD COLUMN is_renamed BOOLEAN NOT NULL DEFAULT 0
+  CHECK(is_renamed = 0 OR is_renamed = 1);
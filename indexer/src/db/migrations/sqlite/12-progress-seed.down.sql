@@ -0,0 +1 @@
+INSERT OR IGNORE INTO progress (chain, block) VALUES (23294, 410435);
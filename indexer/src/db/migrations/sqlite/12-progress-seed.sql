@@ -0,0 +1,3 @@
+-- `01-events.sql` seeded the progress of Sapphire mainnet, which is now seeded from each chain's
+-- configured start block instead. The seed is left alone if indexing has moved past it.
+DELETE FROM progress WHERE chain = 23294 AND block = 410435;
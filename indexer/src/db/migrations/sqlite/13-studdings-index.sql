@@ -0,0 +1,62 @@
+-- Looks up the owner of a token and its listing as of a breeding by seeking to the token's own
+-- events, rather than scanning every acquisition on the chain for each use of a parent.
+CREATE INDEX ix_events_chain_token ON events (chain, token, block, log_index);
+
+DROP VIEW studdings;
+
+-- The use of each parent in a breeding, with the owner of the parent at the time
+-- and the fee paid to them, which is NULL if the breeder was the owner or it was unlisted.
+-- `side` is 0 for the left parent and 1 for the right.
+CREATE VIEW studdings AS
+WITH
+parents AS (
+  SELECT events.chain, events.block, events.log_index, events.token AS child,
+         breed_events.left_parent AS stud, breed_events.right_parent AS coparent, 0 AS side
+    FROM breed_events
+    JOIN events ON events.id = breed_events.event
+   UNION ALL
+  SELECT events.chain, events.block, events.log_index, events.token AS child,
+         breed_events.right_parent AS stud, breed_events.left_parent AS coparent, 1 AS side
+    FROM breed_events
+    JOIN events ON events.id = breed_events.event
+),
+owned AS (
+  SELECT parents.*,
+         (
+           SELECT IFNULL(spawn_events.recipient, transfer_events.recipient)
+             FROM events
+             LEFT JOIN spawn_events ON spawn_events.event = events.id
+             LEFT JOIN transfer_events ON transfer_events.event = events.id
+            WHERE events.chain = parents.chain
+              AND events.token = parents.child
+              AND events.kind IN (1, 3)
+            ORDER BY events.block ASC, events.log_index ASC
+            LIMIT 1
+         ) AS breeder,
+         (
+           SELECT IFNULL(spawn_events.recipient, transfer_events.recipient)
+             FROM events
+             LEFT JOIN spawn_events ON spawn_events.event = events.id
+             LEFT JOIN transfer_events ON transfer_events.event = events.id
+            WHERE events.chain = parents.chain
+              AND events.token = parents.stud
+              AND events.kind IN (1, 3)
+              AND (events.block, events.log_index) < (parents.block, parents.log_index)
+            ORDER BY events.block DESC, events.log_index DESC
+            LIMIT 1
+         ) AS owner,
+         (
+           SELECT list_events.fee
+             FROM events
+             JOIN list_events ON list_events.event = events.id
+            WHERE events.chain = parents.chain
+              AND events.token = parents.stud
+              AND (events.block, events.log_index) < (parents.block, parents.log_index)
+            ORDER BY events.block DESC, events.log_index DESC
+            LIMIT 1
+         ) AS listed_fee
+    FROM parents
+)
+SELECT chain, block, log_index, child, stud, coparent, side, breeder, owner,
+       IIF(breeder = owner, NULL, listed_fee) AS fee
+  FROM owned;
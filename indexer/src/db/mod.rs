@@ -1,613 +1,564 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use ethers::types::{Address, U256};
-use rusqlite::OptionalExtension as _;
-use tracing::{debug, trace};
+use ethers::types::{Address, H256, U256};
 
 use crate::{
     ipfs::Cid,
     nftrout::{
-        ChainId, Event, EventForUi, EventKindForUi, PendingToken, TokenEvent, TokenEventKind,
-        TokenForUi, TokenId, TroutId, TroutToken,
+        ChainId, ContractEventForUi, ContractEventKind, EarningsReconciliation, Event, EventForUi,
+        Generation, MarketStats, PendingToken, StudPayment, TokenForUi, TokenId, TokenLifecycle,
+        TroutId, TroutToken,
     },
 };
 
+mod migrate;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
+
+pub use migrate::MigrationStep;
+
+/// The number of recent block hashes retained per chain for reorg detection.
+/// Older blocks are retained only if they contain events, for their timestamps.
+const BLOCK_HASH_RETENTION: u64 = 256;
 
 #[derive(Clone)]
-pub struct Db {
-    connstr: Arc<String>,
+pub struct Db(Arc<Pool>);
+
+enum Pool {
+    Sqlite(sqlite::Pool),
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Pool),
 }
 
 impl Db {
+    /// Opens the database at `connstr`, which is a `postgres://` URL for a PostgreSQL database
+    /// or else the path of a SQLite database, and brings its schema up to date.
     pub fn open(connstr: String) -> Result<Self, Error> {
-        let this = Self {
-            connstr: Arc::new(connstr),
+        let db = Self::open_unmigrated(connstr)?;
+        db.migrate(None)?;
+        Ok(db)
+    }
+
+    /// Opens the database at `connstr` as does [`Db::open`], but leaves its schema as it is.
+    pub fn open_unmigrated(connstr: String) -> Result<Self, Error> {
+        let is_postgres =
+            connstr.starts_with("postgres://") || connstr.starts_with("postgresql://");
+        let pool = match is_postgres {
+            #[cfg(feature = "postgres")]
+            true => Pool::Postgres(postgres::Pool::open(&connstr)?),
+            #[cfg(not(feature = "postgres"))]
+            true => return Err(Error::Unsupported("postgres")),
+            false => Pool::Sqlite(sqlite::Pool::open(connstr)?),
         };
-        this.with_tx(|tx| tx.migrate(Self::migrations()))?;
-        Ok(this)
+        Ok(Self(Arc::new(pool)))
     }
 
     #[cfg(test)]
     pub fn open_in_memory() -> Result<Self, Error> {
-        let mut rng = rand::thread_rng();
-        let db_name = (0..7)
-            .map(|_| rand::Rng::sample(&mut rng, rand::distributions::Alphanumeric) as char)
-            .collect::<String>();
-        let connstr = format!("file:{db_name}?mode=memory&cache=shared");
-        Box::leak(Box::new(rusqlite::Connection::open(&connstr)?));
-        Self::open(connstr)
+        // The database lives as long as the pool's writer connection.
+        Self::open(format!("file:{}?mode=memory&cache=shared", random_name()))
     }
 
-    pub fn with_conn<T>(&self, f: impl FnOnce(Connection) -> Result<T, Error>) -> Result<T, Error> {
-        f(Connection(&rusqlite::Connection::open(&*self.connstr)?))
+    /// Opens an empty database for a test: a new schema in the PostgreSQL database at
+    /// `NFTROUT_TEST_POSTGRES_URL` if it is set and the `postgres` feature is enabled,
+    /// or else an in-memory SQLite database.
+    #[cfg(test)]
+    pub fn open_for_test() -> Result<Self, Error> {
+        #[cfg(feature = "postgres")]
+        if let Ok(url) = std::env::var("NFTROUT_TEST_POSTGRES_URL") {
+            let schema = format!("test_{}", random_name().to_lowercase());
+            let db = Self(Arc::new(Pool::Postgres(postgres::Pool::open_scratch(
+                &url, schema,
+            )?)));
+            db.migrate(None)?;
+            return Ok(db);
+        }
+        Self::open_in_memory()
     }
 
-    pub fn with_tx<T>(&self, f: impl FnOnce(Transaction) -> Result<T, Error>) -> Result<T, Error> {
-        self.with_conn(|mut conn| conn.with_tx(f))
+    /// Brings the schema to `target` applied migrations, reverting any beyond it, or else to
+    /// the latest, and returns the steps taken.
+    pub fn migrate(&self, target: Option<usize>) -> Result<Vec<MigrationStep>, Error> {
+        self.with_tx(|tx| tx.connection().migrate(target, false))
     }
 
-    const fn migrations() -> &'static [&'static str] {
-        &[
-            include_str!("./migrations/00-init.sql"),
-            include_str!("./migrations/01-events.sql"),
-        ]
+    /// Returns the steps that [`Db::migrate`] would take, without taking them.
+    pub fn pending_migrations(&self, target: Option<usize>) -> Result<Vec<MigrationStep>, Error> {
+        self.with_conn(|conn| conn.migrate(target, true))
     }
-}
 
-pub struct Connection<'a>(&'a rusqlite::Connection);
-
-impl Connection<'_> {
-    pub fn with_tx<T>(
-        &mut self,
-        f: impl FnOnce(Transaction) -> Result<T, Error>,
-    ) -> Result<T, Error> {
-        let tx = self.0.unchecked_transaction()?;
-        let res = f(Transaction(Connection(&tx)))?;
-        tx.commit()?;
-        Ok(res)
+    /// Runs `f` with a pooled reader connection. Writes belong in [`Db::with_tx`]. This blocks,
+    /// and must not be called from async code, which uses [`Db::read`] instead.
+    pub fn with_conn<T>(&self, f: impl FnOnce(Connection) -> Result<T, Error>) -> Result<T, Error> {
+        match &*self.0 {
+            Pool::Sqlite(pool) => pool.with_conn(|conn| f(Connection::Sqlite(conn))),
+            #[cfg(feature = "postgres")]
+            Pool::Postgres(pool) => pool.with_conn(|conn| f(Connection::Postgres(conn))),
+        }
     }
-}
 
-impl Connection<'_> {
-    pub fn latest_known_token_id(&self, chain_id: ChainId) -> Result<Option<TokenId>, Error> {
-        self.0
-            .query_row(
-                "SELECT MAX(self_id) FROM tokens WHERE self_chain = ?",
-                [chain_id],
-                |row| row.get::<_, Option<TokenId>>(0),
-            )
-            .map_err(Into::into)
+    pub fn with_tx<T>(&self, f: impl FnOnce(Transaction) -> Result<T, Error>) -> Result<T, Error> {
+        match &*self.0 {
+            Pool::Sqlite(pool) => pool.with_tx(|conn| f(Transaction(Connection::Sqlite(conn)))),
+            #[cfg(feature = "postgres")]
+            Pool::Postgres(pool) => pool.with_tx(|conn| f(Transaction(Connection::Postgres(conn)))),
+        }
     }
 
-    pub fn latest_processed_block(&self, chain_id: ChainId) -> Result<u64, Error> {
-        self.0
-            .query_row(
-                "SELECT block FROM progress WHERE chain = ?",
-                [chain_id],
-                |row| Ok(row.get::<_, i64>(0)? as u64),
-            )
-            .map_err(Into::into)
+    /// Like [`Db::with_conn`], but runs on the blocking thread pool so as not to stall the
+    /// async runtime.
+    pub async fn read<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(Connection) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.with_conn(f))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
     }
 
-    pub fn token_ids(&self, chain_id: ChainId) -> Result<Vec<TokenId>, Error> {
-        self.0
-            .prepare("SELECT self_id FROM tokens WHERE self_chain = ? ORDER BY self_id ASC")?
-            .query_map([chain_id], |row| row.get::<_, TokenId>(0))?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(Into::into)
+    /// Like [`Db::with_tx`], but runs on the blocking thread pool.
+    pub async fn write<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(Transaction) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.with_tx(f))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
     }
+}
 
-    pub fn needs_coi_analysis(&self) -> Result<Vec<TroutId>, Error> {
-        self.0
-            .prepare(
-                r#"
-                SELECT self_chain, self_id
-                  FROM tokens
-                  JOIN analysis
-                    ON analysis.token = tokens.id
-                 WHERE coi = -1
-                "#,
-            )?
-            .query_map([], |row| {
-                Ok(TroutId {
-                    chain_id: row.get(0)?,
-                    token_id: row.get(1)?,
-                })
-            })?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(Into::into)
-    }
+/// A connection to whichever database backs the [`Db`], through which its [`Storage`] is queried.
+pub enum Connection<'a> {
+    Sqlite(sqlite::Connection<'a>),
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Connection<'a>),
+}
 
-    pub fn outdated_token_ids(&self, chain_id: ChainId) -> Result<Vec<TokenId>, Error> {
-        self.0
-            .prepare(
-                r#"
-                SELECT tokens.self_id
-                  FROM tokens
-                  LEFT JOIN metadata ON metadata.token = tokens.id
-                 WHERE tokens.self_chain = ?
-                   AND (metadata.version IS NULL OR metadata.version < ?)
-                "#,
-            )?
-            .query_map([chain_id, crate::nftrout::CURRENT_VERSION], |row| {
-                row.get::<_, TokenId>(0)
-            })?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(Into::into)
+impl<'a> Connection<'a> {
+    fn connection(&self) -> &Self {
+        self
     }
 
-    pub fn unpinned_cids(&self) -> Result<Vec<Cid>, Error> {
-        self.0
-            .prepare("SELECT cid FROM generations WHERE pinned = 0 AND pin_fails < 20")?
-            .query_map([], |row| row.get::<_, String>(0).map(Into::into))?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(Into::into)
+    fn migrate(&self, target: Option<usize>, dry_run: bool) -> Result<Vec<MigrationStep>, Error> {
+        match self {
+            Self::Sqlite(conn) => migrate::run(conn, sqlite::MIGRATIONS, target, dry_run),
+            #[cfg(feature = "postgres")]
+            Self::Postgres(conn) => migrate::run(conn, postgres::MIGRATIONS, target, dry_run),
+        }
     }
 
-    pub fn token_cid(
-        &self,
-        TroutId { chain_id, token_id }: &TroutId,
-        ord: Option<u32>,
-    ) -> Result<Option<Cid>, Error> {
-        self.0
-            .query_row(
-                r#"
-                SELECT generations.cid FROM generations
-                JOIN tokens ON tokens.id = generations.token
-                WHERE tokens.self_chain = ?
-                AND tokens.self_id = ?
-                AND generations.ord = coalesce(
-                        ?,
-                        (SELECT MAX(ord) FROM generations WHERE generations.token = tokens.id)
-                    )
-                "#,
-                (chain_id, token_id, ord),
-                |row| row.get::<_, String>(0).map(Into::into),
-            )
-            .optional()
-            .map_err(Into::into)
+    #[cfg(test)]
+    pub(crate) fn execute_batch(&self, sql: &str) -> Result<(), Error> {
+        match self {
+            Self::Sqlite(conn) => conn.execute_batch(sql),
+            #[cfg(feature = "postgres")]
+            Self::Postgres(conn) => conn.execute_batch(sql),
+        }
     }
+}
 
-    pub fn list_tokens_for_ui(
-        &self,
-        chain_id: impl Into<Option<ChainId>>,
-    ) -> Result<Vec<TokenForUi>, Error> {
-        self.0
-            .prepare(
-                r#"
-                SELECT tokens.self_id,
-                       tokens.owner,
-                       analysis.coi,
-                       metadata.left_parent_chain,
-                       metadata.left_parent_id,
-                       metadata.right_parent_chain,
-                       metadata.right_parent_id,
-                       metadata.name,
-                       metadata.fee,
-                       metadata.version IS NULL as pending
-                  FROM tokens
-                  LEFT JOIN metadata ON metadata.token = tokens.id
-                  JOIN analysis ON analysis.token = tokens.id
-                 WHERE iif(?1, tokens.self_chain = ?1, 1)
-                 ORDER BY tokens.self_id ASC
-                "#,
-            )?
-            .query_map([chain_id.into()], |row| {
-                macro_rules! get_parent {
-                    ($side:literal) => {
-                        row.get::<_, Option<ChainId>>(concat!($side, "_parent_chain"))?
-                            .map(|chain_id| {
-                                let token_id =
-                                    row.get::<_, TokenId>(concat!($side, "_parent_id"))?;
-                                Ok::<_, rusqlite::Error>(TroutId { chain_id, token_id })
-                            })
-                            .transpose()?
-                    };
-                }
-                let left_parent = get_parent!("left");
-                let right_parent = get_parent!("right");
-                Ok(TokenForUi {
-                    id: row.get("self_id")?,
-                    coi: row.get("coi")?,
-                    owner: row.get::<_, String>("owner")?.parse().unwrap(),
-                    name: row.get("name")?,
-                    fee: row
-                        .get::<_, Option<String>>("fee")?
-                        .map(|f| f.parse().unwrap()),
-                    parents: left_parent.zip(right_parent),
-                    pending: row.get("pending")?,
-                })
-            })?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(Into::into)
+pub struct Transaction<'a>(Connection<'a>);
+
+impl<'a> std::ops::Deref for Transaction<'a> {
+    type Target = Connection<'a>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
+}
 
-    pub fn token_events(&self, token: TroutId) -> Result<Vec<EventForUi>, Error> {
-        let mut breeding_query = self.0.prepare_cached(
-            r#"
-            WITH
-            se AS (
-                SELECT spawn_events.recipient,
-                       metadata.token AS child_token,
-                       metadata.left_parent_chain AS coparent_chain,
-                       metadata.left_parent_id AS coparent_id,
-                       events.block
-                  FROM metadata
-                  JOIN events ON events.token = metadata.token AND events.kind = 1
-                  JOIN spawn_events ON spawn_events.event = events.id
-                 WHERE metadata.right_parent_chain = ?1 AND metadata.right_parent_id = ?2
-                 UNION ALL
-                SELECT spawn_events.recipient,
-                       metadata.token AS child_token,
-                       metadata.right_parent_chain AS coparent_chain,
-                       metadata.right_parent_id AS coparent_id,
-                       events.block
-                  FROM metadata
-                  JOIN events ON events.token = metadata.token AND events.kind = 1
-                  JOIN spawn_events ON spawn_events.event = events.id
-                 WHERE metadata.left_parent_chain = ?1 AND metadata.left_parent_id = ?2
-            ),
-            le AS (
-                SELECT list_events.fee, events.block
-                FROM list_events
-                JOIN events ON events.id = list_events.event
-                WHERE events.token IN (SELECT id FROM tokens WHERE self_chain = ?1 AND self_id = ?2)
-            ),
-            te AS (
-                SELECT transfer_events.recipient, events.block
-                 FROM transfer_events
-                 JOIN events ON events.id = transfer_events.event
-                WHERE events.token IN (SELECT id FROM tokens WHERE self_chain = ?1 AND self_id = ?2)
-            )
-            SELECT
-                tokens.self_chain AS child_chain,
-                tokens.self_id AS child_id,
-                se.recipient AS breeder,
-                se.coparent_chain,
-                se.coparent_id,
-                COALESCE(
-                    (
-                        SELECT recipient
-                        FROM te
-                        WHERE block < se.block
-                        ORDER BY block DESC
-                        LIMIT 1
-                    ),
-                    (
-                        SELECT recipient
-                        FROM spawn_events
-                        JOIN events ON spawn_events.event = events.id
-                        WHERE events.token IN (
-                                SELECT id FROM tokens WHERE self_chain = ?1 AND self_id = ?2
-                              )
-                    )
-                ) AS owner,
-                IIF(
-                    lower(se.recipient) = lower(owner),
-                    '0x00',
-                    (
-                        SELECT fee
-                        FROM le
-                        WHERE block < se.block
-                        ORDER BY block DESC
-                        LIMIT 1
-                    )
-                ) AS fee,
-                se.block
-            FROM se
-            JOIN tokens ON tokens.id = se.child_token;
-            "#,
-        )?;
-        let breeding_events = breeding_query
-            .query_map((token.chain_id, token.token_id), |row| {
-                Ok(EventForUi {
-                    id: token,
-                    block: row.get::<_, i64>("block")? as u64,
-                    kind: EventKindForUi::Breed {
-                        breeder: row
-                            .get::<_, String>("breeder")
-                            .map(|a| a.parse().unwrap())?,
-                        child: TroutId {
-                            chain_id: row.get("child_chain")?,
-                            token_id: row.get("child_id")?,
-                        },
-                        coparent: TroutId {
-                            chain_id: row.get("coparent_chain")?,
-                            token_id: row.get("coparent_id")?,
-                        },
-                        price: row
-                            .get::<_, Option<String>>("fee")?
-                            .map(|f| f.parse().unwrap())
-                            .unwrap_or_default(),
-                        owner: row.get::<_, String>("owner").map(|a| a.parse().unwrap())?,
-                    },
-                })
-            })?
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(breeding_events)
+impl<'a> Transaction<'a> {
+    fn connection(&self) -> &Connection<'a> {
+        &self.0
     }
+}
 
-    pub fn insert_tokens(&self, tokens: impl Iterator<Item = &TroutToken>) -> Result<(), Error> {
-        let mut token_inserter = self.0.prepare_cached(
-            r#"INSERT OR IGNORE INTO tokens (self_chain, self_id, owner) VALUES (?, ?, ?)"#,
-        )?;
-        let mut select_token_rowid = self
-            .0
-            .prepare_cached(r#"SELECT id FROM tokens WHERE self_chain = ? AND self_id = ?"#)?;
-        let mut metadata_inserter = self.0.prepare_cached(
-            r#"
-            INSERT INTO metadata (
-                token,
-                version, name,
-                fee,
-                is_genesis, is_santa,
-                left_parent_chain, left_parent_id,
-                right_parent_chain, right_parent_id
-            ) VALUES (
-                ?,
-                ?, ?,
-                ?,
-                ?, ?,
-                ?, ?,
-                ?, ?
-            )
-            "#,
-        )?;
-        let mut analysis_inserter = self
-            .0
-            .prepare_cached(r#"INSERT INTO analysis (token, coi) VALUES (?, ?)"#)?;
-        let mut generation_inserter = self.0.prepare_cached(
-            r#"INSERT OR IGNORE INTO generations (token, ord, cid) VALUES (?, ?, ?)"#,
-        )?;
-        for token in tokens {
-            debug_assert!(!token.meta.name.is_empty());
-            let props = &token.meta.properties;
-            token_inserter.execute((
-                props.self_id.chain_id,
-                props.self_id.token_id,
-                addr_to_hex(&token.owner),
-            ))?;
-            let token_rowid: i64 = select_token_rowid
-                .query_row([props.self_id.chain_id, props.self_id.token_id], |row| {
-                    row.get(0)
-                })?;
-            metadata_inserter.insert((
-                token_rowid,
-                props.version,
-                &token.meta.name,
-                token.fee.as_ref().map(u256_to_hex),
-                props.attributes.genesis,
-                props.attributes.santa,
-                props.left.map(|token_id| token_id.chain_id),
-                props.left.map(|token_id| token_id.token_id),
-                props.right.map(|token_id| token_id.chain_id),
-                props.right.map(|token_id| token_id.token_id),
-            ))?;
-            analysis_inserter.insert((token_rowid, token.coi))?;
-            generation_inserter.execute((token_rowid, props.generations.len(), &*token.cid))?;
-            for (ord, generation) in props.generations.iter().enumerate() {
-                debug_assert!(!generation.is_empty());
-                generation_inserter.execute((token_rowid, ord, &**generation))?;
-            }
+/// Declares a storage trait along with inherent methods on `$wrapper` that forward to the
+/// trait's implementation by the backend connection within.
+macro_rules! storage {
+    (
+        $(#[$trait_meta:meta])*
+        pub trait $trait:ident $(: $supertrait:ident)? for $wrapper:ident {
+            $(
+                $(#[$meta:meta])*
+                fn $method:ident(&self $(, $arg:ident: $ty:ty)* $(,)?) -> $ret:ty;
+            )*
+        }
+    ) => {
+        $(#[$trait_meta])*
+        pub trait $trait $(: $supertrait)? {
+            $(
+                $(#[$meta])*
+                fn $method(&self $(, $arg: $ty)*) -> $ret;
+            )*
         }
-        Ok(())
-    }
 
-    pub fn insert_pending_tokens(
-        &self,
-        chain_id: ChainId,
-        pending_tokens: impl Iterator<Item = (TokenId, PendingToken)>,
-    ) -> Result<(), Error> {
-        let mut token_inserter = self.0.prepare_cached(
-            r#"
-            INSERT INTO tokens (self_chain, self_id, owner)
-            VALUES (?, ?, ?)
-            ON CONFLICT (self_chain, self_id) DO NOTHING"#,
-        )?;
-        for (token_id, token) in pending_tokens {
-            token_inserter
-                .insert((chain_id, token_id, addr_to_hex(token.owner)))
-                .optional()?;
+        impl $wrapper<'_> {
+            $(
+                $(#[$meta])*
+                pub fn $method(&self $(, $arg: $ty)*) -> $ret {
+                    match self.connection() {
+                        Connection::Sqlite(conn) => $trait::$method(conn $(, $arg)*),
+                        #[cfg(feature = "postgres")]
+                        Connection::Postgres(conn) => $trait::$method(conn $(, $arg)*),
+                    }
+                }
+            )*
         }
-        Ok(())
-    }
+    };
+}
 
-    pub fn set_token_name(&self, id: TroutId, name: &str) -> Result<(), Error> {
-        self.0.execute(
-            r#"
-            UPDATE metadata
-               SET name = ?
-             WHERE token IN (SELECT id FROM tokens WHERE self_chain = ? AND self_id = ?)
-            "#,
-            (name, id.chain_id, id.token_id),
-        )?;
-        Ok(())
-    }
+storage! {
+    /// The queries that a database backend answers.
+    pub trait Storage for Connection {
+        fn latest_known_token_id(&self, chain_id: ChainId) -> Result<Option<TokenId>, Error>;
 
-    pub fn set_cois(&self, cois: impl Iterator<Item = (TroutId, f64)>) -> Result<(), Error> {
-        let mut updater = self.0.prepare_cached(
-            r#"
-            UPDATE analysis
-               SET coi = ?
-             WHERE token IN (SELECT id FROM tokens WHERE self_chain = ? AND self_id = ?)
-            "#,
-        )?;
-        for (trout_id, coi) in cois {
-            updater.execute((coi, trout_id.chain_id, trout_id.token_id))?;
-        }
-        Ok(())
-    }
+        fn latest_processed_block(&self, chain_id: ChainId) -> Result<u64, Error>;
 
-    pub fn update_fees(
-        &self,
-        chain_id: ChainId,
-        tokens: impl Iterator<Item = (TokenId, Option<&U256>)>,
-    ) -> Result<(), Error> {
-        let mut fee_updater = self.0.prepare_cached(
-            r#"
-                UPDATE metadata
-                   SET fee = ?
-                 WHERE token IN (SELECT id FROM tokens WHERE self_chain = ? AND self_id = ?)
-                "#,
-        )?;
-        for (token_id, fee) in tokens {
-            fee_updater.execute((fee.map(u256_to_hex), chain_id, token_id))?;
-        }
-        Ok(())
+        fn block_hash(&self, chain_id: ChainId, block: u64) -> Result<Option<H256>, Error>;
+
+        /// Returns the ids of tokens having events recorded after `block`.
+        fn tokens_changed_since(&self, chain_id: ChainId, block: u64)
+            -> Result<Vec<TokenId>, Error>;
+
+        fn token_ids(&self, chain_id: ChainId) -> Result<Vec<TokenId>, Error>;
+
+        fn needs_coi_analysis(&self, chain_id: ChainId) -> Result<Vec<TroutId>, Error>;
+
+        /// Returns the earliest blocks containing events whose timestamps are not yet known.
+        fn blocks_missing_timestamps(&self, chain_id: ChainId, limit: usize)
+            -> Result<Vec<u64>, Error>;
+
+        fn token_lifecycle(&self, id: TroutId) -> Result<Option<TokenLifecycle>, Error>;
+
+        /// Returns the lifecycles of the chain's tokens whose metadata has not yet been indexed.
+        fn waiting_tokens(&self, chain_id: ChainId) -> Result<Vec<TokenLifecycle>, Error>;
+
+        fn outdated_token_ids(&self, chain_id: ChainId) -> Result<Vec<TokenId>, Error>;
+
+        fn unpinned_cids(&self) -> Result<Vec<Cid>, Error>;
+
+        fn token_cid(&self, id: &TroutId, ord: Option<u32>) -> Result<Option<Cid>, Error>;
+
+        /// Returns the token's metadata generations, oldest first.
+        fn token_generations(&self, id: TroutId) -> Result<Vec<Generation>, Error>;
+
+        /// Returns the latest CIDs of tokens that are missing earlier generations.
+        fn tokens_missing_generations(&self, chain_id: ChainId)
+            -> Result<Vec<(TroutId, Cid)>, Error>;
+
+        /// Records the earlier generations of an indexed token, as listed in its latest metadata.
+        fn insert_generations(&self, id: TroutId, generations: &[Cid]) -> Result<(), Error>;
+
+        fn list_tokens_for_ui(&self, chain_id: impl Into<Option<ChainId>>)
+            -> Result<Vec<TokenForUi>, Error>;
+
+        /// Returns the tokens matching `query` along with the cursor of the next page, if the page
+        /// was filled to its limit.
+        fn query_tokens_for_ui(
+            &self,
+            chain_id: impl Into<Option<ChainId>>,
+            query: &TokenQuery,
+        ) -> Result<(Vec<TokenForUi>, Option<TokenCursor>), Error>;
+
+        /// Returns the total stud fees paid to `owner` by other breeders on the chain.
+        ///
+        /// Each breeding pays the fee at which a parent was listed to whoever owned it at the time,
+        /// unless the breeder was that owner.
+        fn stud_earnings(&self, chain_id: ChainId, owner: &Address) -> Result<U256, Error>;
+
+        /// Returns the chain's market statistics, counting only breedings in blocks timestamped at
+        /// or after `since`, if provided, and including up to `top` of the highest earning studs.
+        /// Breedings in blocks not yet timestamped are counted if they follow the last block known
+        /// to precede `since`.
+        fn market_stats(&self, chain_id: ChainId, since: Option<u64>, top: usize)
+            -> Result<MarketStats, Error>;
+
+        /// Returns the total stud fees paid to each owner in breedings through `block`.
+        fn accrued_earnings(&self, chain_id: ChainId, block: u64)
+            -> Result<HashMap<Address, U256>, Error>;
+
+        /// Returns the stud fees paid to the owner, oldest first.
+        fn stud_payments(&self, chain_id: ChainId, owner: &Address)
+            -> Result<Vec<StudPayment>, Error>;
+
+        fn earnings_reconciliation(&self, chain_id: ChainId, owner: &Address)
+            -> Result<Option<EarningsReconciliation>, Error>;
+
+        /// Returns the token's provenance, oldest first, beginning after the cursor.
+        fn token_events(
+            &self,
+            token: TroutId,
+            after: Option<EventCursor>,
+            limit: Option<u32>,
+        ) -> Result<(Vec<EventForUi>, Option<EventCursor>), Error>;
+
+        /// Returns the chain's marketplace activity across all tokens, newest first, beginning
+        /// before the cursor. Each breeding appears once, as the use of its left parent.
+        fn activity(
+            &self,
+            chain_id: ChainId,
+            before: Option<EventCursor>,
+            limit: u32,
+        ) -> Result<(Vec<EventForUi>, Option<EventCursor>), Error>;
+
+        /// Returns the chain's marketplace activity within the (inclusive) block range, oldest
+        /// first.
+        fn activity_in_blocks(&self, chain_id: ChainId, blocks: std::ops::RangeInclusive<u64>)
+            -> Result<Vec<EventForUi>, Error>;
+
+        /// Returns the token's current owner and stud fee, if it is indexed.
+        fn stud_terms(&self, id: TroutId) -> Result<Option<(Address, Option<U256>)>, Error>;
+
+        /// Returns the latest indexed mint reward and matchmaking fee, which are unknown until the
+        /// contract owner first changes them.
+        fn breeding_rates(&self, chain_id: ChainId) -> Result<(Option<U256>, Option<U256>), Error>;
+
+        /// Returns the chain's contract configuration changes, oldest first.
+        fn contract_events(&self, chain_id: ChainId) -> Result<Vec<ContractEventForUi>, Error>;
+
+        /// Returns the latest change of each kind of contract configuration, oldest first.
+        fn latest_contract_changes(&self, chain_id: ChainId)
+            -> Result<Vec<ContractEventKind>, Error>;
+
+        /// Inserts the tokens, or updates them if they were already indexed, as when re-indexing a
+        /// newer version of their metadata. Names set by owners are kept.
+        fn insert_tokens(&self, tokens: impl Iterator<Item = &TroutToken>) -> Result<(), Error>;
+
+        fn insert_pending_tokens(
+            &self,
+            chain_id: ChainId,
+            pending_tokens: impl Iterator<Item = (TokenId, PendingToken)>,
+        ) -> Result<(), Error>;
+
+        /// Removes tokens that have not yet had their metadata indexed.
+        fn remove_pending_tokens(
+            &self,
+            chain_id: ChainId,
+            token_ids: impl Iterator<Item = TokenId>,
+        ) -> Result<(), Error>;
+
+        fn set_token_name(&self, id: TroutId, name: &str) -> Result<(), Error>;
+
+        fn set_cois(&self, cois: impl Iterator<Item = (TroutId, f64)>) -> Result<(), Error>;
+
+        fn update_fees(
+            &self,
+            chain_id: ChainId,
+            tokens: impl Iterator<Item = (TokenId, Option<&U256>)>,
+        ) -> Result<(), Error>;
+
+        fn update_owners(
+            &self,
+            chain: ChainId,
+            token_owners: impl Iterator<Item = (TokenId, &Address)>,
+        ) -> Result<(), Error>;
+
+        fn mark_pinned(&self, cids: impl Iterator<Item = &Cid>) -> Result<(), Error>;
+
+        fn mark_pin_failed(&self, cids: impl Iterator<Item = &Cid>) -> Result<(), Error>;
     }
+}
 
-    pub fn update_owners(
-        &self,
-        chain: ChainId,
-        token_owners: impl Iterator<Item = (TokenId, &Address)>,
-    ) -> Result<(), Error> {
-        let mut updater = self.0.prepare_cached(
-            r#"UPDATE tokens SET owner = ? WHERE self_chain = ? AND self_id = ?"#,
-        )?;
-        for (token_id, owner) in token_owners {
-            updater.execute((format!("{owner:x}"), chain, token_id))?;
-        }
-        Ok(())
+storage! {
+    /// The writes that a database backend makes only within a transaction.
+    pub trait StorageTx: Storage for Transaction {
+        /// Sets the block from which the chain's events are indexed, unless indexing has begun.
+        fn init_progress(&self, chain: ChainId, start_block: u64) -> Result<(), Error>;
+
+        fn record_events(&self, chain: ChainId, events: impl Iterator<Item = &Event>)
+            -> Result<(), Error>;
+
+        /// Records the owners' accrued and withdrawable earnings as of `block`, replacing older
+        /// ones.
+        fn record_earnings_reconciliations(
+            &self,
+            chain: ChainId,
+            block: u64,
+            reconciliations: impl Iterator<Item = (Address, U256, U256)>,
+        ) -> Result<(), Error>;
+
+        /// Deletes all events and block hashes recorded after `fork_block`
+        /// and moves the chain's progress back to it, if it had gone further.
+        fn roll_back_events(&self, chain: ChainId, fork_block: u64) -> Result<(), Error>;
     }
+}
 
-    pub fn mark_pinned<'a>(&self, cids: impl Iterator<Item = &'a Cid>) -> Result<(), Error> {
-        let mut updater = self
-            .0
-            .prepare_cached(r#"UPDATE generations SET pinned = 1 WHERE cid = ?"#)?;
-        for cid in cids {
-            updater.execute([&**cid])?;
-        }
-        Ok(())
+/// Filters and ordering applied when listing tokens. Unset filters match every token.
+#[derive(Clone, Debug, Default)]
+pub struct TokenQuery {
+    pub owner: Option<Address>,
+    pub listed: Option<bool>,
+    pub min_fee: Option<U256>,
+    pub max_fee: Option<U256>,
+    pub min_coi: Option<f64>,
+    pub max_coi: Option<f64>,
+    pub genesis: Option<bool>,
+    pub santa: Option<bool>,
+    /// Matches the children of this token on the same chain.
+    pub parent: Option<TokenId>,
+    /// Matches names containing this string, ignoring ASCII case.
+    pub name: Option<String>,
+    pub sort: TokenSort,
+    /// Resumes the listing after the last token of a previous page.
+    pub after: Option<TokenCursor>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenSort {
+    #[default]
+    Id,
+    /// Lowest fee first, followed by unlisted tokens.
+    Fee,
+    /// Lowest COI first. Tokens awaiting analysis come first.
+    Coi,
+    /// Most recently minted first.
+    Newest,
+    /// Earliest mint time first, followed by tokens whose mint time is not yet known.
+    Minted,
+}
+
+/// Selects a page of [`EventForUi`]s.
+#[derive(Clone, Debug, Default)]
+struct FeedQuery {
+    /// Restricts the feed to a single token's provenance.
+    token: Option<TokenId>,
+    after: Option<EventCursor>,
+    before: Option<EventCursor>,
+    blocks: Option<std::ops::RangeInclusive<u64>>,
+    newest_first: bool,
+    limit: Option<u32>,
+}
+
+/// The position of an event in a feed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventCursor {
+    block: u64,
+    log_index: u64,
+    batch_index: u32,
+}
+
+impl std::str::FromStr for EventCursor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s
+            .splitn(3, ':')
+            .map(|part| part.parse::<u64>().map_err(drop));
+        let mut next = || parts.next().ok_or(())?;
+        Ok(Self {
+            block: next()?,
+            log_index: next()?,
+            batch_index: next()?.try_into().map_err(drop)?,
+        })
     }
+}
 
-    pub fn mark_pin_failed<'a>(&self, cids: impl Iterator<Item = &'a Cid>) -> Result<(), Error> {
-        let mut updater = self
-            .0
-            .prepare_cached(r#"UPDATE generations SET pin_fails = pin_fails + 1 WHERE cid = ?"#)?;
-        for cid in cids {
-            updater.execute([&**cid])?;
-        }
-        Ok(())
+impl std::fmt::Display for EventCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.block, self.log_index, self.batch_index)
     }
 }
 
-pub struct Transaction<'a>(Connection<'a>);
+/// The position of a token in a listing, as the pair of its sort key and id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenCursor {
+    key: SortKey,
+    id: TokenId,
+}
 
-impl<'a> std::ops::Deref for Transaction<'a> {
-    type Target = Connection<'a>;
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
+/// The value by which a token is sorted, whose type depends on the [`TokenSort`].
+#[derive(Clone, Debug, PartialEq)]
+enum SortKey {
+    Integer(i64),
+    Real(f64),
+    Text(String),
 }
 
-impl Transaction<'_> {
-    fn migrate(&self, migrations: &[&str]) -> Result<usize, Error> {
-        let tx = &self.0 .0;
-        static USER_VERSION: &str = "user_version";
-        let version =
-            tx.pragma_query_value(None, USER_VERSION, |row| row.get::<_, u32>(0))? as usize;
-        debug!(
-            current_version = version,
-            latest_version = migrations.len(),
-            "DbConnection::migrate"
-        );
-        if version >= migrations.len() {
-            if version > migrations.len() {
-                panic!("databse version unexpectedly high?");
+impl TokenCursor {
+    pub fn parse(sort: TokenSort, s: &str) -> Option<Self> {
+        let (key, id) = s.rsplit_once(':')?;
+        let key = match sort {
+            TokenSort::Id | TokenSort::Newest | TokenSort::Minted => {
+                SortKey::Integer(key.parse().ok()?)
             }
-            return Ok(version);
-        }
-        for migration in migrations.iter().skip(version) {
-            tx.execute_batch(migration)?;
-        }
-        tx.pragma_update(None, USER_VERSION, migrations.len())?;
-        Ok(version)
+            TokenSort::Fee => SortKey::Text(key.into()),
+            TokenSort::Coi => SortKey::Real(key.parse().ok()?),
+        };
+        Some(Self {
+            key,
+            id: id.parse().ok()?,
+        })
     }
+}
 
-    pub fn record_events(
-        &self,
-        chain: ChainId,
-        events: impl Iterator<Item = &Event>,
-    ) -> Result<(), Error> {
-        let conn = &self.0 .0;
-        let mut event_inserter = conn.prepare_cached(
-            r#"
-            INSERT OR IGNORE INTO events (kind, token, block, log_index)
-            VALUES (?, ?, ?, ?)
-            RETURNING id
-            "#,
-        )?;
-        let mut list_event_inserter =
-            conn.prepare_cached(r#"INSERT INTO list_events (event, fee) VALUES (?, ?)"#)?;
-        let mut spawn_event_inserter =
-            conn.prepare_cached(r#"INSERT INTO spawn_events (event, recipient) VALUES (?, ?)"#)?;
-        let mut transfer_event_inserter = conn.prepare_cached(
-            r#"INSERT INTO transfer_events (event, sender, recipient) VALUES (?, ?, ?)"#,
-        )?;
-        let mut progress_updater =
-            conn.prepare_cached(r#"UPDATE progress SET block = ? WHERE chain = ?"#)?;
-
-        let mut last_block = None;
-        for event in events {
-            trace!(event = ?event, "processing event");
-            match event {
-                Event::Token(TokenEvent {
-                    token,
-                    kind,
-                    block,
-                    log_index,
-                }) => {
-                    let rowid = event_inserter
-                        .query_row(
-                            (
-                                match kind {
-                                    TokenEventKind::Spawned { .. } => 1,
-                                    TokenEventKind::Relisted { .. } => 2,
-                                    TokenEventKind::Transfer { .. } => 3,
-                                },
-                                token,
-                                block,
-                                log_index,
-                            ),
-                            |row| row.get::<_, i64>(0),
-                        )
-                        .optional()?;
-                    let event_id = match rowid {
-                        Some(id) => id,
-                        None => continue,
-                    };
-                    match kind {
-                        TokenEventKind::Spawned { to } => {
-                            spawn_event_inserter.insert((event_id, addr_to_hex(to)))?;
-                        }
-                        TokenEventKind::Relisted { fee } => {
-                            list_event_inserter
-                                .insert((event_id, fee.as_ref().map(u256_to_hex)))?;
-                        }
-                        TokenEventKind::Transfer { from, to } => {
-                            transfer_event_inserter.insert((
-                                event_id,
-                                addr_to_hex(from),
-                                addr_to_hex(to),
-                            ))?;
-                        }
-                    }
-                }
-                Event::ProcessedBlock(block) => {
-                    last_block = Some(block);
-                }
-            }
+impl std::fmt::Display for TokenCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.key {
+            SortKey::Integer(i) => write!(f, "{i}:{}", self.id),
+            SortKey::Real(r) => write!(f, "{r}:{}", self.id),
+            SortKey::Text(t) => write!(f, "{t}:{}", self.id),
         }
-        if let Some(block) = last_block {
-            trace!(block = block, "processed block");
-            progress_updater.execute((last_block, chain))?;
-        }
-        Ok(())
     }
 }
 
+/// Returns the kind, new value, and previous value with which a contract event is stored.
+fn contract_event_columns(kind: &ContractEventKind) -> (&'static str, String, Option<String>) {
+    use ContractEventKind::*;
+    match kind {
+        MatchmakingFeeChanged { bps } => ("matchmaking_fee_changed", u256_to_hex(bps), None),
+        MintRewardChanged { reward } => ("mint_reward_changed", u256_to_hex(reward), None),
+        Paused { account } => ("paused", addr_to_hex(account), None),
+        Unpaused { account } => ("unpaused", addr_to_hex(account), None),
+        TaskAcceptorChanged { to } => ("task_acceptor_changed", addr_to_hex(to), None),
+        TaskHubChanged { to } => ("task_hub_changed", addr_to_hex(to), None),
+        OwnershipTransferred { from, to } => (
+            "ownership_transferred",
+            addr_to_hex(to),
+            Some(addr_to_hex(from)),
+        ),
+    }
+}
+
+fn contract_event_kind(
+    kind: &str,
+    value: &str,
+    previous: Option<&str>,
+) -> Option<ContractEventKind> {
+    use ContractEventKind::*;
+    Some(match kind {
+        "matchmaking_fee_changed" => MatchmakingFeeChanged {
+            bps: value.parse().ok()?,
+        },
+        "mint_reward_changed" => MintRewardChanged {
+            reward: value.parse().ok()?,
+        },
+        "paused" => Paused {
+            account: value.parse().ok()?,
+        },
+        "unpaused" => Unpaused {
+            account: value.parse().ok()?,
+        },
+        "task_acceptor_changed" => TaskAcceptorChanged {
+            to: value.parse().ok()?,
+        },
+        "task_hub_changed" => TaskHubChanged {
+            to: value.parse().ok()?,
+        },
+        "ownership_transferred" => OwnershipTransferred {
+            from: previous?.parse().ok()?,
+            to: value.parse().ok()?,
+        },
+        _ => return None,
+    })
+}
+
 fn u256_to_hex(big: &U256) -> String {
     format!("{big:#x}")
 }
@@ -616,8 +567,42 @@ fn addr_to_hex(addr: &Address) -> String {
     format!("{addr:#x}")
 }
 
+fn h256_to_hex(hash: &H256) -> String {
+    format!("{hash:#x}")
+}
+
+#[cfg(test)]
+fn random_name() -> String {
+    let mut rng = rand::thread_rng();
+    (0..7)
+        .map(|_| rand::Rng::sample(&mut rng, rand::distributions::Alphanumeric) as char)
+        .collect()
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("database driver error: {0}")]
     Driver(#[from] rusqlite::Error),
+    #[cfg(feature = "postgres")]
+    #[error("database driver error: {0}")]
+    Postgres(#[from] ::postgres::Error),
+    #[error(
+        "the database has {applied} migrations applied{}, but this build knows only {known}; \
+         run a newer build, or use one to revert the schema",
+        latest.as_ref().map(|name| format!(" up to {name}")).unwrap_or_default()
+    )]
+    SchemaAhead {
+        applied: usize,
+        latest: Option<String>,
+        known: usize,
+    },
+    #[error("migration {0} differs from the one applied to the database")]
+    MigrationChanged(String),
+    #[error("no migration brings the schema to version {0}")]
+    UnknownVersion(usize),
+    #[error("unknown event kind {0:?}")]
+    UnknownEventKind(String),
+    #[cfg(not(feature = "postgres"))]
+    #[error("this build does not support {0} databases")]
+    Unsupported(&'static str),
 }
@@ -0,0 +1,1789 @@
+//! Storage in a PostgreSQL database, as enabled by the `postgres` feature.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use ethers::types::{Address, H256, U256};
+use parking_lot::Mutex;
+use postgres::{types::ToSql, GenericClient, NoTls, Row, Statement};
+use tracing::trace;
+
+use super::{
+    addr_to_hex, contract_event_columns, contract_event_kind, h256_to_hex,
+    migrate::{migration, AppliedMigration, Migration, MigrationStore},
+    u256_to_hex, EventCursor, FeedQuery, SortKey, Storage, StorageTx, TokenCursor, TokenQuery,
+    TokenSort, BLOCK_HASH_RETENTION,
+};
+use crate::{
+    db::Error,
+    ipfs::Cid,
+    nftrout::{
+        BlockHeader, ChainId, ContractEvent, ContractEventForUi, ContractEventKind,
+        EarningsReconciliation, Event, EventForUi, EventKindForUi, Generation, Lifecycle,
+        MarketStats, PendingToken, StudEarnings, StudPayment, TokenEvent, TokenEventKind,
+        TokenForUi, TokenId, TokenLifecycle, TroutId, TroutToken,
+    },
+};
+
+/// The number of idle reader connections kept open for reuse.
+const MAX_IDLE_READERS: usize = 16;
+
+pub(super) const MIGRATIONS: &[Migration] = &[migration!("postgres", "00-init")];
+
+type Params<'a> = [&'a (dyn ToSql + Sync)];
+
+pub(super) struct Pool {
+    config: postgres::Config,
+    /// The connection used for all transactions, so that writers queue here rather than
+    /// failing to serialize with one another. It is taken when the pool is dropped.
+    writer: Mutex<Option<postgres::Client>>,
+    readers: Mutex<Vec<postgres::Client>>,
+    /// The schema created for a test, which is dropped along with the pool.
+    #[cfg(test)]
+    scratch_schema: Option<String>,
+}
+
+impl Pool {
+    pub(super) fn open(url: &str) -> Result<Self, Error> {
+        Self::with_config(url.parse()?)
+    }
+
+    /// Opens a pool whose connections use a new `schema` of the database at `url`.
+    #[cfg(test)]
+    pub(super) fn open_scratch(url: &str, schema: String) -> Result<Self, Error> {
+        let mut config: postgres::Config = url.parse()?;
+        config
+            .connect(NoTls)?
+            .batch_execute(&format!("CREATE SCHEMA {schema}"))?;
+        config.options(&format!("-c search_path={schema}"));
+        let mut this = Self::with_config(config)?;
+        this.scratch_schema = Some(schema);
+        Ok(this)
+    }
+
+    fn with_config(config: postgres::Config) -> Result<Self, Error> {
+        let writer = config.connect(NoTls)?;
+        Ok(Self {
+            config,
+            writer: Mutex::new(Some(writer)),
+            readers: Default::default(),
+            #[cfg(test)]
+            scratch_schema: None,
+        })
+    }
+
+    pub(super) fn with_conn<T>(
+        &self,
+        f: impl FnOnce(Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut client = match self.readers.lock().pop() {
+            Some(client) if !client.is_closed() => client,
+            _ => self.config.connect(NoTls)?,
+        };
+        let res = f(Connection(RefCell::new(&mut client)));
+        let mut readers = self.readers.lock();
+        if readers.len() < MAX_IDLE_READERS {
+            readers.push(client);
+        }
+        res
+    }
+
+    pub(super) fn with_tx<T>(
+        &self,
+        f: impl FnOnce(Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut writer = self.writer.lock();
+        let writer = match &mut *writer {
+            Some(client) if !client.is_closed() => client,
+            writer => writer.insert(self.config.connect(NoTls)?),
+        };
+        // The transaction is rolled back if dropped before it is committed.
+        let mut tx = writer.transaction()?;
+        let res = f(Connection(RefCell::new(&mut tx)))?;
+        tx.commit()?;
+        Ok(res)
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        let writer = self.writer.get_mut().take();
+        let readers = std::mem::take(self.readers.get_mut());
+        #[cfg(test)]
+        let (config, scratch_schema) = (self.config.clone(), self.scratch_schema.take());
+        // Clients block on a runtime of their own to close, which cannot be done on a thread of
+        // the async runtime that the last handle to the pool may be dropped on.
+        std::thread::spawn(move || {
+            drop((writer, readers));
+            #[cfg(test)]
+            if let Some(schema) = scratch_schema {
+                config
+                    .connect(NoTls)
+                    .and_then(|mut client| {
+                        client.batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+                    })
+                    .ok();
+            }
+        })
+        .join()
+        .ok();
+    }
+}
+
+/// The methods of a [`postgres::Client`] or [`postgres::Transaction`] used by a [`Connection`],
+/// which unlike [`GenericClient`] can be made into an object.
+trait Queryable {
+    fn prepare(&mut self, query: &str) -> Result<Statement, postgres::Error>;
+    fn query(&mut self, stmt: &Statement, params: &Params) -> Result<Vec<Row>, postgres::Error>;
+    fn query_one(&mut self, stmt: &Statement, params: &Params) -> Result<Row, postgres::Error>;
+    fn query_opt(
+        &mut self,
+        stmt: &Statement,
+        params: &Params,
+    ) -> Result<Option<Row>, postgres::Error>;
+    fn execute(&mut self, stmt: &Statement, params: &Params) -> Result<u64, postgres::Error>;
+    fn batch_execute(&mut self, query: &str) -> Result<(), postgres::Error>;
+}
+
+impl<C: GenericClient> Queryable for C {
+    fn prepare(&mut self, query: &str) -> Result<Statement, postgres::Error> {
+        GenericClient::prepare(self, query)
+    }
+
+    fn query(&mut self, stmt: &Statement, params: &Params) -> Result<Vec<Row>, postgres::Error> {
+        GenericClient::query(self, stmt, params)
+    }
+
+    fn query_one(&mut self, stmt: &Statement, params: &Params) -> Result<Row, postgres::Error> {
+        GenericClient::query_one(self, stmt, params)
+    }
+
+    fn query_opt(
+        &mut self,
+        stmt: &Statement,
+        params: &Params,
+    ) -> Result<Option<Row>, postgres::Error> {
+        GenericClient::query_opt(self, stmt, params)
+    }
+
+    fn execute(&mut self, stmt: &Statement, params: &Params) -> Result<u64, postgres::Error> {
+        GenericClient::execute(self, stmt, params)
+    }
+
+    fn batch_execute(&mut self, query: &str) -> Result<(), postgres::Error> {
+        GenericClient::batch_execute(self, query)
+    }
+}
+
+pub struct Connection<'a>(RefCell<&'a mut (dyn Queryable + 'a)>);
+
+impl Connection<'_> {
+    fn prepare(&self, query: &str) -> Result<Statement, Error> {
+        Ok(self.0.borrow_mut().prepare(query)?)
+    }
+
+    fn query(&self, stmt: &Statement, params: &Params) -> Result<Vec<Row>, Error> {
+        Ok(self.0.borrow_mut().query(stmt, params)?)
+    }
+
+    fn query_one(&self, stmt: &Statement, params: &Params) -> Result<Row, Error> {
+        Ok(self.0.borrow_mut().query_one(stmt, params)?)
+    }
+
+    fn query_opt(&self, stmt: &Statement, params: &Params) -> Result<Option<Row>, Error> {
+        Ok(self.0.borrow_mut().query_opt(stmt, params)?)
+    }
+
+    fn execute(&self, stmt: &Statement, params: &Params) -> Result<u64, Error> {
+        Ok(self.0.borrow_mut().execute(stmt, params)?)
+    }
+
+    pub(super) fn execute_batch(&self, query: &str) -> Result<(), Error> {
+        Ok(self.0.borrow_mut().batch_execute(query)?)
+    }
+}
+
+impl MigrationStore for Connection<'_> {
+    fn create_migrations_table(&self) -> Result<(), Error> {
+        // Concurrently starting indexers wait here for the first to finish migrating.
+        self.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS schema_migrations (
+              ord BIGINT PRIMARY KEY,
+              name TEXT NOT NULL,
+              checksum TEXT NOT NULL,
+              applied_at BIGINT NOT NULL
+            );
+            LOCK TABLE schema_migrations IN EXCLUSIVE MODE;
+            "#,
+        )
+    }
+
+    fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, Error> {
+        let exists: bool = self
+            .query_one(
+                &self.prepare("SELECT to_regclass('schema_migrations') IS NOT NULL")?,
+                &[],
+            )?
+            .try_get(0)?;
+        if !exists {
+            return Ok(Vec::new());
+        }
+        self.query(
+            &self.prepare("SELECT ord, name, checksum FROM schema_migrations ORDER BY ord ASC")?,
+            &[],
+        )?
+        .iter()
+        .map(|row| {
+            Ok(AppliedMigration {
+                ord: row.try_get::<_, i64>(0)? as usize,
+                name: row.try_get(1)?,
+                checksum: row.try_get(2)?,
+            })
+        })
+        .collect()
+    }
+
+    fn legacy_version(&self) -> Result<usize, Error> {
+        let exists: bool = self
+            .query_one(
+                &self.prepare("SELECT to_regclass('schema_version') IS NOT NULL")?,
+                &[],
+            )?
+            .try_get(0)?;
+        if !exists {
+            return Ok(0);
+        }
+        Ok(self
+            .query_opt(&self.prepare("SELECT version FROM schema_version")?, &[])?
+            .map(|row| row.try_get::<_, i64>(0))
+            .transpose()?
+            .unwrap_or_default() as usize)
+    }
+
+    fn set_legacy_version(&self, version: usize) -> Result<(), Error> {
+        self.execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_version (version BIGINT NOT NULL);
+             DELETE FROM schema_version;",
+        )?;
+        self.execute(
+            &self.prepare("INSERT INTO schema_version (version) VALUES ($1)")?,
+            &[&(version as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn execute_migration(&self, sql: &str) -> Result<(), Error> {
+        self.execute_batch(sql)
+    }
+
+    fn record_migration(&self, ord: usize, migration: &Migration) -> Result<(), Error> {
+        self.execute(
+            &self.prepare(
+                r#"
+                INSERT INTO schema_migrations (ord, name, checksum, applied_at)
+                VALUES ($1, $2, $3, EXTRACT(EPOCH FROM now())::BIGINT)
+                "#,
+            )?,
+            &[&(ord as i64), &migration.name, &migration.checksum()],
+        )?;
+        Ok(())
+    }
+
+    fn forget_migration(&self, ord: usize) -> Result<(), Error> {
+        self.execute(
+            &self.prepare("DELETE FROM schema_migrations WHERE ord = $1")?,
+            &[&(ord as i64)],
+        )?;
+        Ok(())
+    }
+}
+
+impl Storage for Connection<'_> {
+    fn latest_known_token_id(&self, chain_id: ChainId) -> Result<Option<TokenId>, Error> {
+        let row = self.query_one(
+            &self.prepare("SELECT MAX(self_id) FROM tokens WHERE self_chain = $1")?,
+            &[&i64::from(chain_id)],
+        )?;
+        Ok(row.try_get::<_, Option<i64>>(0)?.map(|id| id as TokenId))
+    }
+
+    fn latest_processed_block(&self, chain_id: ChainId) -> Result<u64, Error> {
+        let row = self.query_one(
+            &self.prepare("SELECT block FROM progress WHERE chain = $1")?,
+            &[&i64::from(chain_id)],
+        )?;
+        Ok(row.try_get::<_, i64>(0)? as u64)
+    }
+
+    fn block_hash(&self, chain_id: ChainId, block: u64) -> Result<Option<H256>, Error> {
+        self.query_opt(
+            &self.prepare("SELECT hash FROM blocks WHERE chain = $1 AND number = $2")?,
+            &[&i64::from(chain_id), &(block as i64)],
+        )?
+        .map(|row| Ok(row.try_get::<_, &str>(0)?.parse().unwrap()))
+        .transpose()
+    }
+
+    fn tokens_changed_since(&self, chain_id: ChainId, block: u64) -> Result<Vec<TokenId>, Error> {
+        self.query(
+            &self.prepare(
+                "SELECT DISTINCT token FROM events WHERE chain = $1 AND block > $2 ORDER BY token",
+            )?,
+            &[&i64::from(chain_id), &(block as i64)],
+        )?
+        .iter()
+        .map(|row| Ok(row.try_get::<_, i64>(0)? as TokenId))
+        .collect()
+    }
+
+    fn token_ids(&self, chain_id: ChainId) -> Result<Vec<TokenId>, Error> {
+        self.query(
+            &self
+                .prepare("SELECT self_id FROM tokens WHERE self_chain = $1 ORDER BY self_id ASC")?,
+            &[&i64::from(chain_id)],
+        )?
+        .iter()
+        .map(|row| Ok(row.try_get::<_, i64>(0)? as TokenId))
+        .collect()
+    }
+
+    fn needs_coi_analysis(&self, chain_id: ChainId) -> Result<Vec<TroutId>, Error> {
+        self.query(
+            &self.prepare(
+                r#"
+                SELECT self_id
+                  FROM tokens
+                  JOIN analysis
+                    ON analysis.token = tokens.id
+                 WHERE coi = -1
+                   AND self_chain = $1
+                "#,
+            )?,
+            &[&i64::from(chain_id)],
+        )?
+        .iter()
+        .map(|row| {
+            Ok(TroutId {
+                chain_id,
+                token_id: row.try_get::<_, i64>(0)? as TokenId,
+            })
+        })
+        .collect()
+    }
+
+    fn blocks_missing_timestamps(
+        &self,
+        chain_id: ChainId,
+        limit: usize,
+    ) -> Result<Vec<u64>, Error> {
+        self.query(
+            &self.prepare(
+                r#"
+                SELECT eventful.block
+                  FROM (
+                      SELECT block FROM events WHERE chain = $1
+                       UNION
+                      SELECT block FROM contract_events WHERE chain = $1
+                  ) AS eventful
+                 WHERE NOT EXISTS (
+                     SELECT 1 FROM blocks
+                      WHERE blocks.chain = $1
+                        AND blocks.number = eventful.block
+                        AND blocks.timestamp IS NOT NULL
+                 )
+                 ORDER BY eventful.block ASC
+                 LIMIT $2
+                "#,
+            )?,
+            &[&i64::from(chain_id), &(limit as i64)],
+        )?
+        .iter()
+        .map(|row| Ok(row.try_get::<_, i64>(0)? as u64))
+        .collect()
+    }
+
+    fn token_lifecycle(&self, id: TroutId) -> Result<Option<TokenLifecycle>, Error> {
+        Ok(self
+            .query_lifecycles(id.chain_id, Some(id.token_id), false)?
+            .pop())
+    }
+
+    fn waiting_tokens(&self, chain_id: ChainId) -> Result<Vec<TokenLifecycle>, Error> {
+        self.query_lifecycles(chain_id, None, true)
+    }
+
+    fn outdated_token_ids(&self, chain_id: ChainId) -> Result<Vec<TokenId>, Error> {
+        self.query(
+            &self.prepare(
+                r#"
+                SELECT tokens.self_id
+                  FROM tokens
+                  LEFT JOIN metadata ON metadata.token = tokens.id
+                 WHERE tokens.self_chain = $1
+                   AND (metadata.version IS NULL OR metadata.version < $2)
+                "#,
+            )?,
+            &[
+                &i64::from(chain_id),
+                &i64::from(crate::nftrout::CURRENT_VERSION),
+            ],
+        )?
+        .iter()
+        .map(|row| Ok(row.try_get::<_, i64>(0)? as TokenId))
+        .collect()
+    }
+
+    fn unpinned_cids(&self) -> Result<Vec<Cid>, Error> {
+        self.query(
+            &self.prepare("SELECT cid FROM generations WHERE NOT pinned AND pin_fails < 20")?,
+            &[],
+        )?
+        .iter()
+        .map(|row| Ok(row.try_get::<_, String>(0)?.into()))
+        .collect()
+    }
+
+    fn token_cid(
+        &self,
+        TroutId { chain_id, token_id }: &TroutId,
+        ord: Option<u32>,
+    ) -> Result<Option<Cid>, Error> {
+        self.query_opt(
+            &self.prepare(
+                r#"
+                SELECT generations.cid FROM generations
+                JOIN tokens ON tokens.id = generations.token
+                WHERE tokens.self_chain = $1
+                AND tokens.self_id = $2
+                AND generations.ord = coalesce(
+                        $3::BIGINT,
+                        (SELECT MAX(ord) FROM generations WHERE generations.token = tokens.id)
+                    )
+                "#,
+            )?,
+            &[
+                &i64::from(*chain_id),
+                &i64::from(*token_id),
+                &ord.map(i64::from),
+            ],
+        )?
+        .map(|row| Ok(row.try_get::<_, String>(0)?.into()))
+        .transpose()
+    }
+
+    fn token_generations(&self, id: TroutId) -> Result<Vec<Generation>, Error> {
+        self.query(
+            &self.prepare(
+                r#"
+                SELECT generations.ord, generations.cid, generations.pinned
+                  FROM generations
+                  JOIN tokens ON tokens.id = generations.token
+                 WHERE tokens.self_chain = $1 AND tokens.self_id = $2
+                 ORDER BY generations.ord ASC
+                "#,
+            )?,
+            &[&i64::from(id.chain_id), &i64::from(id.token_id)],
+        )?
+        .iter()
+        .map(|row| {
+            Ok(Generation {
+                ord: row.try_get::<_, i64>(0)? as u32,
+                cid: row.try_get::<_, String>(1)?.into(),
+                pinned: row.try_get(2)?,
+            })
+        })
+        .collect()
+    }
+
+    fn tokens_missing_generations(&self, chain_id: ChainId) -> Result<Vec<(TroutId, Cid)>, Error> {
+        self.query(
+            &self.prepare(
+                r#"
+                SELECT tokens.self_id, latest.cid
+                  FROM tokens
+                  JOIN generations AS latest ON latest.token = tokens.id
+                 WHERE tokens.self_chain = $1
+                   AND latest.ord = (SELECT MAX(ord) FROM generations WHERE token = tokens.id)
+                   AND latest.ord + 1 > (SELECT COUNT(*) FROM generations WHERE token = tokens.id)
+                "#,
+            )?,
+            &[&i64::from(chain_id)],
+        )?
+        .iter()
+        .map(|row| {
+            Ok((
+                TroutId {
+                    chain_id,
+                    token_id: row.try_get::<_, i64>(0)? as TokenId,
+                },
+                row.try_get::<_, String>(1)?.into(),
+            ))
+        })
+        .collect()
+    }
+
+    fn insert_generations(&self, id: TroutId, generations: &[Cid]) -> Result<(), Error> {
+        let inserter = self.prepare(
+            r#"
+            INSERT INTO generations (token, ord, cid)
+            SELECT id, $1, $2 FROM tokens WHERE self_chain = $3 AND self_id = $4
+            ON CONFLICT DO NOTHING
+            "#,
+        )?;
+        for (ord, generation) in generations.iter().enumerate() {
+            self.execute(
+                &inserter,
+                &[
+                    &(ord as i64),
+                    &&**generation,
+                    &i64::from(id.chain_id),
+                    &i64::from(id.token_id),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn list_tokens_for_ui(
+        &self,
+        chain_id: impl Into<Option<ChainId>>,
+    ) -> Result<Vec<TokenForUi>, Error> {
+        Ok(self
+            .query_tokens_for_ui(chain_id, &TokenQuery::default())?
+            .0)
+    }
+
+    fn query_tokens_for_ui(
+        &self,
+        chain_id: impl Into<Option<ChainId>>,
+        query: &TokenQuery,
+    ) -> Result<(Vec<TokenForUi>, Option<TokenCursor>), Error> {
+        let minted_at = r#"
+            (
+                SELECT blocks.timestamp
+                  FROM events
+                  JOIN blocks ON blocks.chain = events.chain AND blocks.number = events.block
+                 WHERE events.chain = tokens.self_chain
+                   AND events.kind = 1
+                   AND events.token = tokens.self_id
+                 LIMIT 1
+            )"#;
+        let sort_key = match query.sort {
+            TokenSort::Id => "tokens.self_id".into(),
+            TokenSort::Newest => "-tokens.self_id".into(),
+            TokenSort::Fee => "COALESCE(metadata.fee_key, 'unlisted')".into(),
+            TokenSort::Coi => "analysis.coi".into(),
+            TokenSort::Minted => format!("COALESCE({minted_at}, {})", i64::MAX),
+        };
+        let stmt = self.prepare(&format!(
+            r#"
+            SELECT tokens.self_id,
+                   tokens.owner,
+                   analysis.coi,
+                   metadata.left_parent_chain,
+                   metadata.left_parent_id,
+                   metadata.right_parent_chain,
+                   metadata.right_parent_id,
+                   metadata.name,
+                   metadata.fee,
+                   metadata.version,
+                   metadata.version IS NULL as pending,
+                   EXISTS (
+                       SELECT 1 FROM events
+                        WHERE events.chain = tokens.self_chain
+                          AND events.kind = 5
+                          AND events.token = tokens.self_id
+                   ) AS incubated,
+                   {minted_at} AS minted_at,
+                   {sort_key} AS sort_key
+              FROM tokens
+              LEFT JOIN metadata ON metadata.token = tokens.id
+              JOIN analysis ON analysis.token = tokens.id
+             WHERE ($1::BIGINT IS NULL OR tokens.self_chain = $1)
+               AND ($2::TEXT IS NULL OR tokens.owner = $2)
+               AND ($3::BOOLEAN IS NULL OR (metadata.fee IS NOT NULL) = $3)
+               AND ($4::TEXT IS NULL OR metadata.fee_key >= $4)
+               AND ($5::TEXT IS NULL OR metadata.fee_key <= $5)
+               AND ($6::DOUBLE PRECISION IS NULL OR analysis.coi >= $6)
+               AND ($7::DOUBLE PRECISION IS NULL OR analysis.coi <= $7)
+               AND ($8::BOOLEAN IS NULL OR metadata.is_genesis = $8)
+               AND ($9::BOOLEAN IS NULL OR metadata.is_santa = $9)
+               AND ($10::BIGINT IS NULL
+                    OR (metadata.left_parent_chain = tokens.self_chain
+                        AND metadata.left_parent_id = $10)
+                    OR (metadata.right_parent_chain = tokens.self_chain
+                        AND metadata.right_parent_id = $10))
+               AND ($11::TEXT IS NULL OR metadata.name ILIKE '%' || $11 || '%' ESCAPE '\')
+               AND ($13::BIGINT IS NULL OR ({sort_key}, tokens.self_id) > ($12, $13))
+             ORDER BY sort_key ASC, tokens.self_id ASC
+             LIMIT $14
+            "#
+        ))?;
+        let fee_key = |fee: &U256| format!("{fee:064x}");
+        let name = query.name.as_ref().map(|name| {
+            name.replace('\\', r"\\")
+                .replace('%', r"\%")
+                .replace('_', r"\_")
+        });
+        // The key's type must match the sort key's, even when it is absent.
+        let after_key: Box<dyn ToSql + Sync> = match query.after.as_ref().map(|c| &c.key) {
+            Some(SortKey::Integer(i)) => Box::new(*i),
+            Some(SortKey::Real(r)) => Box::new(*r),
+            Some(SortKey::Text(t)) => Box::new(t.clone()),
+            None => match query.sort {
+                TokenSort::Fee => Box::new(None::<String>),
+                TokenSort::Coi => Box::new(None::<f64>),
+                TokenSort::Id | TokenSort::Newest | TokenSort::Minted => Box::new(None::<i64>),
+            },
+        };
+        let rows = self.query(
+            &stmt,
+            &[
+                &chain_id.into().map(i64::from),
+                &query.owner.as_ref().map(addr_to_hex),
+                &query.listed,
+                &query.min_fee.as_ref().map(fee_key),
+                &query.max_fee.as_ref().map(fee_key),
+                &query.min_coi,
+                &query.max_coi,
+                &query.genesis,
+                &query.santa,
+                &query.parent.map(i64::from),
+                &name,
+                &*after_key,
+                &query.after.as_ref().map(|c| i64::from(c.id)),
+                &query.limit.map(i64::from),
+            ],
+        )?;
+        let mut cursor = None;
+        let tokens = rows
+            .iter()
+            .map(|row| {
+                let parent = |side: &str| -> Result<Option<TroutId>, Error> {
+                    let chain_id =
+                        row.try_get::<_, Option<i64>>(&*format!("{side}_parent_chain"))?;
+                    let token_id = row.try_get::<_, Option<i64>>(&*format!("{side}_parent_id"))?;
+                    Ok(chain_id.zip(token_id).map(|(chain_id, token_id)| TroutId {
+                        chain_id: chain_id as ChainId,
+                        token_id: token_id as TokenId,
+                    }))
+                };
+                let token = TokenForUi {
+                    id: row.try_get::<_, i64>("self_id")? as TokenId,
+                    coi: row.try_get("coi")?,
+                    owner: row.try_get::<_, &str>("owner")?.parse().unwrap(),
+                    name: row.try_get("name")?,
+                    fee: row
+                        .try_get::<_, Option<&str>>("fee")?
+                        .map(|f| f.parse().unwrap()),
+                    parents: parent("left")?.zip(parent("right")?),
+                    pending: row.try_get("pending")?,
+                    state: Lifecycle::new(
+                        row.try_get::<_, Option<i64>>("version")?.map(|v| v as u32),
+                        row.try_get("incubated")?,
+                    ),
+                    minted_at: row
+                        .try_get::<_, Option<i64>>("minted_at")?
+                        .map(|t| t as u64),
+                };
+                cursor = Some(TokenCursor {
+                    key: match query.sort {
+                        TokenSort::Fee => SortKey::Text(row.try_get("sort_key")?),
+                        TokenSort::Coi => SortKey::Real(row.try_get("sort_key")?),
+                        TokenSort::Id | TokenSort::Newest | TokenSort::Minted => {
+                            SortKey::Integer(row.try_get("sort_key")?)
+                        }
+                    },
+                    id: token.id,
+                });
+                Ok(token)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let next = match query.limit {
+            Some(limit) if tokens.len() == limit as usize => cursor,
+            _ => None,
+        };
+        Ok((tokens, next))
+    }
+
+    fn stud_earnings(&self, chain_id: ChainId, owner: &Address) -> Result<U256, Error> {
+        let rows = self.query(
+            &self.prepare(
+                "SELECT fee FROM studdings WHERE chain = $1 AND owner = $2 AND fee IS NOT NULL",
+            )?,
+            &[&i64::from(chain_id), &addr_to_hex(owner)],
+        )?;
+        let mut earnings = U256::zero();
+        for row in rows {
+            let fee: U256 = row.try_get::<_, &str>(0)?.parse().unwrap();
+            earnings = earnings.saturating_add(fee);
+        }
+        Ok(earnings)
+    }
+
+    fn market_stats(
+        &self,
+        chain_id: ChainId,
+        since: Option<u64>,
+        top: usize,
+    ) -> Result<MarketStats, Error> {
+        let total_trout = self
+            .query_one(
+                &self.prepare("SELECT COUNT(*) FROM tokens WHERE self_chain = $1")?,
+                &[&i64::from(chain_id)],
+            )?
+            .try_get::<_, i64>(0)? as u64;
+
+        let fees = self
+            .query(
+                &self.prepare(
+                    r#"
+                    SELECT metadata.fee
+                      FROM tokens
+                      JOIN metadata ON metadata.token = tokens.id
+                     WHERE tokens.self_chain = $1 AND metadata.fee IS NOT NULL
+                     ORDER BY metadata.fee_key ASC
+                    "#,
+                )?,
+                &[&i64::from(chain_id)],
+            )?
+            .iter()
+            .map(|row| Ok(row.try_get::<_, &str>(0)?.parse::<U256>().unwrap()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let median_fee = match fees.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(fees[n / 2]),
+            n => {
+                let (lo, hi) = (fees[n / 2 - 1], fees[n / 2]);
+                Some(lo + (hi - lo) / 2)
+            }
+        };
+
+        let rows = self.query(
+            &self.prepare(
+                r#"
+                SELECT studdings.child, studdings.stud, studdings.fee
+                  FROM studdings
+                  LEFT JOIN blocks
+                    ON blocks.chain = studdings.chain AND blocks.number = studdings.block
+                 WHERE studdings.chain = $1
+                   AND (
+                           $2::BIGINT IS NULL
+                        OR blocks.timestamp >= $2
+                        OR (
+                               blocks.timestamp IS NULL
+                           AND studdings.block > COALESCE((
+                                   SELECT MAX(number) FROM blocks
+                                    WHERE chain = $1 AND timestamp < $2
+                               ), -1)
+                           )
+                       )
+                "#,
+            )?,
+            &[&i64::from(chain_id), &since.map(|since| since as i64)],
+        )?;
+        let mut children = std::collections::HashSet::new();
+        let mut volume = U256::zero();
+        let mut studs = HashMap::<TokenId, (U256, u64)>::new();
+        for row in rows {
+            children.insert(row.try_get::<_, i64>("child")?);
+            let Some(fee) = row.try_get::<_, Option<&str>>("fee")? else {
+                continue;
+            };
+            let fee: U256 = fee.parse().unwrap();
+            volume = volume.saturating_add(fee);
+            let stud = row.try_get::<_, i64>("stud")? as TokenId;
+            let (earnings, paid_breeds) = studs.entry(stud).or_default();
+            *earnings = earnings.saturating_add(fee);
+            *paid_breeds += 1;
+        }
+        let mut top_studs = studs
+            .into_iter()
+            .map(|(token_id, (earnings, paid_breeds))| StudEarnings {
+                id: TroutId { chain_id, token_id },
+                earnings,
+                paid_breeds,
+            })
+            .collect::<Vec<_>>();
+        top_studs.sort_by(|a, b| {
+            b.earnings
+                .cmp(&a.earnings)
+                .then(a.id.token_id.cmp(&b.id.token_id))
+        });
+        top_studs.truncate(top);
+
+        Ok(MarketStats {
+            total_trout,
+            listed_studs: fees.len() as u64,
+            floor_fee: fees.first().copied(),
+            median_fee,
+            max_fee: fees.last().copied(),
+            breeds: children.len() as u64,
+            volume,
+            top_studs,
+        })
+    }
+
+    fn accrued_earnings(
+        &self,
+        chain_id: ChainId,
+        block: u64,
+    ) -> Result<HashMap<Address, U256>, Error> {
+        let rows = self.query(
+            &self.prepare(
+                r#"
+                SELECT owner, fee FROM studdings
+                 WHERE chain = $1 AND block <= $2 AND fee IS NOT NULL
+                "#,
+            )?,
+            &[&i64::from(chain_id), &(block as i64)],
+        )?;
+        let mut earnings = HashMap::<Address, U256>::new();
+        for row in rows {
+            let owner = row.try_get::<_, &str>(0)?.parse().unwrap();
+            let fee: U256 = row.try_get::<_, &str>(1)?.parse().unwrap();
+            let accrued = earnings.entry(owner).or_default();
+            *accrued = accrued.saturating_add(fee);
+        }
+        Ok(earnings)
+    }
+
+    fn stud_payments(&self, chain_id: ChainId, owner: &Address) -> Result<Vec<StudPayment>, Error> {
+        self.query(
+            &self.prepare(
+                r#"
+                SELECT studdings.block, blocks.timestamp, studdings.stud, studdings.child,
+                       studdings.breeder, studdings.fee
+                  FROM studdings
+                  LEFT JOIN blocks
+                    ON blocks.chain = studdings.chain AND blocks.number = studdings.block
+                 WHERE studdings.chain = $1 AND studdings.owner = $2 AND studdings.fee IS NOT NULL
+                 ORDER BY studdings.block ASC, studdings.log_index ASC
+                "#,
+            )?,
+            &[&i64::from(chain_id), &addr_to_hex(owner)],
+        )?
+        .iter()
+        .map(|row| {
+            let trout = |col| {
+                Ok::<TroutId, Error>(TroutId {
+                    chain_id,
+                    token_id: row.try_get::<_, i64>(col)? as TokenId,
+                })
+            };
+            Ok(StudPayment {
+                block: row.try_get::<_, i64>("block")? as u64,
+                timestamp: row
+                    .try_get::<_, Option<i64>>("timestamp")?
+                    .map(|t| t as u64),
+                stud: trout("stud")?,
+                child: trout("child")?,
+                breeder: row.try_get::<_, &str>("breeder")?.parse().unwrap(),
+                fee: row.try_get::<_, &str>("fee")?.parse().unwrap(),
+            })
+        })
+        .collect()
+    }
+
+    fn earnings_reconciliation(
+        &self,
+        chain_id: ChainId,
+        owner: &Address,
+    ) -> Result<Option<EarningsReconciliation>, Error> {
+        self.query_opt(
+            &self.prepare(
+                r#"
+                SELECT block, accrued, withdrawable, reconciled_at
+                  FROM earnings_reconciliations
+                 WHERE chain = $1 AND owner = $2
+                "#,
+            )?,
+            &[&i64::from(chain_id), &addr_to_hex(owner)],
+        )?
+        .map(|row| {
+            Ok(EarningsReconciliation {
+                block: row.try_get::<_, i64>("block")? as u64,
+                accrued: row.try_get::<_, &str>("accrued")?.parse().unwrap(),
+                withdrawable: row.try_get::<_, &str>("withdrawable")?.parse().unwrap(),
+                reconciled_at: row.try_get::<_, i64>("reconciled_at")? as u64,
+            })
+        })
+        .transpose()
+    }
+
+    fn token_events(
+        &self,
+        token: TroutId,
+        after: Option<EventCursor>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<EventForUi>, Option<EventCursor>), Error> {
+        self.query_events_for_ui(
+            token.chain_id,
+            &FeedQuery {
+                token: Some(token.token_id),
+                after,
+                limit,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn activity(
+        &self,
+        chain_id: ChainId,
+        before: Option<EventCursor>,
+        limit: u32,
+    ) -> Result<(Vec<EventForUi>, Option<EventCursor>), Error> {
+        self.query_events_for_ui(
+            chain_id,
+            &FeedQuery {
+                before,
+                newest_first: true,
+                limit: Some(limit),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn activity_in_blocks(
+        &self,
+        chain_id: ChainId,
+        blocks: std::ops::RangeInclusive<u64>,
+    ) -> Result<Vec<EventForUi>, Error> {
+        let (events, _) = self.query_events_for_ui(
+            chain_id,
+            &FeedQuery {
+                blocks: Some(blocks),
+                ..Default::default()
+            },
+        )?;
+        Ok(events)
+    }
+
+    fn stud_terms(&self, id: TroutId) -> Result<Option<(Address, Option<U256>)>, Error> {
+        self.query_opt(
+            &self.prepare(
+                r#"
+                SELECT tokens.owner, metadata.fee
+                  FROM tokens
+                  LEFT JOIN metadata ON metadata.token = tokens.id
+                 WHERE tokens.self_chain = $1 AND tokens.self_id = $2
+                "#,
+            )?,
+            &[&i64::from(id.chain_id), &i64::from(id.token_id)],
+        )?
+        .map(|row| {
+            Ok((
+                row.try_get::<_, &str>(0)?.parse().unwrap(),
+                row.try_get::<_, Option<&str>>(1)?
+                    .map(|fee| fee.parse().unwrap()),
+            ))
+        })
+        .transpose()
+    }
+
+    fn breeding_rates(&self, chain_id: ChainId) -> Result<(Option<U256>, Option<U256>), Error> {
+        let stmt = self.prepare(
+            r#"
+            SELECT value FROM contract_events
+             WHERE chain = $1 AND kind = $2
+             ORDER BY block DESC, log_index DESC
+             LIMIT 1
+            "#,
+        )?;
+        let latest = |kind: &str| -> Result<Option<U256>, Error> {
+            self.query_opt(&stmt, &[&i64::from(chain_id), &kind])?
+                .map(|row| Ok(row.try_get::<_, &str>(0)?.parse().unwrap()))
+                .transpose()
+        };
+        Ok((
+            latest("mint_reward_changed")?,
+            latest("matchmaking_fee_changed")?,
+        ))
+    }
+
+    fn contract_events(&self, chain_id: ChainId) -> Result<Vec<ContractEventForUi>, Error> {
+        self.query(
+            &self.prepare(
+                r#"
+                SELECT kind, block, log_index, value, previous, blocks.timestamp
+                  FROM contract_events
+                  LEFT JOIN blocks
+                    ON blocks.chain = contract_events.chain
+                   AND blocks.number = contract_events.block
+                 WHERE contract_events.chain = $1
+                 ORDER BY block ASC, log_index ASC
+                "#,
+            )?,
+            &[&i64::from(chain_id)],
+        )?
+        .iter()
+        .map(|row| {
+            Ok(ContractEventForUi {
+                block: row.try_get::<_, i64>("block")? as u64,
+                timestamp: row
+                    .try_get::<_, Option<i64>>("timestamp")?
+                    .map(|t| t as u64),
+                kind: contract_event_kind(
+                    row.try_get("kind")?,
+                    row.try_get("value")?,
+                    row.try_get("previous")?,
+                )
+                .expect("malformed contract event"),
+            })
+        })
+        .collect()
+    }
+
+    fn latest_contract_changes(&self, chain_id: ChainId) -> Result<Vec<ContractEventKind>, Error> {
+        self.query(
+            &self.prepare(
+                r#"
+                SELECT kind, value, previous
+                  FROM contract_events
+                 WHERE chain = $1
+                   AND NOT EXISTS (
+                       SELECT 1 FROM contract_events AS later
+                        WHERE later.chain = contract_events.chain
+                          AND later.kind = contract_events.kind
+                          AND (later.block, later.log_index)
+                              > (contract_events.block, contract_events.log_index)
+                   )
+                 ORDER BY block ASC, log_index ASC
+                "#,
+            )?,
+            &[&i64::from(chain_id)],
+        )?
+        .iter()
+        .map(|row| {
+            Ok(contract_event_kind(
+                row.try_get("kind")?,
+                row.try_get("value")?,
+                row.try_get("previous")?,
+            )
+            .expect("malformed contract event"))
+        })
+        .collect()
+    }
+
+    fn insert_tokens(&self, tokens: impl Iterator<Item = &TroutToken>) -> Result<(), Error> {
+        let token_inserter = self.prepare(
+            r#"
+            INSERT INTO tokens (self_chain, self_id, owner) VALUES ($1, $2, $3)
+            ON CONFLICT (self_chain, self_id) DO UPDATE SET owner = excluded.owner
+            RETURNING id
+            "#,
+        )?;
+        let metadata_inserter = self.prepare(
+            r#"
+            INSERT INTO metadata (
+                token,
+                version, name,
+                fee,
+                is_genesis, is_santa,
+                left_parent_chain, left_parent_id,
+                right_parent_chain, right_parent_id,
+                indexed_at
+            ) VALUES (
+                $1,
+                $2, $3,
+                $4,
+                $5, $6,
+                $7, $8,
+                $9, $10,
+                unixepoch()
+            )
+            ON CONFLICT (token) DO UPDATE SET
+                version = excluded.version,
+                name = CASE WHEN metadata.is_renamed THEN metadata.name ELSE excluded.name END,
+                fee = excluded.fee,
+                is_genesis = excluded.is_genesis,
+                is_santa = excluded.is_santa,
+                left_parent_chain = excluded.left_parent_chain,
+                left_parent_id = excluded.left_parent_id,
+                right_parent_chain = excluded.right_parent_chain,
+                right_parent_id = excluded.right_parent_id
+            "#,
+        )?;
+        let analysis_inserter = self.prepare(
+            r#"
+            INSERT INTO analysis (token, coi) VALUES ($1, $2)
+            ON CONFLICT (token) DO UPDATE SET coi = excluded.coi
+            "#,
+        )?;
+        let generation_inserter = self.prepare(
+            r#"
+            INSERT INTO generations (token, ord, cid) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+        )?;
+        for token in tokens {
+            debug_assert!(!token.meta.name.is_empty());
+            let props = &token.meta.properties;
+            let token_rowid: i64 = self
+                .query_one(
+                    &token_inserter,
+                    &[
+                        &i64::from(props.self_id.chain_id),
+                        &i64::from(props.self_id.token_id),
+                        &addr_to_hex(&token.owner),
+                    ],
+                )?
+                .try_get(0)?;
+            self.execute(
+                &metadata_inserter,
+                &[
+                    &token_rowid,
+                    &i64::from(props.version),
+                    &token.meta.name,
+                    &token.fee.as_ref().map(u256_to_hex),
+                    &props.attributes.genesis,
+                    &props.attributes.santa,
+                    &props.left.map(|token_id| i64::from(token_id.chain_id)),
+                    &props.left.map(|token_id| i64::from(token_id.token_id)),
+                    &props.right.map(|token_id| i64::from(token_id.chain_id)),
+                    &props.right.map(|token_id| i64::from(token_id.token_id)),
+                ],
+            )?;
+            self.execute(&analysis_inserter, &[&token_rowid, &token.coi])?;
+            self.execute(
+                &generation_inserter,
+                &[
+                    &token_rowid,
+                    &(props.generations.len() as i64),
+                    &&*token.cid,
+                ],
+            )?;
+            for (ord, generation) in props.generations.iter().enumerate() {
+                debug_assert!(!generation.is_empty());
+                self.execute(
+                    &generation_inserter,
+                    &[&token_rowid, &(ord as i64), &&**generation],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_pending_tokens(
+        &self,
+        chain_id: ChainId,
+        pending_tokens: impl Iterator<Item = (TokenId, PendingToken)>,
+    ) -> Result<(), Error> {
+        let token_inserter = self.prepare(
+            r#"
+            INSERT INTO tokens (self_chain, self_id, owner)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (self_chain, self_id) DO NOTHING"#,
+        )?;
+        for (token_id, token) in pending_tokens {
+            self.execute(
+                &token_inserter,
+                &[
+                    &i64::from(chain_id),
+                    &i64::from(token_id),
+                    &addr_to_hex(token.owner),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn remove_pending_tokens(
+        &self,
+        chain_id: ChainId,
+        token_ids: impl Iterator<Item = TokenId>,
+    ) -> Result<(), Error> {
+        let remover = self.prepare(
+            r#"
+            DELETE FROM tokens
+             WHERE self_chain = $1 AND self_id = $2
+               AND NOT EXISTS (SELECT 1 FROM metadata WHERE metadata.token = tokens.id)
+            "#,
+        )?;
+        for token_id in token_ids {
+            self.execute(&remover, &[&i64::from(chain_id), &i64::from(token_id)])?;
+        }
+        Ok(())
+    }
+
+    fn set_token_name(&self, id: TroutId, name: &str) -> Result<(), Error> {
+        self.execute(
+            &self.prepare(
+                r#"
+                UPDATE metadata
+                   SET name = $1, is_renamed = TRUE
+                 WHERE token IN (SELECT id FROM tokens WHERE self_chain = $2 AND self_id = $3)
+                "#,
+            )?,
+            &[&name, &i64::from(id.chain_id), &i64::from(id.token_id)],
+        )?;
+        Ok(())
+    }
+
+    fn set_cois(&self, cois: impl Iterator<Item = (TroutId, f64)>) -> Result<(), Error> {
+        let updater = self.prepare(
+            r#"
+            UPDATE analysis
+               SET coi = $1
+             WHERE token IN (SELECT id FROM tokens WHERE self_chain = $2 AND self_id = $3)
+            "#,
+        )?;
+        for (trout_id, coi) in cois {
+            self.execute(
+                &updater,
+                &[
+                    &coi,
+                    &i64::from(trout_id.chain_id),
+                    &i64::from(trout_id.token_id),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn update_fees(
+        &self,
+        chain_id: ChainId,
+        tokens: impl Iterator<Item = (TokenId, Option<&U256>)>,
+    ) -> Result<(), Error> {
+        let fee_updater = self.prepare(
+            r#"
+            UPDATE metadata
+               SET fee = $1
+             WHERE token IN (SELECT id FROM tokens WHERE self_chain = $2 AND self_id = $3)
+            "#,
+        )?;
+        for (token_id, fee) in tokens {
+            self.execute(
+                &fee_updater,
+                &[
+                    &fee.map(u256_to_hex),
+                    &i64::from(chain_id),
+                    &i64::from(token_id),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn update_owners(
+        &self,
+        chain: ChainId,
+        token_owners: impl Iterator<Item = (TokenId, &Address)>,
+    ) -> Result<(), Error> {
+        let updater =
+            self.prepare(r#"UPDATE tokens SET owner = $1 WHERE self_chain = $2 AND self_id = $3"#)?;
+        for (token_id, owner) in token_owners {
+            self.execute(
+                &updater,
+                &[&addr_to_hex(owner), &i64::from(chain), &i64::from(token_id)],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn mark_pinned(&self, cids: impl Iterator<Item = &Cid>) -> Result<(), Error> {
+        let updater = self.prepare(r#"UPDATE generations SET pinned = TRUE WHERE cid = $1"#)?;
+        for cid in cids {
+            self.execute(&updater, &[&&**cid])?;
+        }
+        Ok(())
+    }
+
+    fn mark_pin_failed(&self, cids: impl Iterator<Item = &Cid>) -> Result<(), Error> {
+        let updater =
+            self.prepare(r#"UPDATE generations SET pin_fails = pin_fails + 1 WHERE cid = $1"#)?;
+        for cid in cids {
+            self.execute(&updater, &[&&**cid])?;
+        }
+        Ok(())
+    }
+}
+
+impl StorageTx for Connection<'_> {
+    fn init_progress(&self, chain: ChainId, start_block: u64) -> Result<(), Error> {
+        self.execute(
+            &self.prepare(
+                r#"
+                INSERT INTO progress (chain, block) VALUES ($1, $2)
+                ON CONFLICT (chain) DO NOTHING
+                "#,
+            )?,
+            &[&i64::from(chain), &(start_block as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn record_events(
+        &self,
+        chain: ChainId,
+        events: impl Iterator<Item = &Event>,
+    ) -> Result<(), Error> {
+        let event_inserter = self.prepare(
+            r#"
+            INSERT INTO events (
+                chain, kind, token, block, log_index, batch_index, observed_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, unixepoch())
+            ON CONFLICT DO NOTHING
+            RETURNING id
+            "#,
+        )?;
+        let list_event_inserter =
+            self.prepare(r#"INSERT INTO list_events (event, fee) VALUES ($1, $2)"#)?;
+        let spawn_event_inserter =
+            self.prepare(r#"INSERT INTO spawn_events (event, recipient) VALUES ($1, $2)"#)?;
+        let transfer_event_inserter = self.prepare(
+            r#"INSERT INTO transfer_events (event, sender, recipient) VALUES ($1, $2, $3)"#,
+        )?;
+        let breed_event_inserter = self.prepare(
+            r#"INSERT INTO breed_events (event, left_parent, right_parent) VALUES ($1, $2, $3)"#,
+        )?;
+        let contract_event_inserter = self.prepare(
+            r#"
+            INSERT INTO contract_events (chain, kind, block, log_index, value, previous)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT DO NOTHING
+            "#,
+        )?;
+        let block_inserter = self.prepare(
+            r#"
+            INSERT INTO blocks (chain, number, hash, timestamp) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (chain, number) DO UPDATE SET
+                hash = excluded.hash,
+                timestamp = excluded.timestamp
+            "#,
+        )?;
+        let block_pruner = self.prepare(
+            r#"
+            DELETE FROM blocks
+             WHERE chain = $1 AND number < $2
+               AND NOT EXISTS (
+                   SELECT 1 FROM events WHERE events.chain = $1 AND events.block = blocks.number
+               )
+               AND NOT EXISTS (
+                   SELECT 1 FROM contract_events
+                    WHERE contract_events.chain = $1 AND contract_events.block = blocks.number
+               )
+            "#,
+        )?;
+        let progress_updater =
+            self.prepare(r#"UPDATE progress SET block = $1 WHERE chain = $2"#)?;
+
+        let chain_id = i64::from(chain);
+        let mut last_block = None;
+        let mut last_header = None;
+        for event in events {
+            trace!(event = ?event, "processing event");
+            match event {
+                Event::Token(TokenEvent {
+                    token,
+                    kind,
+                    block,
+                    log_index,
+                    batch_index,
+                }) => {
+                    let row = self.query_opt(
+                        &event_inserter,
+                        &[
+                            &chain_id,
+                            &match kind {
+                                TokenEventKind::Spawned { .. } => 1i64,
+                                TokenEventKind::Relisted { .. } => 2,
+                                TokenEventKind::Transfer { .. } => 3,
+                                TokenEventKind::Bred { .. } => 4,
+                                TokenEventKind::Incubated => 5,
+                            },
+                            &i64::from(*token),
+                            &(*block as i64),
+                            &(*log_index as i64),
+                            &i64::from(*batch_index),
+                        ],
+                    )?;
+                    let event_id: i64 = match row {
+                        Some(row) => row.try_get(0)?,
+                        None => continue,
+                    };
+                    match kind {
+                        TokenEventKind::Spawned { to } => {
+                            self.execute(&spawn_event_inserter, &[&event_id, &addr_to_hex(to)])?;
+                        }
+                        TokenEventKind::Relisted { fee } => {
+                            self.execute(
+                                &list_event_inserter,
+                                &[&event_id, &fee.as_ref().map(u256_to_hex)],
+                            )?;
+                        }
+                        TokenEventKind::Transfer { from, to } => {
+                            self.execute(
+                                &transfer_event_inserter,
+                                &[&event_id, &addr_to_hex(from), &addr_to_hex(to)],
+                            )?;
+                        }
+                        TokenEventKind::Bred { left, right } => {
+                            self.execute(
+                                &breed_event_inserter,
+                                &[&event_id, &i64::from(*left), &i64::from(*right)],
+                            )?;
+                        }
+                        TokenEventKind::Incubated => {}
+                    }
+                }
+                Event::Contract(ContractEvent {
+                    kind,
+                    block,
+                    log_index,
+                }) => {
+                    let (kind, value, previous) = contract_event_columns(kind);
+                    self.execute(
+                        &contract_event_inserter,
+                        &[
+                            &chain_id,
+                            &kind,
+                            &(*block as i64),
+                            &(*log_index as i64),
+                            &value,
+                            &previous,
+                        ],
+                    )?;
+                }
+                Event::Block(BlockHeader {
+                    number,
+                    hash,
+                    timestamp,
+                    ..
+                }) => {
+                    self.execute(
+                        &block_inserter,
+                        &[
+                            &chain_id,
+                            &(*number as i64),
+                            &h256_to_hex(hash),
+                            &(*timestamp as i64),
+                        ],
+                    )?;
+                    last_header = Some(*number);
+                }
+                Event::ProcessedBlock(block) => {
+                    last_block = Some(*block);
+                }
+            }
+        }
+        if let Some(block) = last_header {
+            let horizon = block.saturating_sub(BLOCK_HASH_RETENTION) as i64;
+            self.execute(&block_pruner, &[&chain_id, &horizon])?;
+        }
+        if let Some(block) = last_block {
+            trace!(block = block, "processed block");
+            self.execute(&progress_updater, &[&(block as i64), &chain_id])?;
+        }
+        Ok(())
+    }
+
+    fn record_earnings_reconciliations(
+        &self,
+        chain: ChainId,
+        block: u64,
+        reconciliations: impl Iterator<Item = (Address, U256, U256)>,
+    ) -> Result<(), Error> {
+        let inserter = self.prepare(
+            r#"
+            INSERT INTO earnings_reconciliations (
+                chain, owner, block, accrued, withdrawable, reconciled_at
+            )
+            VALUES ($1, $2, $3, $4, $5, unixepoch())
+            ON CONFLICT (chain, owner) DO UPDATE SET
+                block = excluded.block,
+                accrued = excluded.accrued,
+                withdrawable = excluded.withdrawable,
+                reconciled_at = excluded.reconciled_at
+            "#,
+        )?;
+        for (owner, accrued, withdrawable) in reconciliations {
+            self.execute(
+                &inserter,
+                &[
+                    &i64::from(chain),
+                    &addr_to_hex(&owner),
+                    &(block as i64),
+                    &u256_to_hex(&accrued),
+                    &u256_to_hex(&withdrawable),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn roll_back_events(&self, chain: ChainId, fork_block: u64) -> Result<(), Error> {
+        let params: &Params = &[&i64::from(chain), &(fork_block as i64)];
+        for table in [
+            "spawn_events",
+            "list_events",
+            "transfer_events",
+            "breed_events",
+        ] {
+            self.execute(
+                &self.prepare(&format!(
+                    r#"
+                    DELETE FROM {table}
+                     WHERE event IN (SELECT id FROM events WHERE chain = $1 AND block > $2)
+                    "#
+                ))?,
+                params,
+            )?;
+        }
+        for query in [
+            r#"DELETE FROM events WHERE chain = $1 AND block > $2"#,
+            r#"DELETE FROM contract_events WHERE chain = $1 AND block > $2"#,
+            r#"DELETE FROM blocks WHERE chain = $1 AND number > $2"#,
+            r#"DELETE FROM earnings_reconciliations WHERE chain = $1 AND block > $2"#,
+            r#"UPDATE progress SET block = LEAST(block, $2) WHERE chain = $1"#,
+        ] {
+            self.execute(&self.prepare(query)?, params)?;
+        }
+        Ok(())
+    }
+}
+
+impl Connection<'_> {
+    fn query_lifecycles(
+        &self,
+        chain_id: ChainId,
+        token_id: Option<TokenId>,
+        waiting: bool,
+    ) -> Result<Vec<TokenLifecycle>, Error> {
+        self.query(
+            &self.prepare(
+                r#"
+                WITH
+                known AS (
+                    SELECT token AS id
+                      FROM events
+                     WHERE chain = $1 AND kind = 1 AND ($2::BIGINT IS NULL OR token = $2)
+                     UNION
+                    SELECT self_id AS id
+                      FROM tokens
+                     WHERE self_chain = $1 AND ($2::BIGINT IS NULL OR self_id = $2)
+                ),
+                stages AS (
+                    SELECT known.id,
+                           (
+                               SELECT MIN(COALESCE(blocks.timestamp, events.observed_at))
+                                 FROM events
+                                 LEFT JOIN blocks
+                                        ON blocks.chain = events.chain
+                                       AND blocks.number = events.block
+                                WHERE events.chain = $1
+                                  AND events.kind = 1
+                                  AND events.token = known.id
+                           ) AS spawned_at,
+                           (
+                               SELECT MIN(COALESCE(blocks.timestamp, events.observed_at))
+                                 FROM events
+                                 LEFT JOIN blocks
+                                        ON blocks.chain = events.chain
+                                       AND blocks.number = events.block
+                                WHERE events.chain = $1
+                                  AND events.kind = 5
+                                  AND events.token = known.id
+                           ) AS incubated_at,
+                           EXISTS (
+                               SELECT 1 FROM events
+                                WHERE chain = $1 AND kind = 5 AND token = known.id
+                           ) AS incubated
+                      FROM known
+                )
+                SELECT stages.*,
+                       metadata.version,
+                       metadata.indexed_at,
+                       unixepoch() AS now
+                  FROM stages
+                  LEFT JOIN tokens ON tokens.self_chain = $1 AND tokens.self_id = stages.id
+                  LEFT JOIN metadata ON metadata.token = tokens.id
+                 WHERE NOT $3 OR metadata.version IS NULL
+                 ORDER BY stages.id ASC
+                "#,
+            )?,
+            &[&i64::from(chain_id), &token_id.map(i64::from), &waiting],
+        )?
+        .iter()
+        .map(|row| {
+            let timestamp = |col| -> Result<Option<u64>, Error> {
+                Ok(row.try_get::<_, Option<i64>>(col)?.map(|t| t as u64))
+            };
+            let state = Lifecycle::new(
+                row.try_get::<_, Option<i64>>("version")?.map(|v| v as u32),
+                row.try_get("incubated")?,
+            );
+            let spawned_at = timestamp("spawned_at")?;
+            let incubated_at = timestamp("incubated_at")?;
+            let since = match state {
+                Lifecycle::Incubating => spawned_at,
+                Lifecycle::AwaitingMetadata => incubated_at,
+                Lifecycle::Indexed | Lifecycle::OutdatedVersion => None,
+            };
+            let now = row.try_get::<_, i64>("now")? as u64;
+            Ok(TokenLifecycle {
+                id: row.try_get::<_, i64>("id")? as TokenId,
+                state,
+                spawned_at,
+                incubated_at,
+                indexed_at: timestamp("indexed_at")?,
+                waiting_secs: since.map(|since| now.saturating_sub(since)),
+            })
+        })
+        .collect()
+    }
+
+    fn query_events_for_ui(
+        &self,
+        chain_id: ChainId,
+        query: &FeedQuery,
+    ) -> Result<(Vec<EventForUi>, Option<EventCursor>), Error> {
+        let order = if query.newest_first { "DESC" } else { "ASC" };
+        // Both parents of a breeding share its log, so a token's feed tells them apart by their
+        // side as the `batch_index`, while the chain's feed shows each breeding once, as the use of
+        // its left parent.
+        let stmt = self.prepare(&format!(
+            r#"
+            WITH
+            feed_events AS (
+                SELECT events.id, events.token, events.block, events.log_index, events.batch_index
+                  FROM events
+                 WHERE events.chain = $1 AND ($2::BIGINT IS NULL OR events.token = $2)
+            ),
+            feed AS (
+                SELECT 'mint' AS kind, token, block, log_index, batch_index,
+                       NULL::TEXT AS sender, spawn_events.recipient,
+                       NULL::TEXT AS fee, NULL::BIGINT AS child, NULL::BIGINT AS coparent,
+                       NULL::TEXT AS breeder, NULL::TEXT AS owner
+                  FROM feed_events
+                  JOIN spawn_events ON spawn_events.event = feed_events.id
+                 UNION ALL
+                SELECT 'transfer', token, block, log_index, batch_index,
+                       transfer_events.sender, transfer_events.recipient,
+                       NULL, NULL, NULL, NULL, NULL
+                  FROM feed_events
+                  JOIN transfer_events ON transfer_events.event = feed_events.id
+                 UNION ALL
+                SELECT CASE WHEN list_events.fee IS NULL THEN 'delisted' ELSE 'listed' END,
+                       token, block, log_index, batch_index,
+                       NULL, NULL,
+                       list_events.fee, NULL, NULL, NULL, NULL
+                  FROM feed_events
+                  JOIN list_events ON list_events.event = feed_events.id
+                 UNION ALL
+                SELECT 'breed', stud, block, log_index, side,
+                       NULL, NULL,
+                       fee, child, coparent, breeder, owner
+                  FROM studdings
+                 WHERE chain = $1 AND ($2::BIGINT IS NULL AND side = 0 OR stud = $2)
+            )
+            SELECT feed.*, blocks.timestamp
+              FROM feed
+              LEFT JOIN blocks ON blocks.chain = $1 AND blocks.number = feed.block
+             WHERE (
+                       $3::BIGINT IS NULL
+                    OR (feed.block, feed.log_index, feed.batch_index) > ($3, $4, $5)
+                   )
+               AND (
+                       $6::BIGINT IS NULL
+                    OR (feed.block, feed.log_index, feed.batch_index) < ($6, $7, $8)
+                   )
+               AND ($9::BIGINT IS NULL OR feed.block BETWEEN $9 AND $10)
+             ORDER BY feed.block {order}, feed.log_index {order}, feed.batch_index {order}
+             LIMIT $11
+            "#
+        ))?;
+        let position = |cursor: Option<EventCursor>| {
+            (
+                cursor.map(|c| c.block as i64),
+                cursor.map(|c| c.log_index as i64),
+                cursor.map(|c| i64::from(c.batch_index)),
+            )
+        };
+        let (after_block, after_log_index, after_batch_index) = position(query.after);
+        let (before_block, before_log_index, before_batch_index) = position(query.before);
+        let rows = self.query(
+            &stmt,
+            &[
+                &i64::from(chain_id),
+                &query.token.map(i64::from),
+                &after_block,
+                &after_log_index,
+                &after_batch_index,
+                &before_block,
+                &before_log_index,
+                &before_batch_index,
+                &query.blocks.as_ref().map(|b| *b.start() as i64),
+                &query.blocks.as_ref().map(|b| *b.end() as i64),
+                &query.limit.map(i64::from),
+            ],
+        )?;
+        let mut cursor = None;
+        let events = rows
+            .iter()
+            .map(|row| {
+                let addr = |col| {
+                    Ok::<Address, Error>(
+                        row.try_get::<_, Option<&str>>(col)?
+                            .map(|a| a.parse().unwrap())
+                            .unwrap_or_default(),
+                    )
+                };
+                let fee = || {
+                    Ok::<U256, Error>(
+                        row.try_get::<_, Option<&str>>("fee")?
+                            .map(|f| f.parse().unwrap())
+                            .unwrap_or_default(),
+                    )
+                };
+                let trout = |col| {
+                    Ok::<TroutId, Error>(TroutId {
+                        chain_id,
+                        token_id: row.try_get::<_, i64>(col)? as TokenId,
+                    })
+                };
+                let kind = match row.try_get::<_, &str>("kind")? {
+                    "mint" => EventKindForUi::Mint {
+                        to: addr("recipient")?,
+                    },
+                    "transfer" => EventKindForUi::Transfer {
+                        from: addr("sender")?,
+                        to: addr("recipient")?,
+                    },
+                    "listed" => EventKindForUi::Listed { fee: fee()? },
+                    "delisted" => EventKindForUi::Delisted,
+                    "breed" => EventKindForUi::Breed {
+                        breeder: addr("breeder")?,
+                        child: trout("child")?,
+                        coparent: trout("coparent")?,
+                        price: fee()?,
+                        owner: addr("owner")?,
+                    },
+                    kind => return Err(Error::UnknownEventKind(kind.into())),
+                };
+                let position = EventCursor {
+                    block: row.try_get::<_, i64>("block")? as u64,
+                    log_index: row.try_get::<_, i64>("log_index")? as u64,
+                    batch_index: row.try_get::<_, i64>("batch_index")? as u32,
+                };
+                cursor = Some(position);
+                Ok(EventForUi {
+                    id: trout("token")?,
+                    block: position.block,
+                    timestamp: row
+                        .try_get::<_, Option<i64>>("timestamp")?
+                        .map(|t| t as u64),
+                    kind,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let next = match query.limit {
+            Some(limit) if events.len() == limit as usize => cursor,
+            _ => None,
+        };
+        Ok((events, next))
+    }
+}
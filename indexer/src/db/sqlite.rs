@@ -0,0 +1,1583 @@
+//! Storage in a SQLite database, as by default.
+
+use std::{collections::HashMap, time::Duration};
+
+use ethers::types::{Address, H256, U256};
+use parking_lot::Mutex;
+use rusqlite::{
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    OptionalExtension as _, ToSql,
+};
+use tracing::trace;
+
+use super::{
+    addr_to_hex, contract_event_columns, contract_event_kind, h256_to_hex,
+    migrate::{migration, AppliedMigration, Migration, MigrationStore},
+    u256_to_hex, EventCursor, FeedQuery, SortKey, Storage, StorageTx, TokenCursor, TokenQuery,
+    TokenSort, BLOCK_HASH_RETENTION,
+};
+use crate::{
+    db::Error,
+    ipfs::Cid,
+    nftrout::{
+        BlockHeader, ChainId, ContractEvent, ContractEventForUi, ContractEventKind,
+        EarningsReconciliation, Event, EventForUi, EventKindForUi, Generation, Lifecycle,
+        MarketStats, PendingToken, StudEarnings, StudPayment, TokenEvent, TokenEventKind,
+        TokenForUi, TokenId, TokenLifecycle, TroutId, TroutToken,
+    },
+};
+
+/// The number of idle reader connections kept open for reuse.
+const MAX_IDLE_READERS: usize = 16;
+
+/// How long a connection waits for another's lock before failing with `SQLITE_BUSY`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(super) const MIGRATIONS: &[Migration] = &[
+    migration!("sqlite", "00-init"),
+    migration!("sqlite", "01-events"),
+    migration!("sqlite", "02-blocks"),
+    migration!("sqlite", "03-listing"),
+    migration!("sqlite", "04-breed-events"),
+    migration!("sqlite", "05-lifecycle"),
+    migration!("sqlite", "06-batch-events"),
+    migration!("sqlite", "07-contract-events"),
+    migration!("sqlite", "08-block-timestamps"),
+    migration!("sqlite", "09-earnings"),
+    migration!("sqlite", "10-generations"),
+    migration!("sqlite", "11-renames"),
+    migration!("sqlite", "12-progress-seed"),
+    migration!("sqlite", "13-studdings-index"),
+];
+
+/// Connections held open so that their statement caches are reused.
+pub(super) struct Pool {
+    connstr: String,
+    /// The connection used for all transactions, so that writers queue here rather than
+    /// contending for SQLite's write lock.
+    writer: Mutex<rusqlite::Connection>,
+    readers: Mutex<Vec<rusqlite::Connection>>,
+}
+
+impl Pool {
+    pub(super) fn open(connstr: String) -> Result<Self, Error> {
+        let writer = open_connection(&connstr)?;
+        Ok(Self {
+            connstr,
+            writer: Mutex::new(writer),
+            readers: Default::default(),
+        })
+    }
+
+    pub(super) fn with_conn<T>(
+        &self,
+        f: impl FnOnce(Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let conn = match self.readers.lock().pop() {
+            Some(conn) => conn,
+            None => open_connection(&self.connstr)?,
+        };
+        let res = f(Connection(&conn));
+        let mut readers = self.readers.lock();
+        if readers.len() < MAX_IDLE_READERS {
+            readers.push(conn);
+        }
+        res
+    }
+
+    pub(super) fn with_tx<T>(
+        &self,
+        f: impl FnOnce(Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let writer = self.writer.lock();
+        let tx = writer.unchecked_transaction()?;
+        let res = f(Connection(&tx))?;
+        tx.commit()?;
+        Ok(res)
+    }
+}
+
+pub struct Connection<'a>(&'a rusqlite::Connection);
+
+impl Storage for Connection<'_> {
+    fn latest_known_token_id(&self, chain_id: ChainId) -> Result<Option<TokenId>, Error> {
+        self.0
+            .query_row(
+                "SELECT MAX(self_id) FROM tokens WHERE self_chain = ?",
+                [chain_id],
+                |row| row.get::<_, Option<TokenId>>(0),
+            )
+            .map_err(Into::into)
+    }
+
+    fn latest_processed_block(&self, chain_id: ChainId) -> Result<u64, Error> {
+        self.0
+            .query_row(
+                "SELECT block FROM progress WHERE chain = ?",
+                [chain_id],
+                |row| Ok(row.get::<_, i64>(0)? as u64),
+            )
+            .map_err(Into::into)
+    }
+
+    fn block_hash(&self, chain_id: ChainId, block: u64) -> Result<Option<H256>, Error> {
+        self.0
+            .query_row(
+                "SELECT hash FROM blocks WHERE chain = ? AND number = ?",
+                (chain_id, block),
+                |row| Ok(row.get::<_, String>(0)?.parse().unwrap()),
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn tokens_changed_since(&self, chain_id: ChainId, block: u64) -> Result<Vec<TokenId>, Error> {
+        self.0
+            .prepare(
+                "SELECT DISTINCT token FROM events WHERE chain = ? AND block > ? ORDER BY token",
+            )?
+            .query_map((chain_id, block), |row| row.get::<_, TokenId>(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn token_ids(&self, chain_id: ChainId) -> Result<Vec<TokenId>, Error> {
+        self.0
+            .prepare("SELECT self_id FROM tokens WHERE self_chain = ? ORDER BY self_id ASC")?
+            .query_map([chain_id], |row| row.get::<_, TokenId>(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn needs_coi_analysis(&self, chain_id: ChainId) -> Result<Vec<TroutId>, Error> {
+        self.0
+            .prepare(
+                r#"
+                SELECT self_chain, self_id
+                  FROM tokens
+                  JOIN analysis
+                    ON analysis.token = tokens.id
+                 WHERE coi = -1
+                   AND self_chain = ?
+                "#,
+            )?
+            .query_map([chain_id], |row| {
+                Ok(TroutId {
+                    chain_id: row.get(0)?,
+                    token_id: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn blocks_missing_timestamps(
+        &self,
+        chain_id: ChainId,
+        limit: usize,
+    ) -> Result<Vec<u64>, Error> {
+        self.0
+            .prepare_cached(
+                r#"
+                SELECT eventful.block
+                  FROM (
+                      SELECT block FROM events WHERE chain = ?1
+                       UNION
+                      SELECT block FROM contract_events WHERE chain = ?1
+                  ) AS eventful
+                 WHERE NOT EXISTS (
+                     SELECT 1 FROM blocks
+                      WHERE blocks.chain = ?1
+                        AND blocks.number = eventful.block
+                        AND blocks.timestamp IS NOT NULL
+                 )
+                 ORDER BY eventful.block ASC
+                 LIMIT ?2
+                "#,
+            )?
+            .query_map((chain_id, limit), |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn token_lifecycle(&self, id: TroutId) -> Result<Option<TokenLifecycle>, Error> {
+        Ok(self
+            .query_lifecycles(id.chain_id, Some(id.token_id), false)?
+            .pop())
+    }
+
+    fn waiting_tokens(&self, chain_id: ChainId) -> Result<Vec<TokenLifecycle>, Error> {
+        self.query_lifecycles(chain_id, None, true)
+    }
+
+    fn outdated_token_ids(&self, chain_id: ChainId) -> Result<Vec<TokenId>, Error> {
+        self.0
+            .prepare(
+                r#"
+                SELECT tokens.self_id
+                  FROM tokens
+                  LEFT JOIN metadata ON metadata.token = tokens.id
+                 WHERE tokens.self_chain = ?
+                   AND (metadata.version IS NULL OR metadata.version < ?)
+                "#,
+            )?
+            .query_map([chain_id, crate::nftrout::CURRENT_VERSION], |row| {
+                row.get::<_, TokenId>(0)
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn unpinned_cids(&self) -> Result<Vec<Cid>, Error> {
+        self.0
+            .prepare("SELECT cid FROM generations WHERE pinned = 0 AND pin_fails < 20")?
+            .query_map([], |row| row.get::<_, String>(0).map(Into::into))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn token_cid(
+        &self,
+        TroutId { chain_id, token_id }: &TroutId,
+        ord: Option<u32>,
+    ) -> Result<Option<Cid>, Error> {
+        self.0
+            .query_row(
+                r#"
+                SELECT generations.cid FROM generations
+                JOIN tokens ON tokens.id = generations.token
+                WHERE tokens.self_chain = ?
+                AND tokens.self_id = ?
+                AND generations.ord = coalesce(
+                        ?,
+                        (SELECT MAX(ord) FROM generations WHERE generations.token = tokens.id)
+                    )
+                "#,
+                (chain_id, token_id, ord),
+                |row| row.get::<_, String>(0).map(Into::into),
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn token_generations(&self, id: TroutId) -> Result<Vec<Generation>, Error> {
+        self.0
+            .prepare_cached(
+                r#"
+                SELECT generations.ord, generations.cid, generations.pinned
+                  FROM generations
+                  JOIN tokens ON tokens.id = generations.token
+                 WHERE tokens.self_chain = ? AND tokens.self_id = ?
+                 ORDER BY generations.ord ASC
+                "#,
+            )?
+            .query_map((id.chain_id, id.token_id), |row| {
+                Ok(Generation {
+                    ord: row.get(0)?,
+                    cid: row.get::<_, String>(1)?.into(),
+                    pinned: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn tokens_missing_generations(&self, chain_id: ChainId) -> Result<Vec<(TroutId, Cid)>, Error> {
+        self.0
+            .prepare_cached(
+                r#"
+                SELECT tokens.self_id, latest.cid
+                  FROM tokens
+                  JOIN generations AS latest ON latest.token = tokens.id
+                 WHERE tokens.self_chain = ?
+                   AND latest.ord = (SELECT MAX(ord) FROM generations WHERE token = tokens.id)
+                   AND latest.ord + 1 > (SELECT COUNT(*) FROM generations WHERE token = tokens.id)
+                "#,
+            )?
+            .query_map([chain_id], |row| {
+                Ok((
+                    TroutId {
+                        chain_id,
+                        token_id: row.get(0)?,
+                    },
+                    row.get::<_, String>(1)?.into(),
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn insert_generations(&self, id: TroutId, generations: &[Cid]) -> Result<(), Error> {
+        let mut inserter = self.0.prepare_cached(
+            r#"
+            INSERT OR IGNORE INTO generations (token, ord, cid)
+            SELECT id, ?, ? FROM tokens WHERE self_chain = ? AND self_id = ?
+            "#,
+        )?;
+        for (ord, generation) in generations.iter().enumerate() {
+            inserter.execute((ord, &**generation, id.chain_id, id.token_id))?;
+        }
+        Ok(())
+    }
+
+    fn list_tokens_for_ui(
+        &self,
+        chain_id: impl Into<Option<ChainId>>,
+    ) -> Result<Vec<TokenForUi>, Error> {
+        Ok(self
+            .query_tokens_for_ui(chain_id, &TokenQuery::default())?
+            .0)
+    }
+
+    fn query_tokens_for_ui(
+        &self,
+        chain_id: impl Into<Option<ChainId>>,
+        query: &TokenQuery,
+    ) -> Result<(Vec<TokenForUi>, Option<TokenCursor>), Error> {
+        let minted_at = r#"
+            (
+                SELECT blocks.timestamp
+                  FROM events
+                  JOIN blocks ON blocks.chain = events.chain AND blocks.number = events.block
+                 WHERE events.chain = tokens.self_chain
+                   AND events.kind = 1
+                   AND events.token = tokens.self_id
+            )"#;
+        let sort_key = match query.sort {
+            TokenSort::Id => "tokens.self_id".into(),
+            TokenSort::Newest => "-tokens.self_id".into(),
+            TokenSort::Fee => "IFNULL(metadata.fee_key, 'unlisted')".into(),
+            TokenSort::Coi => "analysis.coi".into(),
+            TokenSort::Minted => format!("IFNULL({minted_at}, {})", i64::MAX),
+        };
+        let mut stmt = self.0.prepare_cached(&format!(
+            r#"
+            SELECT tokens.self_id,
+                   tokens.owner,
+                   analysis.coi,
+                   metadata.left_parent_chain,
+                   metadata.left_parent_id,
+                   metadata.right_parent_chain,
+                   metadata.right_parent_id,
+                   metadata.name,
+                   metadata.fee,
+                   metadata.version,
+                   metadata.version IS NULL as pending,
+                   EXISTS (
+                       SELECT 1 FROM events
+                        WHERE events.chain = tokens.self_chain
+                          AND events.kind = 5
+                          AND events.token = tokens.self_id
+                   ) AS incubated,
+                   {minted_at} AS minted_at,
+                   {sort_key} AS sort_key
+              FROM tokens
+              LEFT JOIN metadata ON metadata.token = tokens.id
+              JOIN analysis ON analysis.token = tokens.id
+             WHERE iif(:chain, tokens.self_chain = :chain, 1)
+               AND (:owner IS NULL OR tokens.owner = :owner)
+               AND (:listed IS NULL OR (metadata.fee IS NOT NULL) = :listed)
+               AND (:min_fee IS NULL OR metadata.fee_key >= :min_fee)
+               AND (:max_fee IS NULL OR metadata.fee_key <= :max_fee)
+               AND (:min_coi IS NULL OR analysis.coi >= :min_coi)
+               AND (:max_coi IS NULL OR analysis.coi <= :max_coi)
+               AND (:genesis IS NULL OR metadata.is_genesis = :genesis)
+               AND (:santa IS NULL OR metadata.is_santa = :santa)
+               AND (:parent IS NULL
+                    OR (metadata.left_parent_chain = tokens.self_chain
+                        AND metadata.left_parent_id = :parent)
+                    OR (metadata.right_parent_chain = tokens.self_chain
+                        AND metadata.right_parent_id = :parent))
+               AND (:name IS NULL OR metadata.name LIKE '%' || :name || '%' ESCAPE '\')
+               AND (:after_id IS NULL OR ({sort_key}, tokens.self_id) > (:after_key, :after_id))
+             ORDER BY sort_key ASC, tokens.self_id ASC
+             LIMIT :limit
+            "#
+        ))?;
+        let fee_key = |fee: &U256| format!("{fee:064x}");
+        let name = query.name.as_ref().map(|name| {
+            name.replace('\\', r"\\")
+                .replace('%', r"\%")
+                .replace('_', r"\_")
+        });
+        let (after_key, after_id) = match &query.after {
+            Some(TokenCursor { key, id }) => (Some(key), Some(id)),
+            None => (None, None),
+        };
+        let mut cursor = None;
+        let tokens = stmt
+            .query_map(
+                rusqlite::named_params! {
+                    ":chain": chain_id.into(),
+                    ":owner": query.owner.as_ref().map(addr_to_hex),
+                    ":listed": query.listed,
+                    ":min_fee": query.min_fee.as_ref().map(fee_key),
+                    ":max_fee": query.max_fee.as_ref().map(fee_key),
+                    ":min_coi": query.min_coi,
+                    ":max_coi": query.max_coi,
+                    ":genesis": query.genesis,
+                    ":santa": query.santa,
+                    ":parent": query.parent,
+                    ":name": name,
+                    ":after_key": after_key,
+                    ":after_id": after_id,
+                    ":limit": query.limit.map(i64::from).unwrap_or(-1),
+                },
+                |row| {
+                    macro_rules! get_parent {
+                        ($side:literal) => {
+                            row.get::<_, Option<ChainId>>(concat!($side, "_parent_chain"))?
+                                .map(|chain_id| {
+                                    let token_id =
+                                        row.get::<_, TokenId>(concat!($side, "_parent_id"))?;
+                                    Ok::<_, rusqlite::Error>(TroutId { chain_id, token_id })
+                                })
+                                .transpose()?
+                        };
+                    }
+                    let left_parent = get_parent!("left");
+                    let right_parent = get_parent!("right");
+                    let token = TokenForUi {
+                        id: row.get("self_id")?,
+                        coi: row.get("coi")?,
+                        owner: row.get::<_, String>("owner")?.parse().unwrap(),
+                        name: row.get("name")?,
+                        fee: row
+                            .get::<_, Option<String>>("fee")?
+                            .map(|f| f.parse().unwrap()),
+                        parents: left_parent.zip(right_parent),
+                        pending: row.get("pending")?,
+                        state: Lifecycle::new(row.get("version")?, row.get("incubated")?),
+                        minted_at: row.get("minted_at")?,
+                    };
+                    cursor = Some(TokenCursor {
+                        key: row.get("sort_key")?,
+                        id: token.id,
+                    });
+                    Ok(token)
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        let next = match query.limit {
+            Some(limit) if tokens.len() == limit as usize => cursor,
+            _ => None,
+        };
+        Ok((tokens, next))
+    }
+
+    fn stud_earnings(&self, chain_id: ChainId, owner: &Address) -> Result<U256, Error> {
+        let mut stmt = self.0.prepare_cached(
+            r#"
+            SELECT fee FROM studdings WHERE chain = ? AND owner = ? AND fee IS NOT NULL
+            "#,
+        )?;
+        let mut earnings = U256::zero();
+        let mut rows = stmt.query((chain_id, addr_to_hex(owner)))?;
+        while let Some(row) = rows.next()? {
+            let fee: U256 = row.get::<_, String>(0)?.parse().unwrap();
+            earnings = earnings.saturating_add(fee);
+        }
+        Ok(earnings)
+    }
+
+    fn market_stats(
+        &self,
+        chain_id: ChainId,
+        since: Option<u64>,
+        top: usize,
+    ) -> Result<MarketStats, Error> {
+        let total_trout = self.0.query_row(
+            "SELECT COUNT(*) FROM tokens WHERE self_chain = ?",
+            [chain_id],
+            |row| row.get::<_, u64>(0),
+        )?;
+
+        let fees = self
+            .0
+            .prepare_cached(
+                r#"
+                SELECT metadata.fee
+                  FROM tokens
+                  JOIN metadata ON metadata.token = tokens.id
+                 WHERE tokens.self_chain = ? AND metadata.fee IS NOT NULL
+                 ORDER BY metadata.fee_key ASC
+                "#,
+            )?
+            .query_map([chain_id], |row| {
+                Ok(row.get::<_, String>(0)?.parse::<U256>().unwrap())
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let median_fee = match fees.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(fees[n / 2]),
+            n => {
+                let (lo, hi) = (fees[n / 2 - 1], fees[n / 2]);
+                Some(lo + (hi - lo) / 2)
+            }
+        };
+
+        let mut stmt = self.0.prepare_cached(
+            r#"
+            SELECT studdings.child, studdings.stud, studdings.fee
+              FROM studdings
+              LEFT JOIN blocks ON blocks.chain = studdings.chain AND blocks.number = studdings.block
+             WHERE studdings.chain = :chain
+               AND (
+                       :since IS NULL
+                    OR blocks.timestamp >= :since
+                    OR (
+                           blocks.timestamp IS NULL
+                       AND studdings.block > IFNULL((
+                               SELECT MAX(number) FROM blocks
+                                WHERE chain = :chain AND timestamp < :since
+                           ), -1)
+                       )
+                   )
+            "#,
+        )?;
+        let mut rows = stmt.query(rusqlite::named_params! {
+            ":chain": chain_id,
+            ":since": since,
+        })?;
+        let mut children = std::collections::HashSet::new();
+        let mut volume = U256::zero();
+        let mut studs = std::collections::HashMap::<TokenId, (U256, u64)>::new();
+        while let Some(row) = rows.next()? {
+            children.insert(row.get::<_, TokenId>("child")?);
+            let Some(fee) = row.get::<_, Option<String>>("fee")? else {
+                continue;
+            };
+            let fee: U256 = fee.parse().unwrap();
+            volume = volume.saturating_add(fee);
+            let (earnings, paid_breeds) = studs.entry(row.get("stud")?).or_default();
+            *earnings = earnings.saturating_add(fee);
+            *paid_breeds += 1;
+        }
+        let mut top_studs = studs
+            .into_iter()
+            .map(|(token_id, (earnings, paid_breeds))| StudEarnings {
+                id: TroutId { chain_id, token_id },
+                earnings,
+                paid_breeds,
+            })
+            .collect::<Vec<_>>();
+        top_studs.sort_by(|a, b| {
+            b.earnings
+                .cmp(&a.earnings)
+                .then(a.id.token_id.cmp(&b.id.token_id))
+        });
+        top_studs.truncate(top);
+
+        Ok(MarketStats {
+            total_trout,
+            listed_studs: fees.len() as u64,
+            floor_fee: fees.first().copied(),
+            median_fee,
+            max_fee: fees.last().copied(),
+            breeds: children.len() as u64,
+            volume,
+            top_studs,
+        })
+    }
+
+    fn accrued_earnings(
+        &self,
+        chain_id: ChainId,
+        block: u64,
+    ) -> Result<HashMap<Address, U256>, Error> {
+        let mut stmt = self.0.prepare_cached(
+            r#"
+            SELECT owner, fee FROM studdings WHERE chain = ? AND block <= ? AND fee IS NOT NULL
+            "#,
+        )?;
+        let mut earnings = HashMap::<Address, U256>::new();
+        let mut rows = stmt.query((chain_id, block))?;
+        while let Some(row) = rows.next()? {
+            let owner = row.get::<_, String>(0)?.parse().unwrap();
+            let fee: U256 = row.get::<_, String>(1)?.parse().unwrap();
+            let accrued = earnings.entry(owner).or_default();
+            *accrued = accrued.saturating_add(fee);
+        }
+        Ok(earnings)
+    }
+
+    fn stud_payments(&self, chain_id: ChainId, owner: &Address) -> Result<Vec<StudPayment>, Error> {
+        self.0
+            .prepare_cached(
+                r#"
+                SELECT studdings.block, blocks.timestamp, studdings.stud, studdings.child,
+                       studdings.breeder, studdings.fee
+                  FROM studdings
+                  LEFT JOIN blocks
+                    ON blocks.chain = studdings.chain AND blocks.number = studdings.block
+                 WHERE studdings.chain = ? AND studdings.owner = ? AND studdings.fee IS NOT NULL
+                 ORDER BY studdings.block ASC, studdings.log_index ASC
+                "#,
+            )?
+            .query_map((chain_id, addr_to_hex(owner)), |row| {
+                let trout = |col| {
+                    Ok::<TroutId, rusqlite::Error>(TroutId {
+                        chain_id,
+                        token_id: row.get(col)?,
+                    })
+                };
+                Ok(StudPayment {
+                    block: row.get("block")?,
+                    timestamp: row.get("timestamp")?,
+                    stud: trout("stud")?,
+                    child: trout("child")?,
+                    breeder: row.get::<_, String>("breeder")?.parse().unwrap(),
+                    fee: row.get::<_, String>("fee")?.parse().unwrap(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn earnings_reconciliation(
+        &self,
+        chain_id: ChainId,
+        owner: &Address,
+    ) -> Result<Option<EarningsReconciliation>, Error> {
+        self.0
+            .prepare_cached(
+                r#"
+                SELECT block, accrued, withdrawable, reconciled_at
+                  FROM earnings_reconciliations
+                 WHERE chain = ? AND owner = ?
+                "#,
+            )?
+            .query_row((chain_id, addr_to_hex(owner)), |row| {
+                Ok(EarningsReconciliation {
+                    block: row.get("block")?,
+                    accrued: row.get::<_, String>("accrued")?.parse().unwrap(),
+                    withdrawable: row.get::<_, String>("withdrawable")?.parse().unwrap(),
+                    reconciled_at: row.get("reconciled_at")?,
+                })
+            })
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn token_events(
+        &self,
+        token: TroutId,
+        after: Option<EventCursor>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<EventForUi>, Option<EventCursor>), Error> {
+        self.query_events_for_ui(
+            token.chain_id,
+            &FeedQuery {
+                token: Some(token.token_id),
+                after,
+                limit,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn activity(
+        &self,
+        chain_id: ChainId,
+        before: Option<EventCursor>,
+        limit: u32,
+    ) -> Result<(Vec<EventForUi>, Option<EventCursor>), Error> {
+        self.query_events_for_ui(
+            chain_id,
+            &FeedQuery {
+                before,
+                newest_first: true,
+                limit: Some(limit),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn activity_in_blocks(
+        &self,
+        chain_id: ChainId,
+        blocks: std::ops::RangeInclusive<u64>,
+    ) -> Result<Vec<EventForUi>, Error> {
+        let (events, _) = self.query_events_for_ui(
+            chain_id,
+            &FeedQuery {
+                blocks: Some(blocks),
+                ..Default::default()
+            },
+        )?;
+        Ok(events)
+    }
+
+    fn stud_terms(&self, id: TroutId) -> Result<Option<(Address, Option<U256>)>, Error> {
+        self.0
+            .query_row(
+                r#"
+                SELECT tokens.owner, metadata.fee
+                  FROM tokens
+                  LEFT JOIN metadata ON metadata.token = tokens.id
+                 WHERE tokens.self_chain = ? AND tokens.self_id = ?
+                "#,
+                (id.chain_id, id.token_id),
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?.parse().unwrap(),
+                        row.get::<_, Option<String>>(1)?
+                            .map(|fee| fee.parse().unwrap()),
+                    ))
+                },
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn breeding_rates(&self, chain_id: ChainId) -> Result<(Option<U256>, Option<U256>), Error> {
+        let mut stmt = self.0.prepare_cached(
+            r#"
+            SELECT value FROM contract_events
+             WHERE chain = ? AND kind = ?
+             ORDER BY block DESC, log_index DESC
+             LIMIT 1
+            "#,
+        )?;
+        let mut latest = |kind| {
+            stmt.query_row((chain_id, kind), |row| row.get::<_, String>(0))
+                .optional()
+                .map(|value| value.map(|value| value.parse().unwrap()))
+        };
+        Ok((
+            latest("mint_reward_changed")?,
+            latest("matchmaking_fee_changed")?,
+        ))
+    }
+
+    fn contract_events(&self, chain_id: ChainId) -> Result<Vec<ContractEventForUi>, Error> {
+        self.0
+            .prepare_cached(
+                r#"
+                SELECT kind, block, log_index, value, previous, blocks.timestamp
+                  FROM contract_events
+                  LEFT JOIN blocks
+                    ON blocks.chain = contract_events.chain
+                   AND blocks.number = contract_events.block
+                 WHERE contract_events.chain = ?
+                 ORDER BY block ASC, log_index ASC
+                "#,
+            )?
+            .query_map([chain_id], |row| {
+                let kind: String = row.get("kind")?;
+                let value: String = row.get("value")?;
+                let previous: Option<String> = row.get("previous")?;
+                Ok(ContractEventForUi {
+                    block: row.get("block")?,
+                    timestamp: row.get("timestamp")?,
+                    kind: contract_event_kind(&kind, &value, previous.as_deref())
+                        .expect("malformed contract event"),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn latest_contract_changes(&self, chain_id: ChainId) -> Result<Vec<ContractEventKind>, Error> {
+        self.0
+            .prepare_cached(
+                r#"
+                SELECT kind, value, previous
+                  FROM contract_events
+                 WHERE chain = ?
+                   AND NOT EXISTS (
+                       SELECT 1 FROM contract_events AS later
+                        WHERE later.chain = contract_events.chain
+                          AND later.kind = contract_events.kind
+                          AND (later.block, later.log_index)
+                              > (contract_events.block, contract_events.log_index)
+                   )
+                 ORDER BY block ASC, log_index ASC
+                "#,
+            )?
+            .query_map([chain_id], |row| {
+                let kind: String = row.get("kind")?;
+                let value: String = row.get("value")?;
+                let previous: Option<String> = row.get("previous")?;
+                Ok(contract_event_kind(&kind, &value, previous.as_deref())
+                    .expect("malformed contract event"))
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn insert_tokens(&self, tokens: impl Iterator<Item = &TroutToken>) -> Result<(), Error> {
+        let mut token_inserter = self.0.prepare_cached(
+            r#"
+            INSERT INTO tokens (self_chain, self_id, owner) VALUES (?, ?, ?)
+            ON CONFLICT (self_chain, self_id) DO UPDATE SET owner = excluded.owner
+            "#,
+        )?;
+        let mut select_token_rowid = self
+            .0
+            .prepare_cached(r#"SELECT id FROM tokens WHERE self_chain = ? AND self_id = ?"#)?;
+        let mut metadata_inserter = self.0.prepare_cached(
+            r#"
+            INSERT INTO metadata (
+                token,
+                version, name,
+                fee,
+                is_genesis, is_santa,
+                left_parent_chain, left_parent_id,
+                right_parent_chain, right_parent_id,
+                indexed_at
+            ) VALUES (
+                ?,
+                ?, ?,
+                ?,
+                ?, ?,
+                ?, ?,
+                ?, ?,
+                unixepoch()
+            )
+            ON CONFLICT (token) DO UPDATE SET
+                version = excluded.version,
+                name = IIF(metadata.is_renamed, metadata.name, excluded.name),
+                fee = excluded.fee,
+                is_genesis = excluded.is_genesis,
+                is_santa = excluded.is_santa,
+                left_parent_chain = excluded.left_parent_chain,
+                left_parent_id = excluded.left_parent_id,
+                right_parent_chain = excluded.right_parent_chain,
+                right_parent_id = excluded.right_parent_id
+            "#,
+        )?;
+        let mut analysis_inserter = self.0.prepare_cached(
+            r#"
+            INSERT INTO analysis (token, coi) VALUES (?, ?)
+            ON CONFLICT (token) DO UPDATE SET coi = excluded.coi
+            "#,
+        )?;
+        let mut generation_inserter = self.0.prepare_cached(
+            r#"INSERT OR IGNORE INTO generations (token, ord, cid) VALUES (?, ?, ?)"#,
+        )?;
+        for token in tokens {
+            debug_assert!(!token.meta.name.is_empty());
+            let props = &token.meta.properties;
+            token_inserter.execute((
+                props.self_id.chain_id,
+                props.self_id.token_id,
+                addr_to_hex(&token.owner),
+            ))?;
+            let token_rowid: i64 = select_token_rowid
+                .query_row([props.self_id.chain_id, props.self_id.token_id], |row| {
+                    row.get(0)
+                })?;
+            metadata_inserter.execute((
+                token_rowid,
+                props.version,
+                &token.meta.name,
+                token.fee.as_ref().map(u256_to_hex),
+                props.attributes.genesis,
+                props.attributes.santa,
+                props.left.map(|token_id| token_id.chain_id),
+                props.left.map(|token_id| token_id.token_id),
+                props.right.map(|token_id| token_id.chain_id),
+                props.right.map(|token_id| token_id.token_id),
+            ))?;
+            analysis_inserter.execute((token_rowid, token.coi))?;
+            generation_inserter.execute((token_rowid, props.generations.len(), &*token.cid))?;
+            for (ord, generation) in props.generations.iter().enumerate() {
+                debug_assert!(!generation.is_empty());
+                generation_inserter.execute((token_rowid, ord, &**generation))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_pending_tokens(
+        &self,
+        chain_id: ChainId,
+        pending_tokens: impl Iterator<Item = (TokenId, PendingToken)>,
+    ) -> Result<(), Error> {
+        let mut token_inserter = self.0.prepare_cached(
+            r#"
+            INSERT INTO tokens (self_chain, self_id, owner)
+            VALUES (?, ?, ?)
+            ON CONFLICT (self_chain, self_id) DO NOTHING"#,
+        )?;
+        for (token_id, token) in pending_tokens {
+            token_inserter
+                .insert((chain_id, token_id, addr_to_hex(token.owner)))
+                .optional()?;
+        }
+        Ok(())
+    }
+
+    fn remove_pending_tokens(
+        &self,
+        chain_id: ChainId,
+        token_ids: impl Iterator<Item = TokenId>,
+    ) -> Result<(), Error> {
+        let mut remover = self.0.prepare_cached(
+            r#"
+            DELETE FROM tokens
+             WHERE self_chain = ? AND self_id = ?
+               AND NOT EXISTS (SELECT 1 FROM metadata WHERE metadata.token = tokens.id)
+            "#,
+        )?;
+        for token_id in token_ids {
+            remover.execute((chain_id, token_id))?;
+        }
+        Ok(())
+    }
+
+    fn set_token_name(&self, id: TroutId, name: &str) -> Result<(), Error> {
+        self.0.execute(
+            r#"
+            UPDATE metadata
+               SET name = ?, is_renamed = 1
+             WHERE token IN (SELECT id FROM tokens WHERE self_chain = ? AND self_id = ?)
+            "#,
+            (name, id.chain_id, id.token_id),
+        )?;
+        Ok(())
+    }
+
+    fn set_cois(&self, cois: impl Iterator<Item = (TroutId, f64)>) -> Result<(), Error> {
+        let mut updater = self.0.prepare_cached(
+            r#"
+            UPDATE analysis
+               SET coi = ?
+             WHERE token IN (SELECT id FROM tokens WHERE self_chain = ? AND self_id = ?)
+            "#,
+        )?;
+        for (trout_id, coi) in cois {
+            updater.execute((coi, trout_id.chain_id, trout_id.token_id))?;
+        }
+        Ok(())
+    }
+
+    fn update_fees(
+        &self,
+        chain_id: ChainId,
+        tokens: impl Iterator<Item = (TokenId, Option<&U256>)>,
+    ) -> Result<(), Error> {
+        let mut fee_updater = self.0.prepare_cached(
+            r#"
+                UPDATE metadata
+                   SET fee = ?
+                 WHERE token IN (SELECT id FROM tokens WHERE self_chain = ? AND self_id = ?)
+                "#,
+        )?;
+        for (token_id, fee) in tokens {
+            fee_updater.execute((fee.map(u256_to_hex), chain_id, token_id))?;
+        }
+        Ok(())
+    }
+
+    fn update_owners(
+        &self,
+        chain: ChainId,
+        token_owners: impl Iterator<Item = (TokenId, &Address)>,
+    ) -> Result<(), Error> {
+        let mut updater = self.0.prepare_cached(
+            r#"UPDATE tokens SET owner = ? WHERE self_chain = ? AND self_id = ?"#,
+        )?;
+        for (token_id, owner) in token_owners {
+            updater.execute((addr_to_hex(owner), chain, token_id))?;
+        }
+        Ok(())
+    }
+
+    fn mark_pinned(&self, cids: impl Iterator<Item = &Cid>) -> Result<(), Error> {
+        let mut updater = self
+            .0
+            .prepare_cached(r#"UPDATE generations SET pinned = 1 WHERE cid = ?"#)?;
+        for cid in cids {
+            updater.execute([&**cid])?;
+        }
+        Ok(())
+    }
+
+    fn mark_pin_failed(&self, cids: impl Iterator<Item = &Cid>) -> Result<(), Error> {
+        let mut updater = self
+            .0
+            .prepare_cached(r#"UPDATE generations SET pin_fails = pin_fails + 1 WHERE cid = ?"#)?;
+        for cid in cids {
+            updater.execute([&**cid])?;
+        }
+        Ok(())
+    }
+}
+
+impl StorageTx for Connection<'_> {
+    fn init_progress(&self, chain: ChainId, start_block: u64) -> Result<(), Error> {
+        self.0.execute(
+            r#"INSERT INTO progress (chain, block) VALUES (?, ?) ON CONFLICT (chain) DO NOTHING"#,
+            (chain, start_block),
+        )?;
+        Ok(())
+    }
+
+    fn record_events(
+        &self,
+        chain: ChainId,
+        events: impl Iterator<Item = &Event>,
+    ) -> Result<(), Error> {
+        let conn = &self.0;
+        let mut event_inserter = conn.prepare_cached(
+            r#"
+            INSERT OR IGNORE INTO events (
+                chain, kind, token, block, log_index, batch_index, observed_at
+            )
+            VALUES (?, ?, ?, ?, ?, ?, unixepoch())
+            RETURNING id
+            "#,
+        )?;
+        let mut list_event_inserter =
+            conn.prepare_cached(r#"INSERT INTO list_events (event, fee) VALUES (?, ?)"#)?;
+        let mut spawn_event_inserter =
+            conn.prepare_cached(r#"INSERT INTO spawn_events (event, recipient) VALUES (?, ?)"#)?;
+        let mut transfer_event_inserter = conn.prepare_cached(
+            r#"INSERT INTO transfer_events (event, sender, recipient) VALUES (?, ?, ?)"#,
+        )?;
+        let mut breed_event_inserter = conn.prepare_cached(
+            r#"INSERT INTO breed_events (event, left_parent, right_parent) VALUES (?, ?, ?)"#,
+        )?;
+        let mut contract_event_inserter = conn.prepare_cached(
+            r#"
+            INSERT OR IGNORE INTO contract_events (chain, kind, block, log_index, value, previous)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )?;
+        let mut block_inserter = conn.prepare_cached(
+            r#"INSERT OR REPLACE INTO blocks (chain, number, hash, timestamp) VALUES (?, ?, ?, ?)"#,
+        )?;
+        let mut block_pruner = conn.prepare_cached(
+            r#"
+            DELETE FROM blocks
+             WHERE chain = ?1 AND number < ?2
+               AND NOT EXISTS (
+                   SELECT 1 FROM events WHERE events.chain = ?1 AND events.block = blocks.number
+               )
+               AND NOT EXISTS (
+                   SELECT 1 FROM contract_events
+                    WHERE contract_events.chain = ?1 AND contract_events.block = blocks.number
+               )
+            "#,
+        )?;
+        let mut progress_updater =
+            conn.prepare_cached(r#"UPDATE progress SET block = ? WHERE chain = ?"#)?;
+
+        let mut last_block = None;
+        let mut last_header = None;
+        for event in events {
+            trace!(event = ?event, "processing event");
+            match event {
+                Event::Token(TokenEvent {
+                    token,
+                    kind,
+                    block,
+                    log_index,
+                    batch_index,
+                }) => {
+                    let rowid = event_inserter
+                        .query_row(
+                            (
+                                chain,
+                                match kind {
+                                    TokenEventKind::Spawned { .. } => 1,
+                                    TokenEventKind::Relisted { .. } => 2,
+                                    TokenEventKind::Transfer { .. } => 3,
+                                    TokenEventKind::Bred { .. } => 4,
+                                    TokenEventKind::Incubated => 5,
+                                },
+                                token,
+                                block,
+                                log_index,
+                                batch_index,
+                            ),
+                            |row| row.get::<_, i64>(0),
+                        )
+                        .optional()?;
+                    let event_id = match rowid {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    match kind {
+                        TokenEventKind::Spawned { to } => {
+                            spawn_event_inserter.insert((event_id, addr_to_hex(to)))?;
+                        }
+                        TokenEventKind::Relisted { fee } => {
+                            list_event_inserter
+                                .insert((event_id, fee.as_ref().map(u256_to_hex)))?;
+                        }
+                        TokenEventKind::Transfer { from, to } => {
+                            transfer_event_inserter.insert((
+                                event_id,
+                                addr_to_hex(from),
+                                addr_to_hex(to),
+                            ))?;
+                        }
+                        TokenEventKind::Bred { left, right } => {
+                            breed_event_inserter.insert((event_id, left, right))?;
+                        }
+                        TokenEventKind::Incubated => {}
+                    }
+                }
+                Event::Contract(ContractEvent {
+                    kind,
+                    block,
+                    log_index,
+                }) => {
+                    let (kind, value, previous) = contract_event_columns(kind);
+                    contract_event_inserter
+                        .execute((chain, kind, block, log_index, value, previous))?;
+                }
+                Event::Block(BlockHeader {
+                    number,
+                    hash,
+                    timestamp,
+                    ..
+                }) => {
+                    block_inserter.execute((chain, number, h256_to_hex(hash), timestamp))?;
+                    last_header = Some(*number);
+                }
+                Event::ProcessedBlock(block) => {
+                    last_block = Some(block);
+                }
+            }
+        }
+        if let Some(block) = last_header {
+            block_pruner.execute((chain, block.saturating_sub(BLOCK_HASH_RETENTION)))?;
+        }
+        if let Some(block) = last_block {
+            trace!(block = block, "processed block");
+            progress_updater.execute((last_block, chain))?;
+        }
+        Ok(())
+    }
+
+    fn record_earnings_reconciliations(
+        &self,
+        chain: ChainId,
+        block: u64,
+        reconciliations: impl Iterator<Item = (Address, U256, U256)>,
+    ) -> Result<(), Error> {
+        let mut inserter = self.0.prepare_cached(
+            r#"
+            INSERT INTO earnings_reconciliations (
+                chain, owner, block, accrued, withdrawable, reconciled_at
+            )
+            VALUES (?, ?, ?, ?, ?, unixepoch())
+            ON CONFLICT (chain, owner) DO UPDATE SET
+                block = excluded.block,
+                accrued = excluded.accrued,
+                withdrawable = excluded.withdrawable,
+                reconciled_at = excluded.reconciled_at
+            "#,
+        )?;
+        for (owner, accrued, withdrawable) in reconciliations {
+            inserter.execute((
+                chain,
+                addr_to_hex(&owner),
+                block,
+                u256_to_hex(&accrued),
+                u256_to_hex(&withdrawable),
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn roll_back_events(&self, chain: ChainId, fork_block: u64) -> Result<(), Error> {
+        let conn = &self.0;
+        for table in [
+            "spawn_events",
+            "list_events",
+            "transfer_events",
+            "breed_events",
+        ] {
+            conn.execute(
+                &format!(
+                    r#"
+                    DELETE FROM {table}
+                     WHERE event IN (SELECT id FROM events WHERE chain = ? AND block > ?)
+                    "#
+                ),
+                (chain, fork_block),
+            )?;
+        }
+        conn.execute(
+            r#"DELETE FROM events WHERE chain = ? AND block > ?"#,
+            (chain, fork_block),
+        )?;
+        conn.execute(
+            r#"DELETE FROM contract_events WHERE chain = ? AND block > ?"#,
+            (chain, fork_block),
+        )?;
+        conn.execute(
+            r#"DELETE FROM blocks WHERE chain = ? AND number > ?"#,
+            (chain, fork_block),
+        )?;
+        conn.execute(
+            r#"DELETE FROM earnings_reconciliations WHERE chain = ? AND block > ?"#,
+            (chain, fork_block),
+        )?;
+        conn.execute(
+            r#"UPDATE progress SET block = MIN(block, ?) WHERE chain = ?"#,
+            (fork_block, chain),
+        )?;
+        Ok(())
+    }
+}
+
+impl Connection<'_> {
+    fn query_lifecycles(
+        &self,
+        chain_id: ChainId,
+        token_id: Option<TokenId>,
+        waiting: bool,
+    ) -> Result<Vec<TokenLifecycle>, Error> {
+        self.0
+            .prepare_cached(
+                r#"
+                WITH
+                known AS (
+                    SELECT token AS id
+                      FROM events
+                     WHERE chain = :chain AND kind = 1 AND (:token IS NULL OR token = :token)
+                     UNION
+                    SELECT self_id AS id
+                      FROM tokens
+                     WHERE self_chain = :chain AND (:token IS NULL OR self_id = :token)
+                ),
+                stages AS (
+                    SELECT known.id,
+                           (
+                               SELECT MIN(COALESCE(blocks.timestamp, events.observed_at))
+                                 FROM events
+                                 LEFT JOIN blocks
+                                        ON blocks.chain = events.chain
+                                       AND blocks.number = events.block
+                                WHERE events.chain = :chain
+                                  AND events.kind = 1
+                                  AND events.token = known.id
+                           ) AS spawned_at,
+                           (
+                               SELECT MIN(COALESCE(blocks.timestamp, events.observed_at))
+                                 FROM events
+                                 LEFT JOIN blocks
+                                        ON blocks.chain = events.chain
+                                       AND blocks.number = events.block
+                                WHERE events.chain = :chain
+                                  AND events.kind = 5
+                                  AND events.token = known.id
+                           ) AS incubated_at,
+                           EXISTS (
+                               SELECT 1 FROM events
+                                WHERE chain = :chain AND kind = 5 AND token = known.id
+                           ) AS incubated
+                      FROM known
+                )
+                SELECT stages.*,
+                       metadata.version,
+                       metadata.indexed_at,
+                       unixepoch() AS now
+                  FROM stages
+                  LEFT JOIN tokens ON tokens.self_chain = :chain AND tokens.self_id = stages.id
+                  LEFT JOIN metadata ON metadata.token = tokens.id
+                 WHERE NOT :waiting OR metadata.version IS NULL
+                 ORDER BY stages.id ASC
+                "#,
+            )?
+            .query_map(
+                rusqlite::named_params! {
+                    ":chain": chain_id,
+                    ":token": token_id,
+                    ":waiting": waiting,
+                },
+                |row| {
+                    let state = Lifecycle::new(row.get("version")?, row.get("incubated")?);
+                    let spawned_at: Option<u64> = row.get("spawned_at")?;
+                    let incubated_at: Option<u64> = row.get("incubated_at")?;
+                    let since = match state {
+                        Lifecycle::Incubating => spawned_at,
+                        Lifecycle::AwaitingMetadata => incubated_at,
+                        Lifecycle::Indexed | Lifecycle::OutdatedVersion => None,
+                    };
+                    Ok(TokenLifecycle {
+                        id: row.get("id")?,
+                        state,
+                        spawned_at,
+                        incubated_at,
+                        indexed_at: row.get("indexed_at")?,
+                        waiting_secs: since
+                            .map(|since| {
+                                row.get::<_, u64>("now")
+                                    .map(|now| now.saturating_sub(since))
+                            })
+                            .transpose()?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn query_events_for_ui(
+        &self,
+        chain_id: ChainId,
+        query: &FeedQuery,
+    ) -> Result<(Vec<EventForUi>, Option<EventCursor>), Error> {
+        let order = if query.newest_first { "DESC" } else { "ASC" };
+        // Both parents of a breeding share its log, so a token's feed tells them apart by their
+        // side as the `batch_index`, while the chain's feed shows each breeding once, as the use of
+        // its left parent.
+        let mut stmt = self.0.prepare_cached(&format!(
+            r#"
+            WITH
+            feed_events AS (
+                SELECT events.id, events.token, events.block, events.log_index, events.batch_index
+                  FROM events
+                 WHERE events.chain = :chain AND (:token IS NULL OR events.token = :token)
+            ),
+            feed AS (
+                SELECT 'mint' AS kind, token, block, log_index, batch_index,
+                       NULL AS sender, spawn_events.recipient,
+                       NULL AS fee, NULL AS child, NULL AS coparent, NULL AS breeder, NULL AS owner
+                  FROM feed_events
+                  JOIN spawn_events ON spawn_events.event = feed_events.id
+                 UNION ALL
+                SELECT 'transfer', token, block, log_index, batch_index,
+                       transfer_events.sender, transfer_events.recipient,
+                       NULL, NULL, NULL, NULL, NULL
+                  FROM feed_events
+                  JOIN transfer_events ON transfer_events.event = feed_events.id
+                 UNION ALL
+                SELECT IIF(list_events.fee IS NULL, 'delisted', 'listed'),
+                       token, block, log_index, batch_index,
+                       NULL, NULL,
+                       list_events.fee, NULL, NULL, NULL, NULL
+                  FROM feed_events
+                  JOIN list_events ON list_events.event = feed_events.id
+                 UNION ALL
+                SELECT 'breed', stud, block, log_index, side,
+                       NULL, NULL,
+                       fee, child, coparent, breeder, owner
+                  FROM studdings
+                 WHERE chain = :chain AND (:token IS NULL AND side = 0 OR stud = :token)
+            )
+            SELECT feed.*, blocks.timestamp
+              FROM feed
+              LEFT JOIN blocks ON blocks.chain = :chain AND blocks.number = feed.block
+             WHERE (
+                       :after_block IS NULL
+                    OR (feed.block, feed.log_index, feed.batch_index)
+                       > (:after_block, :after_log_index, :after_batch_index)
+                   )
+               AND (
+                       :before_block IS NULL
+                    OR (feed.block, feed.log_index, feed.batch_index)
+                       < (:before_block, :before_log_index, :before_batch_index)
+                   )
+               AND (:from_block IS NULL OR feed.block BETWEEN :from_block AND :to_block)
+             ORDER BY feed.block {order}, feed.log_index {order}, feed.batch_index {order}
+             LIMIT :limit
+            "#
+        ))?;
+        let mut cursor = None;
+        let events = stmt
+            .query_map(
+                rusqlite::named_params! {
+                    ":chain": chain_id,
+                    ":token": query.token,
+                    ":after_block": query.after.map(|c| c.block),
+                    ":after_log_index": query.after.map(|c| c.log_index),
+                    ":after_batch_index": query.after.map(|c| c.batch_index),
+                    ":before_block": query.before.map(|c| c.block),
+                    ":before_log_index": query.before.map(|c| c.log_index),
+                    ":before_batch_index": query.before.map(|c| c.batch_index),
+                    ":from_block": query.blocks.as_ref().map(|b| *b.start()),
+                    ":to_block": query.blocks.as_ref().map(|b| *b.end()),
+                    ":limit": query.limit.map(i64::from).unwrap_or(-1),
+                },
+                |row| {
+                    let addr = |col| {
+                        Ok::<Address, rusqlite::Error>(
+                            row.get::<_, Option<String>>(col)?
+                                .map(|a| a.parse().unwrap())
+                                .unwrap_or_default(),
+                        )
+                    };
+                    let fee = || {
+                        Ok::<U256, rusqlite::Error>(
+                            row.get::<_, Option<String>>("fee")?
+                                .map(|f| f.parse().unwrap())
+                                .unwrap_or_default(),
+                        )
+                    };
+                    let trout = |col| {
+                        Ok::<TroutId, rusqlite::Error>(TroutId {
+                            chain_id,
+                            token_id: row.get(col)?,
+                        })
+                    };
+                    let kind = match row.get_ref("kind")?.as_str()? {
+                        "mint" => EventKindForUi::Mint {
+                            to: addr("recipient")?,
+                        },
+                        "transfer" => EventKindForUi::Transfer {
+                            from: addr("sender")?,
+                            to: addr("recipient")?,
+                        },
+                        "listed" => EventKindForUi::Listed { fee: fee()? },
+                        "delisted" => EventKindForUi::Delisted,
+                        "breed" => EventKindForUi::Breed {
+                            breeder: addr("breeder")?,
+                            child: trout("child")?,
+                            coparent: trout("coparent")?,
+                            price: fee()?,
+                            owner: addr("owner")?,
+                        },
+                        kind => {
+                            return Err(rusqlite::Error::FromSqlConversionFailure(
+                                row.as_ref().column_index("kind")?,
+                                rusqlite::types::Type::Text,
+                                Box::new(Error::UnknownEventKind(kind.into())),
+                            ))
+                        }
+                    };
+                    let position = EventCursor {
+                        block: row.get("block")?,
+                        log_index: row.get("log_index")?,
+                        batch_index: row.get("batch_index")?,
+                    };
+                    cursor = Some(position);
+                    Ok(EventForUi {
+                        id: trout("token")?,
+                        block: position.block,
+                        timestamp: row.get("timestamp")?,
+                        kind,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        let next = match query.limit {
+            Some(limit) if events.len() == limit as usize => cursor,
+            _ => None,
+        };
+        Ok((events, next))
+    }
+}
+
+impl Connection<'_> {
+    #[cfg(test)]
+    pub(super) fn execute_batch(&self, sql: &str) -> Result<(), Error> {
+        self.0.execute_batch(sql).map_err(Into::into)
+    }
+}
+
+/// The bare version number kept before migrations were recorded individually.
+const USER_VERSION: &str = "user_version";
+
+impl MigrationStore for Connection<'_> {
+    fn create_migrations_table(&self) -> Result<(), Error> {
+        // The writer's transaction already excludes other indexers.
+        self.0.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS schema_migrations (
+              ord INTEGER PRIMARY KEY,
+              name TEXT NOT NULL,
+              checksum TEXT NOT NULL,
+              applied_at INTEGER NOT NULL
+            );
+            "#,
+        )?;
+        Ok(())
+    }
+
+    fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, Error> {
+        let exists = self
+            .0
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations'",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if !exists {
+            return Ok(Vec::new());
+        }
+        self.0
+            .prepare("SELECT ord, name, checksum FROM schema_migrations ORDER BY ord ASC")?
+            .query_map([], |row| {
+                Ok(AppliedMigration {
+                    ord: row.get(0)?,
+                    name: row.get(1)?,
+                    checksum: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn legacy_version(&self) -> Result<usize, Error> {
+        Ok(self
+            .0
+            .pragma_query_value(None, USER_VERSION, |row| row.get(0))?)
+    }
+
+    fn set_legacy_version(&self, version: usize) -> Result<(), Error> {
+        self.0.pragma_update(None, USER_VERSION, version)?;
+        Ok(())
+    }
+
+    fn execute_migration(&self, sql: &str) -> Result<(), Error> {
+        self.0.execute_batch(sql)?;
+        Ok(())
+    }
+
+    fn record_migration(&self, ord: usize, migration: &Migration) -> Result<(), Error> {
+        self.0.execute(
+            r#"
+            INSERT INTO schema_migrations (ord, name, checksum, applied_at)
+            VALUES (?, ?, ?, unixepoch())
+            "#,
+            (ord, migration.name, migration.checksum()),
+        )?;
+        Ok(())
+    }
+
+    fn forget_migration(&self, ord: usize) -> Result<(), Error> {
+        self.0
+            .execute("DELETE FROM schema_migrations WHERE ord = ?", [ord])?;
+        Ok(())
+    }
+}
+
+impl ToSql for SortKey {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            Self::Integer(i) => (*i).into(),
+            Self::Real(r) => (*r).into(),
+            Self::Text(t) => t.as_str().into(),
+        })
+    }
+}
+
+impl FromSql for SortKey {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Ok(match value {
+            ValueRef::Integer(i) => Self::Integer(i),
+            ValueRef::Real(r) => Self::Real(r),
+            ValueRef::Text(_) => Self::Text(value.as_str()?.into()),
+            ValueRef::Null | ValueRef::Blob(_) => return Err(FromSqlError::InvalidType),
+        })
+    }
+}
+
+fn open_connection(connstr: &str) -> Result<rusqlite::Connection, Error> {
+    let conn = rusqlite::Connection::open(connstr)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // In-memory databases keep their own journal mode, which is returned instead.
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    Ok(conn)
+}
@@ -1,6 +1,9 @@
 use rand::Rng as _;
 
 use super::*;
+use crate::nftrout::{
+    BlockHeader, ContractEvent, EventKindForUi, Lifecycle, StudEarnings, TokenEvent, TokenEventKind,
+};
 
 fn test_cid() -> Cid {
     let mut rng = rand::thread_rng();
@@ -17,7 +20,7 @@ fn test_trout_id() -> TroutId {
     }
 }
 
-fn test_token() -> TroutToken {
+pub(crate) fn test_token() -> TroutToken {
     TroutToken {
         cid: test_cid(),
         meta: crate::nftrout::TroutMetadata {
@@ -45,7 +48,7 @@ fn test_token() -> TroutToken {
 
 #[test]
 fn token_cid() {
-    let db = Db::open_in_memory().unwrap();
+    let db = Db::open_for_test().unwrap();
     db.with_conn(|conn| {
         let tt1 = test_token();
         let tt2 = test_token();
@@ -68,14 +71,1061 @@ fn token_cid() {
     .unwrap();
 }
 
+#[test]
+fn generation_history() {
+    let db = Db::open_for_test().unwrap();
+    db.with_conn(|conn| {
+        let token = test_token();
+        let id = token.meta.properties.self_id;
+        conn.insert_tokens([token.clone()].iter())?;
+        let cids = |conn: &Connection| -> Result<Vec<Cid>, Error> {
+            Ok(conn
+                .token_generations(id)?
+                .into_iter()
+                .map(|generation| generation.cid)
+                .collect())
+        };
+        let mut history = token.meta.properties.generations.clone();
+        history.push(token.cid.clone());
+        assert_eq!(cids(&conn)?, history);
+        assert!(conn.tokens_missing_generations(id.chain_id)?.is_empty());
+
+        // Only the latest generation was kept before generations were keyed on their ord.
+        conn.execute_batch("DELETE FROM generations WHERE ord < 2")?;
+        assert_eq!(
+            conn.tokens_missing_generations(id.chain_id)?,
+            [(id, token.cid.clone())]
+        );
+        conn.insert_generations(id, &token.meta.properties.generations)?;
+        assert_eq!(cids(&conn)?, history);
+        assert!(conn.tokens_missing_generations(id.chain_id)?.is_empty());
+        assert!(conn.token_generations(test_trout_id())?.is_empty());
+        Ok(())
+    })
+    .unwrap();
+}
+
 #[test]
 fn duplicate_token() {
-    let db = Db::open_in_memory().unwrap();
+    let db = Db::open_for_test().unwrap();
     db.with_conn(|conn| {
         let token = test_token();
-        conn.insert_tokens([token.clone(), token].iter())
-            .unwrap_err();
+        let chain_id = token.meta.properties.self_id.chain_id;
+        conn.insert_tokens([token.clone(), token].iter())?;
+        assert_eq!(conn.token_ids(chain_id)?.len(), 1);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn reindex_token() {
+    let db = Db::open_for_test().unwrap();
+    let (renamed, original) = (test_token(), test_token());
+    let reindexed = |token: &TroutToken| {
+        let mut token = token.clone();
+        token.meta.properties.version = crate::nftrout::CURRENT_VERSION;
+        token.meta.properties.generations.push(token.cid.clone());
+        token.cid = test_cid();
+        token.meta.name = "Upgraded TROUT".into();
+        token.fee = Some(0x42.into());
+        token.coi = 0.5;
+        token
+    };
+    let trout = |conn: &Connection, token: &TroutToken| -> Result<TokenForUi, Error> {
+        let id = token.meta.properties.self_id;
+        Ok(conn
+            .list_tokens_for_ui(id.chain_id)?
+            .into_iter()
+            .find(|t| t.id == id.token_id)
+            .unwrap())
+    };
+    db.with_conn(|conn| {
+        conn.insert_tokens([renamed.clone(), original.clone()].iter())?;
+        conn.set_token_name(renamed.meta.properties.self_id, "Sir Swims-a-Lot")?;
+        assert_eq!(trout(&conn, &renamed)?.state, Lifecycle::OutdatedVersion);
+        // Backdated so that a re-index within the same second would still be noticed.
+        conn.execute_batch("UPDATE metadata SET indexed_at = 1000")?;
+
+        let (renamed, original) = (reindexed(&renamed), reindexed(&original));
+        conn.insert_tokens([renamed.clone(), original.clone()].iter())?;
+        let lifecycle = conn
+            .token_lifecycle(renamed.meta.properties.self_id)?
+            .unwrap();
+        assert_eq!(lifecycle.indexed_at, Some(1000));
+
+        let upgraded = trout(&conn, &renamed)?;
+        assert_eq!(upgraded.state, Lifecycle::Indexed);
+        assert_eq!(upgraded.name, "Sir Swims-a-Lot");
+        assert_eq!(upgraded.fee, Some(0x42.into()));
+        assert_eq!(upgraded.coi, 0.5);
+        assert_eq!(upgraded.owner, renamed.owner);
+        assert_eq!(trout(&conn, &original)?.name, "Upgraded TROUT");
+
+        let id = renamed.meta.properties.self_id;
+        assert_eq!(conn.token_cid(&id, None)?, Some(renamed.cid.clone()));
+        assert_eq!(
+            conn.token_cid(&id, Some(2))?.as_ref(),
+            renamed.meta.properties.generations.last()
+        );
+        assert_eq!(conn.token_generations(id)?.len(), 4);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn roll_back_events() {
+    let db = Db::open_for_test().unwrap();
+    let chain = 31337;
+    let owner: Address = rand::random();
+    let header = |number: u64| BlockHeader {
+        number,
+        hash: rand::random(),
+        parent_hash: rand::random(),
+        timestamp: number * 6,
+    };
+    let spawn = |token: TokenId, block: u64| {
+        Event::Token(TokenEvent {
+            token,
+            kind: TokenEventKind::Spawned { to: owner },
+            block,
+            log_index: 0,
+            batch_index: 0,
+        })
+    };
+    let (h1, h2, h3) = (header(1), header(2), header(3));
+    db.with_tx(|tx| {
+        tx.record_events(
+            chain,
+            [
+                Event::Block(h1),
+                spawn(1, 1),
+                Event::ProcessedBlock(1),
+                Event::Block(h2),
+                spawn(2, 2),
+                Event::ProcessedBlock(2),
+                Event::Block(h3),
+                spawn(3, 3),
+                Event::ProcessedBlock(3),
+            ]
+            .iter(),
+        )
+    })
+    .unwrap();
+
+    db.with_tx(|tx| {
+        assert_eq!(tx.block_hash(chain, 2)?, Some(h2.hash));
+        assert_eq!(tx.tokens_changed_since(chain, 1)?, vec![2, 3]);
+        tx.roll_back_events(chain, 1)?;
+        assert_eq!(tx.block_hash(chain, 1)?, Some(h1.hash));
+        assert_eq!(tx.block_hash(chain, 2)?, None);
+        assert!(tx.tokens_changed_since(chain, 1)?.is_empty());
+        assert_eq!(tx.tokens_changed_since(chain, 0)?, vec![1]);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn query_tokens() {
+    let db = Db::open_for_test().unwrap();
+    let owner: Address = rand::random();
+    let make_token = |token_id: TokenId, name: &str, fee: Option<u64>, coi: f64| {
+        let mut token = test_token();
+        token.meta.name = name.into();
+        token.meta.properties.self_id = TroutId {
+            chain_id: 31337,
+            token_id,
+        };
+        token.meta.properties.attributes.genesis = token_id == 1;
+        token.fee = fee.map(Into::into);
+        token.coi = coi;
+        token
+    };
+    let mut tokens = vec![
+        make_token(1, "Genesis 100%", Some(0x100), 0.0),
+        make_token(2, "Spotted", None, 0.0),
+        make_token(3, "Speckled", Some(0x20), 0.25),
+        make_token(4, "Spotted Too", Some(0x3000), 0.125),
+    ];
+    for child in &mut tokens[2..] {
+        let parent = |token_id| TroutId {
+            chain_id: 31337,
+            token_id,
+        };
+        child.meta.properties.left = Some(parent(1));
+        child.meta.properties.right = Some(parent(2));
+    }
+    tokens[3].owner = owner;
+    db.with_conn(|conn| conn.insert_tokens(tokens.iter()))
+        .unwrap();
+
+    let ids = |query: TokenQuery| {
+        db.with_conn(|conn| conn.query_tokens_for_ui(31337, &query))
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|t| t.id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids(TokenQuery::default()), [1, 2, 3, 4]);
+    assert_eq!(
+        ids(TokenQuery {
+            owner: Some(owner),
+            ..Default::default()
+        }),
+        [4]
+    );
+    assert_eq!(
+        ids(TokenQuery {
+            listed: Some(false),
+            ..Default::default()
+        }),
+        [2]
+    );
+    assert_eq!(
+        ids(TokenQuery {
+            min_fee: Some(0x21.into()),
+            max_fee: Some(0x1000.into()),
+            ..Default::default()
+        }),
+        [1]
+    );
+    assert_eq!(
+        ids(TokenQuery {
+            min_coi: Some(0.1),
+            ..Default::default()
+        }),
+        [3, 4]
+    );
+    assert_eq!(
+        ids(TokenQuery {
+            genesis: Some(true),
+            ..Default::default()
+        }),
+        [1]
+    );
+    assert_eq!(
+        ids(TokenQuery {
+            parent: Some(2),
+            ..Default::default()
+        }),
+        [3, 4]
+    );
+    assert_eq!(
+        ids(TokenQuery {
+            name: Some("spotted".into()),
+            ..Default::default()
+        }),
+        [2, 4]
+    );
+    assert_eq!(
+        ids(TokenQuery {
+            name: Some("0%".into()),
+            ..Default::default()
+        }),
+        [1]
+    );
+    assert!(ids(TokenQuery {
+        name: Some("S_o".into()),
+        ..Default::default()
+    })
+    .is_empty());
+    assert_eq!(
+        ids(TokenQuery {
+            sort: TokenSort::Fee,
+            ..Default::default()
+        }),
+        [3, 1, 4, 2]
+    );
+    assert_eq!(
+        ids(TokenQuery {
+            sort: TokenSort::Coi,
+            ..Default::default()
+        }),
+        [1, 2, 4, 3]
+    );
+    assert_eq!(
+        ids(TokenQuery {
+            sort: TokenSort::Newest,
+            ..Default::default()
+        }),
+        [4, 3, 2, 1]
+    );
+
+    for sort in [
+        TokenSort::Id,
+        TokenSort::Fee,
+        TokenSort::Coi,
+        TokenSort::Newest,
+        TokenSort::Minted,
+    ] {
+        let mut query = TokenQuery {
+            sort,
+            limit: Some(3),
+            ..Default::default()
+        };
+        let mut pages = Vec::new();
+        loop {
+            let (page, next) = db
+                .with_conn(|conn| conn.query_tokens_for_ui(31337, &query))
+                .unwrap();
+            pages.push(page.into_iter().map(|t| t.id).collect::<Vec<_>>());
+            let Some(next) = next else { break };
+            query.after = Some(TokenCursor::parse(sort, &next.to_string()).unwrap());
+        }
+        let unpaged = ids(TokenQuery {
+            sort,
+            ..Default::default()
+        });
+        assert_eq!(pages.len(), 2, "{sort:?}");
+        assert_eq!(pages.concat(), unpaged, "{sort:?}");
+    }
+}
+
+#[test]
+fn studdings() {
+    let db = Db::open_for_test().unwrap();
+    let chain = 31337;
+    let (alice, bob, carol): (Address, Address, Address) =
+        (rand::random(), rand::random(), rand::random());
+    let bred = TokenEventKind::Bred { left: 1, right: 2 };
+    let events = [
+        (1, TokenEventKind::Spawned { to: alice }, 1),
+        (2, TokenEventKind::Spawned { to: carol }, 1),
+        (
+            1,
+            TokenEventKind::Relisted {
+                fee: Some(0x10.into()),
+            },
+            2,
+        ),
+        (
+            2,
+            TokenEventKind::Relisted {
+                fee: Some(0x20.into()),
+            },
+            2,
+        ),
+        // Bob pays both studs.
+        (3, TokenEventKind::Spawned { to: bob }, 3),
+        (3, bred.clone(), 3),
+        (
+            1,
+            TokenEventKind::Transfer {
+                from: alice,
+                to: bob,
+            },
+            4,
+        ),
+        // Bob now owns the left parent and only pays for the right.
+        (4, bred.clone(), 5),
+        (4, TokenEventKind::Spawned { to: bob }, 5),
+        (2, TokenEventKind::Relisted { fee: None }, 6),
+        // Alice pays Bob, but not Carol, who delisted.
+        (5, TokenEventKind::Spawned { to: alice }, 7),
+        (5, bred.clone(), 7),
+    ];
+    db.with_tx(|tx| {
+        tx.record_events(
+            chain,
+            events
+                .into_iter()
+                .enumerate()
+                .map(|(i, (token, kind, block))| {
+                    Event::Token(TokenEvent {
+                        token,
+                        kind,
+                        block,
+                        log_index: i as u64,
+                        batch_index: 0,
+                    })
+                })
+                .collect::<Vec<_>>()
+                .iter(),
+        )?;
+        let header = BlockHeader {
+            number: 3,
+            hash: rand::random(),
+            parent_hash: rand::random(),
+            timestamp: 1_700_000_000,
+        };
+        tx.record_events(chain, [Event::Block(header)].iter())
+    })
+    .unwrap();
+
+    db.with_conn(|conn| {
+        assert_eq!(conn.stud_earnings(chain, &alice)?, 0x10.into());
+        assert_eq!(conn.stud_earnings(chain, &bob)?, 0x10.into());
+        assert_eq!(conn.stud_earnings(chain, &carol)?, 0x40.into());
+        assert_eq!(conn.stud_earnings(chain + 1, &carol)?, 0.into());
+
+        let stud = TroutId {
+            chain_id: chain,
+            token_id: 1,
+        };
+        let (feed, next) = conn.token_events(stud, None, None)?;
+        assert!(next.is_none());
+        assert_eq!(feed[2].timestamp, Some(1_700_000_000));
+        assert!(feed.iter().all(|event| event.id == stud));
+        let feed = feed
+            .into_iter()
+            .map(|event| (event.block, event.kind))
+            .collect::<Vec<_>>();
+        let breeding = |child, breeder, owner, price: u64| EventKindForUi::Breed {
+            breeder,
+            child: TroutId {
+                chain_id: chain,
+                token_id: child,
+            },
+            coparent: TroutId {
+                chain_id: chain,
+                token_id: 2,
+            },
+            price: price.into(),
+            owner,
+        };
+        assert_eq!(
+            feed,
+            [
+                (1, EventKindForUi::Mint { to: alice }),
+                (2, EventKindForUi::Listed { fee: 0x10.into() }),
+                (3, breeding(3, bob, alice, 0x10)),
+                (
+                    4,
+                    EventKindForUi::Transfer {
+                        from: alice,
+                        to: bob,
+                    }
+                ),
+                (5, breeding(4, bob, bob, 0)),
+                (7, breeding(5, alice, bob, 0x10)),
+            ]
+        );
+
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let (page, next) = conn.token_events(stud, after, Some(4))?;
+            pages.push(
+                page.into_iter()
+                    .map(|event| event.block)
+                    .collect::<Vec<_>>(),
+            );
+            match next {
+                Some(next) => after = Some(next.to_string().parse().unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(pages, [vec![1, 2, 3, 4], vec![5, 7]]);
+
+        let coparent = TroutId {
+            chain_id: chain,
+            token_id: 2,
+        };
+        let (feed, _) = conn.token_events(coparent, None, None)?;
+        assert_eq!(feed[feed.len() - 2].kind, EventKindForUi::Delisted);
+
+        let payments = conn.stud_payments(chain, &carol)?;
+        assert_eq!(
+            payments
+                .iter()
+                .map(|p| (p.block, p.child.token_id, p.breeder, p.fee))
+                .collect::<Vec<_>>(),
+            [(3, 3, bob, 0x20.into()), (5, 4, bob, 0x20.into())]
+        );
+        assert_eq!(payments[0].timestamp, Some(1_700_000_000));
+        assert_eq!(
+            conn.accrued_earnings(chain, 5)?,
+            [(alice, 0x10.into()), (carol, 0x40.into())].into()
+        );
+        Ok(())
+    })
+    .unwrap();
+
+    db.with_tx(|tx| {
+        tx.record_earnings_reconciliations(
+            chain,
+            5,
+            [(carol, 0x40.into(), 0x3e.into())].into_iter(),
+        )?;
+        tx.record_earnings_reconciliations(
+            chain,
+            7,
+            [(carol, 0x40.into(), 0x20.into())].into_iter(),
+        )?;
+        let reconciliation = tx.earnings_reconciliation(chain, &carol)?.unwrap();
+        assert_eq!(reconciliation.block, 7);
+        assert_eq!(reconciliation.withdrawable, 0x20.into());
+        tx.roll_back_events(chain, 6)?;
+        assert!(tx.earnings_reconciliation(chain, &carol)?.is_none());
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn lifecycles() {
+    let db = Db::open_for_test().unwrap();
+    let chain = 31337;
+    let indexed = |token_id: TokenId, version| {
+        let mut token = test_token();
+        token.meta.properties.self_id = TroutId {
+            chain_id: chain,
+            token_id,
+        };
+        token.meta.properties.version = version;
+        token
+    };
+    let events = [
+        (1, TokenEventKind::Spawned { to: rand::random() }),
+        (2, TokenEventKind::Spawned { to: rand::random() }),
+        (3, TokenEventKind::Spawned { to: rand::random() }),
+        (2, TokenEventKind::Incubated),
+        (3, TokenEventKind::Incubated),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, (token, kind))| {
+        Event::Token(TokenEvent {
+            token,
+            kind,
+            // The header of block 2 has not been fetched.
+            block: if token == 3 { 2 } else { 1 },
+            log_index: i as u64,
+            batch_index: 0,
+        })
+    })
+    .collect::<Vec<_>>();
+    let header = BlockHeader {
+        number: 1,
+        hash: rand::random(),
+        parent_hash: rand::random(),
+        timestamp: 1_000,
+    };
+    db.with_tx(|tx| {
+        tx.record_events(chain, [Event::Block(header)].iter().chain(events.iter()))?;
+        tx.insert_tokens([indexed(3, crate::nftrout::CURRENT_VERSION), indexed(4, 1)].iter())
+    })
+    .unwrap();
+
+    db.with_conn(|conn| {
+        let state = |token_id| {
+            conn.token_lifecycle(TroutId {
+                chain_id: chain,
+                token_id,
+            })
+            .map(|lc| lc.map(|lc| lc.state))
+        };
+        assert_eq!(state(1)?, Some(Lifecycle::Incubating));
+        assert_eq!(state(2)?, Some(Lifecycle::AwaitingMetadata));
+        assert_eq!(state(3)?, Some(Lifecycle::Indexed));
+        assert_eq!(state(4)?, Some(Lifecycle::OutdatedVersion));
+        assert_eq!(state(5)?, None);
+
+        let waiting = conn.waiting_tokens(chain)?;
+        assert_eq!(waiting.iter().map(|lc| lc.id).collect::<Vec<_>>(), [1, 2]);
+        assert!(waiting.iter().all(|lc| lc.waiting_secs.is_some()));
+        assert!(waiting[0].incubated_at.is_none());
+        assert_eq!(waiting[0].spawned_at, Some(1_000));
+        assert_eq!(waiting[1].incubated_at, Some(1_000));
+
+        let lc = conn
+            .token_lifecycle(TroutId {
+                chain_id: chain,
+                token_id: 3,
+            })?
+            .unwrap();
+        assert!(lc.spawned_at.is_some() && lc.incubated_at.is_some() && lc.indexed_at.is_some());
+        assert_ne!(lc.spawned_at, Some(1_000));
+        assert_eq!(lc.waiting_secs, None);
         Ok(())
     })
     .unwrap();
 }
+
+#[test]
+fn batch_events() {
+    let db = Db::open_for_test().unwrap();
+    let chain = 31337;
+    let minter: Address = rand::random();
+    let events = (1..=5)
+        .zip(0..)
+        .map(|(token, batch_index)| {
+            Event::Token(TokenEvent {
+                token,
+                kind: TokenEventKind::Spawned { to: minter },
+                block: 1,
+                log_index: 0,
+                batch_index,
+            })
+        })
+        .collect::<Vec<_>>();
+    db.with_tx(|tx| {
+        tx.record_events(chain, events.iter())?;
+        tx.record_events(chain, events.iter())
+    })
+    .unwrap();
+    db.with_conn(|conn| {
+        assert_eq!(conn.tokens_changed_since(chain, 0)?, [1, 2, 3, 4, 5]);
+        assert_eq!(conn.waiting_tokens(chain)?.len(), 5);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn contract_events() {
+    let db = Db::open_for_test().unwrap();
+    let chain = 31337;
+    let (owner, hub): (Address, Address) = (rand::random(), rand::random());
+    let events = [
+        ContractEventKind::OwnershipTransferred {
+            from: Address::zero(),
+            to: owner,
+        },
+        ContractEventKind::TaskHubChanged { to: hub },
+        ContractEventKind::MintRewardChanged {
+            reward: 1000.into(),
+        },
+        ContractEventKind::Paused { account: owner },
+        ContractEventKind::MintRewardChanged {
+            reward: 2000.into(),
+        },
+    ]
+    .into_iter()
+    .zip(1..)
+    .map(|(kind, block)| ContractEvent {
+        kind,
+        block,
+        log_index: 0,
+    })
+    .collect::<Vec<_>>();
+    db.with_tx(|tx| {
+        tx.record_events(
+            chain,
+            events
+                .iter()
+                .cloned()
+                .map(Event::Contract)
+                .collect::<Vec<_>>()
+                .iter(),
+        )
+    })
+    .unwrap();
+
+    let for_ui = |events: &[ContractEvent]| {
+        events
+            .iter()
+            .map(|event| ContractEventForUi {
+                block: event.block,
+                timestamp: None,
+                kind: event.kind.clone(),
+            })
+            .collect::<Vec<_>>()
+    };
+    db.with_tx(|tx| {
+        assert_eq!(tx.contract_events(chain)?, for_ui(&events));
+        assert!(tx.contract_events(chain + 1)?.is_empty());
+        assert_eq!(tx.breeding_rates(chain)?, (Some(2000.into()), None));
+        let kinds = |events: &[ContractEvent]| {
+            events
+                .iter()
+                .map(|event| event.kind.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tx.latest_contract_changes(chain)?,
+            kinds(&[&events[..2], &events[3..]].concat())
+        );
+        tx.roll_back_events(chain, 3)?;
+        assert_eq!(tx.contract_events(chain)?, for_ui(&events[..3]));
+        assert_eq!(tx.latest_contract_changes(chain)?, kinds(&events[..3]));
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn block_timestamps() {
+    let db = Db::open_for_test().unwrap();
+    let chain = 31337;
+    let tokens = (1..=4)
+        .map(|token_id| {
+            let mut token = test_token();
+            token.meta.properties.self_id = TroutId {
+                chain_id: chain,
+                token_id,
+            };
+            token
+        })
+        .collect::<Vec<_>>();
+    let header = |number| {
+        Event::Block(BlockHeader {
+            number,
+            hash: rand::random(),
+            parent_hash: rand::random(),
+            timestamp: 1_700_000_000 + number * 6,
+        })
+    };
+    db.with_conn(|conn| conn.insert_tokens(tokens.iter()))
+        .unwrap();
+    db.with_tx(|tx| {
+        let mut events = [(1, 4), (2, 3), (3, 5), (4, 6)]
+            .into_iter()
+            .map(|(token, block)| {
+                Event::Token(TokenEvent {
+                    token,
+                    kind: TokenEventKind::Spawned { to: rand::random() },
+                    block,
+                    log_index: 0,
+                    batch_index: 0,
+                })
+            })
+            .collect::<Vec<_>>();
+        events.push(Event::Contract(ContractEvent {
+            kind: ContractEventKind::MintRewardChanged { reward: 1.into() },
+            block: 7,
+            log_index: 0,
+        }));
+        events.extend([header(3), header(4)]);
+        tx.record_events(chain, events.iter())
+    })
+    .unwrap();
+
+    let minted = || {
+        db.with_conn(|conn| {
+            conn.query_tokens_for_ui(
+                chain,
+                &TokenQuery {
+                    sort: TokenSort::Minted,
+                    ..Default::default()
+                },
+            )
+        })
+        .unwrap()
+        .0
+        .into_iter()
+        .map(|t| (t.id, t.minted_at))
+        .collect::<Vec<_>>()
+    };
+
+    db.with_conn(|conn| {
+        assert_eq!(conn.blocks_missing_timestamps(chain, 10)?, [5, 6, 7]);
+        assert_eq!(conn.blocks_missing_timestamps(chain, 2)?, [5, 6]);
+        assert!(conn.blocks_missing_timestamps(chain + 1, 10)?.is_empty());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(
+        minted(),
+        [
+            (2, Some(1_700_000_018)),
+            (1, Some(1_700_000_024)),
+            (3, None),
+            (4, None)
+        ]
+    );
+
+    db.with_tx(|tx| tx.record_events(chain, [header(6), header(7)].iter()))
+        .unwrap();
+    db.with_conn(|conn| {
+        assert_eq!(conn.blocks_missing_timestamps(chain, 10)?, [5]);
+        assert_eq!(
+            conn.contract_events(chain)?[0].timestamp,
+            Some(1_700_000_042)
+        );
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(minted()[2..], [(4, Some(1_700_000_036)), (3, None)]);
+}
+
+#[test]
+fn activity() {
+    let db = Db::open_for_test().unwrap();
+    let chain = 31337;
+    let (alice, bob): (Address, Address) = (rand::random(), rand::random());
+    let events = [
+        (1, TokenEventKind::Spawned { to: alice }, 1),
+        (2, TokenEventKind::Spawned { to: alice }, 1),
+        (
+            1,
+            TokenEventKind::Relisted {
+                fee: Some(0x10.into()),
+            },
+            2,
+        ),
+        (
+            2,
+            TokenEventKind::Relisted {
+                fee: Some(0x20.into()),
+            },
+            2,
+        ),
+        (3, TokenEventKind::Spawned { to: bob }, 3),
+        (3, TokenEventKind::Bred { left: 1, right: 2 }, 3),
+        (
+            3,
+            TokenEventKind::Transfer {
+                from: bob,
+                to: alice,
+            },
+            4,
+        ),
+    ];
+    db.with_tx(|tx| {
+        tx.record_events(
+            chain,
+            events
+                .into_iter()
+                .enumerate()
+                .map(|(i, (token, kind, block))| {
+                    Event::Token(TokenEvent {
+                        token,
+                        kind,
+                        block,
+                        log_index: i as u64,
+                        batch_index: 0,
+                    })
+                })
+                .collect::<Vec<_>>()
+                .iter(),
+        )
+    })
+    .unwrap();
+
+    db.with_conn(|conn| {
+        let (feed, next) = conn.activity(chain, None, 100)?;
+        assert!(next.is_none());
+        let feed = feed
+            .into_iter()
+            .map(|event| (event.id.token_id, event.block))
+            .collect::<Vec<_>>();
+        // The breeding appears once, as the use of its left parent, and the newest event comes
+        // first.
+        assert_eq!(
+            feed,
+            [(3, 4), (1, 3), (3, 3), (2, 2), (1, 2), (2, 1), (1, 1)]
+        );
+
+        let mut pages = Vec::new();
+        let mut before = None;
+        loop {
+            let (page, next) = conn.activity(chain, before, 3)?;
+            pages.extend(
+                page.into_iter()
+                    .map(|event| (event.id.token_id, event.block)),
+            );
+            match next {
+                Some(next) => before = Some(next.to_string().parse().unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(pages, feed);
+
+        let recent = conn.activity_in_blocks(chain, 3..=4)?;
+        assert_eq!(recent.len(), 3);
+        assert!(matches!(recent[0].kind, EventKindForUi::Mint { to } if to == bob));
+        assert!(matches!(
+            recent[1].kind,
+            EventKindForUi::Breed { coparent, price, .. }
+                if coparent.token_id == 2 && price == 0x10.into()
+        ));
+        assert!(matches!(
+            recent[2].kind,
+            EventKindForUi::Transfer { from, to } if from == bob && to == alice
+        ));
+        assert!(conn.activity(chain + 1, None, 100)?.0.is_empty());
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn market_stats() {
+    let db = Db::open_for_test().unwrap();
+    let chain = 31337;
+    let (alice, bob, carol): (Address, Address, Address) =
+        (rand::random(), rand::random(), rand::random());
+    let tokens = [Some(0x10), Some(0x30), None, Some(0x20)]
+        .into_iter()
+        .zip(1..)
+        .map(|(fee, token_id)| {
+            let mut token = test_token();
+            token.meta.properties.self_id = TroutId {
+                chain_id: chain,
+                token_id,
+            };
+            token.fee = fee.map(Into::into);
+            token
+        })
+        .collect::<Vec<_>>();
+    db.with_conn(|conn| conn.insert_tokens(tokens.iter()))
+        .unwrap();
+
+    let bred = TokenEventKind::Bred { left: 1, right: 2 };
+    let listed = |fee: Option<u64>| TokenEventKind::Relisted {
+        fee: fee.map(Into::into),
+    };
+    let events = [
+        (1, TokenEventKind::Spawned { to: alice }, 1),
+        (2, TokenEventKind::Spawned { to: carol }, 1),
+        (1, listed(Some(0x10)), 2),
+        (2, listed(Some(0x20)), 2),
+        (3, TokenEventKind::Spawned { to: bob }, 3),
+        (3, bred.clone(), 3),
+        (
+            1,
+            TokenEventKind::Transfer {
+                from: alice,
+                to: bob,
+            },
+            4,
+        ),
+        (4, TokenEventKind::Spawned { to: bob }, 5),
+        (4, bred.clone(), 5),
+        (2, listed(None), 6),
+        (5, TokenEventKind::Spawned { to: alice }, 7),
+        (5, bred, 7),
+    ];
+    db.with_tx(|tx| {
+        let mut events = events
+            .into_iter()
+            .enumerate()
+            .map(|(i, (token, kind, block))| {
+                Event::Token(TokenEvent {
+                    token,
+                    kind,
+                    block,
+                    log_index: i as u64,
+                    batch_index: 0,
+                })
+            })
+            .collect::<Vec<_>>();
+        // The last breeding's block is not yet timestamped.
+        events.extend((1..=6).map(|number| {
+            Event::Block(BlockHeader {
+                number,
+                hash: rand::random(),
+                parent_hash: rand::random(),
+                timestamp: 1000 + number,
+            })
+        }));
+        tx.record_events(chain, events.iter())
+    })
+    .unwrap();
+
+    let stud = |token_id, earnings: u64, paid_breeds| StudEarnings {
+        id: TroutId {
+            chain_id: chain,
+            token_id,
+        },
+        earnings: earnings.into(),
+        paid_breeds,
+    };
+    db.with_conn(|conn| {
+        assert_eq!(
+            conn.market_stats(chain, None, 10)?,
+            MarketStats {
+                total_trout: 4,
+                listed_studs: 3,
+                floor_fee: Some(0x10.into()),
+                median_fee: Some(0x20.into()),
+                max_fee: Some(0x30.into()),
+                breeds: 3,
+                volume: 0x60.into(),
+                top_studs: vec![stud(2, 0x40, 2), stud(1, 0x20, 2)],
+            }
+        );
+
+        let recent = conn.market_stats(chain, Some(1005), 1)?;
+        assert_eq!(recent.breeds, 2);
+        assert_eq!(recent.volume, 0x30.into());
+        assert_eq!(recent.top_studs, [stud(2, 0x20, 1)]);
+        let latest = conn.market_stats(chain, Some(1006), 10)?;
+        assert_eq!(latest.breeds, 1);
+        assert_eq!(latest.volume, 0x10.into());
+
+        let terms = |token_id| {
+            conn.stud_terms(TroutId {
+                chain_id: chain,
+                token_id,
+            })
+        };
+        assert_eq!(terms(1)?, Some((tokens[0].owner, Some(0x10.into()))));
+        assert_eq!(terms(3)?, Some((tokens[2].owner, None)));
+        assert_eq!(terms(5)?, None);
+
+        let empty = conn.market_stats(chain + 1, None, 10)?;
+        assert_eq!(empty, MarketStats::default());
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn migrations() {
+    let db = Db::open_for_test().unwrap();
+    assert!(db.pending_migrations(None).unwrap().is_empty());
+    db.with_tx(|tx| tx.insert_tokens([test_token()].iter()))
+        .unwrap();
+
+    let reverts = db.pending_migrations(Some(0)).unwrap();
+    assert!(reverts
+        .iter()
+        .all(|s| s.direction == migrate::Direction::Down));
+    assert!(reverts.windows(2).all(|w| w[0].ord > w[1].ord));
+    assert!(db.pending_migrations(None).unwrap().is_empty());
+
+    let reverted = db.migrate(Some(1)).unwrap();
+    assert_eq!(reverted.len(), reverts.len() - 1);
+    let reverted = db.migrate(Some(0)).unwrap();
+    assert_eq!(reverted.len(), 1);
+    let applied = db.migrate(None).unwrap();
+    assert_eq!(applied.len(), reverts.len());
+    assert!(applied
+        .iter()
+        .all(|s| s.direction == migrate::Direction::Up));
+    db.with_tx(|tx| {
+        assert!(tx.token_ids(31337)?.is_empty());
+        tx.insert_tokens([test_token()].iter())
+    })
+    .unwrap();
+
+    // Migrations applied before they were recorded are adopted from the bare version number.
+    db.with_conn(|conn| conn.execute_batch("DROP TABLE schema_migrations"))
+        .unwrap();
+    assert!(db.migrate(None).unwrap().is_empty());
+    assert_eq!(db.pending_migrations(Some(0)).unwrap().len(), reverts.len());
+
+    assert!(matches!(
+        db.migrate(Some(reverts.len() + 1)),
+        Err(Error::UnknownVersion(_))
+    ));
+
+    db.with_conn(|conn| {
+        conn.execute_batch("UPDATE schema_migrations SET checksum = 'edited' WHERE ord = 0")
+    })
+    .unwrap();
+    assert!(matches!(
+        db.migrate(None),
+        Err(Error::MigrationChanged(name)) if name == reverts.last().unwrap().name
+    ));
+
+    let db = Db::open_for_test().unwrap();
+    db.with_conn(|conn| {
+        conn.execute_batch(
+            r#"
+            INSERT INTO schema_migrations (ord, name, checksum, applied_at)
+            VALUES (99, '99-future', '', 0)
+            "#,
+        )
+    })
+    .unwrap();
+    assert!(matches!(
+        db.pending_migrations(None),
+        Err(Error::SchemaAhead { latest: Some(name), .. }) if name == "99-future"
+    ));
+}
@@ -1,9 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use ethers::types::{Address, U256};
 use futures::StreamExt as _;
 use parking_lot::RwLock;
-use tokio::time::{sleep, timeout, Duration};
+use tokio::{
+    sync::broadcast,
+    time::{sleep, timeout, Duration},
+};
 use tracing::{debug, error, instrument, trace, warn};
 
 use crate::{
@@ -11,30 +14,37 @@ use crate::{
     ipfs::Client as IpfsClient,
     nftrout::{
         algo::{self, Ancestors},
-        Client as NFTroutClient, Event, PendingToken, TokenEvent, TokenEventKind, TokenId,
-        TroutToken,
+        ChainId, Client as NFTroutClient, Event, EventForUi, PendingToken, TokenEvent,
+        TokenEventKind, TokenId, TroutId, TroutMetadata, TroutToken,
     },
-    utils::retry,
+    utils::{retry, retry_if, retry_times},
 };
 
 const INDEX_BATCH_SIZE: usize = 200;
 const PIN_BATCH_SIZE: usize = 50;
 const IPFS_TIMEOUT: Duration = Duration::from_secs(60);
 const PINNING_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// The number of attempts to fetch a block's header before leaving it for the next round.
+const BLOCK_HEADER_RETRIES: u64 = 5;
 
-#[instrument(skip_all)]
-pub async fn run(nftrout: &NFTroutClient, ipfs_client: &IpfsClient, db: &Db) {
+#[instrument(skip_all, fields(chain = nftrout.chain_id()))]
+pub async fn run(
+    nftrout: &NFTroutClient,
+    ipfs_client: &IpfsClient,
+    db: &Db,
+    g: &RwLock<Ancestors>,
+    activity: &broadcast::Sender<EventForUi>,
+) {
     let chain = nftrout.chain_id();
-    let (tokens, needs_coi_analysis, events_start_block) = db
-        .with_conn(|conn| {
+    let (needs_coi_analysis, events_start_block) = db
+        .read(move |conn| {
             Ok((
-                conn.list_tokens_for_ui(None)?,
-                conn.needs_coi_analysis()?,
+                conn.needs_coi_analysis(chain)?,
                 conn.latest_processed_block(chain)?,
             ))
         })
+        .await
         .unwrap();
-    let g = RwLock::new(algo::make_graph(tokens.into_iter()));
 
     // Quickly index new and changed token metadata
     let start_block = retry(|| nftrout.latest_block()).await;
@@ -42,43 +52,35 @@ pub async fn run(nftrout: &NFTroutClient, ipfs_client: &IpfsClient, db: &Db) {
         let past_nftrout = nftrout.at_block(start_block);
         debug!("starting initial index from {start_block}");
         index_ownership_and_fees(&past_nftrout, db, None).await;
-        index_new_tokens(&past_nftrout, ipfs_client, db, &g, None).await;
-        index_new_versions(&past_nftrout, ipfs_client, db, &g, None).await;
+        index_new_tokens(&past_nftrout, ipfs_client, db, g, None).await;
+        index_new_versions(&past_nftrout, ipfs_client, db, g, None).await;
         debug!("finished initial index from {start_block}");
     }
 
     if !needs_coi_analysis.is_empty() {
-        let g = g.read();
         debug!("starting COI analysis");
-        let cois = needs_coi_analysis
-            .iter()
-            .copied()
-            .map(|token| (token, algo::inbreeding(&g, token)))
-            .collect::<Vec<_>>();
-        db.with_tx(|tx| tx.set_cois(cois.into_iter())).unwrap();
+        let cois = {
+            let mut g = g.write();
+            needs_coi_analysis
+                .iter()
+                .copied()
+                .map(|token| (token, g.inbreeding(token)))
+                .collect::<Vec<_>>()
+        };
+        db.write(move |tx| tx.set_cois(cois.into_iter()))
+            .await
+            .unwrap();
         debug!("completed COI analysis");
     }
 
-    let pin_fut = async {
-        loop {
-            debug!("pinning unpinned CIDs");
-            if timeout(PINNING_TIMEOUT, pin_cids(ipfs_client, db, None))
-                .await
-                .is_err()
-            {
-                warn!("pinning timed out");
-            }
-            sleep(Duration::from_secs(60)).await;
-        }
-    };
-
     let reindex_fut = async {
         loop {
             debug!("batch re-indexing tokens");
-            let new_fut = index_new_tokens(nftrout, ipfs_client, db, &g, None);
-            let skipped_fut = index_skipped_tokens(nftrout, ipfs_client, db, &g, None);
-            let pending_fut = index_new_versions(nftrout, ipfs_client, db, &g, None);
-            tokio::join!(new_fut, skipped_fut, pending_fut);
+            let new_fut = index_new_tokens(nftrout, ipfs_client, db, g, None);
+            let skipped_fut = index_skipped_tokens(nftrout, ipfs_client, db, g, None);
+            let pending_fut = index_new_versions(nftrout, ipfs_client, db, g, None);
+            let history_fut = index_generation_history(nftrout, ipfs_client, db, None);
+            tokio::join!(new_fut, skipped_fut, pending_fut, history_fut);
             debug!("finished batch re-indexing");
             sleep(Duration::from_secs(60)).await;
         }
@@ -87,28 +89,93 @@ pub async fn run(nftrout: &NFTroutClient, ipfs_client: &IpfsClient, db: &Db) {
     let events_fut = nftrout
         .events(events_start_block, Some(start_block))
         .buffered(100)
-        .chunks(1000)
+        .ready_chunks(1000)
         .for_each(|batch| async move {
-            db.with_tx(|tx| tx.record_events(chain, batch.iter().flatten()))
+            db.write(move |tx| tx.record_events(chain, batch.iter().flatten()))
+                .await
                 .unwrap()
         });
 
     // Start watching blocks for real-time updates
-    debug!("watching events stream");
-    let realtime_fut = nftrout
-        .events(start_block + 1, None)
-        .buffered(25)
-        .chunks(1) // set higher for greater throughput, but higher latency
-        .for_each(|batch| async move {
-            integrate_token_events(nftrout, db, &batch).await;
-            db.with_tx(|tx| tx.record_events(nftrout.chain_id(), batch.iter().flatten()))
-                .unwrap();
-        });
+    let realtime_fut = async {
+        let mut next_block = start_block + 1;
+        loop {
+            debug!(block = next_block, "watching events stream");
+            // set the chunk size higher for greater throughput, but higher latency
+            let mut batches =
+                std::pin::pin!(nftrout.events(next_block, None).buffered(25).chunks(1));
+            while let Some(batch) = batches.next().await {
+                if let Some(fork_block) = find_fork_block(nftrout, db, &batch).await {
+                    roll_back(nftrout, db, fork_block).await;
+                    next_block = fork_block + 1;
+                    break;
+                }
+                integrate_token_events(nftrout, db, &batch).await;
+                let batch = db
+                    .write(move |tx| {
+                        tx.record_events(chain, batch.iter().flatten())?;
+                        Ok(batch)
+                    })
+                    .await
+                    .unwrap();
+                publish_activity(db, chain, &batch, activity).await;
+            }
+        }
+    };
+
+    let timestamps_fut = async {
+        loop {
+            index_block_timestamps(nftrout, db, None).await;
+            sleep(Duration::from_secs(60)).await;
+        }
+    };
+
+    let earnings_fut = async {
+        loop {
+            reconcile_earnings(nftrout, db, None).await;
+            sleep(Duration::from_secs(10 * 60)).await;
+        }
+    };
 
-    tokio::join!(realtime_fut, events_fut, pin_fut, reindex_fut);
+    tokio::join!(
+        realtime_fut,
+        events_fut,
+        reindex_fut,
+        timestamps_fut,
+        earnings_fut
+    );
     unreachable!("contract event stream broke");
 }
 
+/// Builds the pedigree of the chain's trout from those already indexed.
+pub async fn load_ancestors(chain: ChainId, db: &Db) -> Ancestors {
+    let tokens = db
+        .read(move |conn| conn.list_tokens_for_ui(chain))
+        .await
+        .unwrap();
+    algo::make_graph(tokens.into_iter().map(|token| {
+        let id = TroutId {
+            chain_id: chain,
+            token_id: token.id,
+        };
+        (id, token.parents)
+    }))
+}
+
+/// Periodically pins the CIDs of all indexed tokens, regardless of chain.
+pub async fn pin(ipfs_client: &IpfsClient, db: &Db) {
+    loop {
+        debug!("pinning unpinned CIDs");
+        if timeout(PINNING_TIMEOUT, pin_cids(ipfs_client, db, None))
+            .await
+            .is_err()
+        {
+            warn!("pinning timed out");
+        }
+        sleep(Duration::from_secs(60)).await;
+    }
+}
+
 #[instrument(skip_all)]
 async fn index_ownership_and_fees(nftrout: &NFTroutClient, db: &Db, concurrency: Option<usize>) {
     let chain_id = nftrout.chain_id();
@@ -125,13 +192,14 @@ async fn index_ownership_and_fees(nftrout: &NFTroutClient, db: &Db, concurrency:
         trace!("fetching batch owners");
         let owners = retry(|| nftrout.owners(batch.clone())).await;
         trace!("fetching batch fees");
-        let fees = batch.clone().map(|i| studs.get(&i));
+        let fees: Vec<_> = batch.clone().map(|i| studs.get(&i).copied()).collect();
         trace!("writing batch updates");
-        db.with_tx(|tx| {
-            tx.update_fees(chain_id, batch.clone().zip(fees))?;
-            tx.update_owners(chain_id, batch.zip(owners.iter().as_ref()))?;
+        db.write(move |tx| {
+            tx.update_fees(chain_id, batch.clone().zip(fees.iter().map(Option::as_ref)))?;
+            tx.update_owners(chain_id, batch.zip(owners.iter()))?;
             Ok(())
         })
+        .await
         .unwrap();
     }
 }
@@ -143,60 +211,257 @@ async fn integrate_token_events<const N: usize>(
     batch: &[smallvec::SmallVec<[Event; N]>],
 ) {
     let chain_id = nftrout.chain_id();
-    let mut ownership_changes: HashMap<TokenId, &Address> = HashMap::new();
-    let mut pending_tokens: HashMap<TokenId, PendingToken> = HashMap::new();
-    let mut fee_changes: HashMap<TokenId, Option<&U256>> = HashMap::new();
+    let mut ownership_changes: HashMap<TokenId, Address> = HashMap::new();
+    let mut pending_owners: HashMap<TokenId, Address> = HashMap::new();
+    let mut fee_changes: HashMap<TokenId, Option<U256>> = HashMap::new();
     for event in batch.iter().flatten() {
         let TokenEvent {
             token: id, kind, ..
         } = match event {
             Event::Token(event) => event,
-            Event::ProcessedBlock(_) => continue,
+            Event::Block(_) | Event::ProcessedBlock(_) | Event::Contract(_) => continue,
         };
         let id = *id;
         match kind {
             TokenEventKind::Relisted { fee } => {
-                fee_changes.insert(id, fee.as_ref());
+                fee_changes.insert(id, *fee);
                 debug!(id = id, "listed token")
             }
             TokenEventKind::Spawned { to } => {
-                let pending = crate::nftrout::PendingToken { id, owner: to };
-                pending_tokens.insert(id, pending);
+                pending_owners.insert(id, *to);
                 debug!(id = id, to = %to, "created token");
             }
             TokenEventKind::Transfer { from, to } => {
                 ownership_changes
                     .entry(id)
-                    .and_modify(|v| debug_assert_eq!(*v, from))
-                    .insert_entry(to);
+                    .and_modify(|v| debug_assert_eq!(v, from))
+                    .insert_entry(*to);
                 debug!(id = id, to = %to, "transferred token")
             }
+            TokenEventKind::Bred { left, right } => {
+                debug!(id = id, left = left, right = right, "bred token")
+            }
+            TokenEventKind::Incubated => debug!(id = id, "incubated token"),
         }
     }
-    if fee_changes.is_empty() && ownership_changes.is_empty() && pending_tokens.is_empty() {
+    if fee_changes.is_empty() && ownership_changes.is_empty() && pending_owners.is_empty() {
         return;
     }
-    db.with_tx(|tx| {
+    db.write(move |tx| {
         if !fee_changes.is_empty() {
-            tx.update_fees(chain_id, fee_changes.into_iter()).unwrap();
+            let fees = fee_changes.iter().map(|(id, fee)| (*id, fee.as_ref()));
+            tx.update_fees(chain_id, fees).unwrap();
         }
         if !ownership_changes.is_empty() {
-            tx.update_owners(chain_id, ownership_changes.into_iter())
-                .unwrap();
+            let owners = ownership_changes.iter().map(|(id, owner)| (*id, owner));
+            tx.update_owners(chain_id, owners).unwrap();
+        }
+        if !pending_owners.is_empty() {
+            debug_assert!(pending_owners.values().all(|owner| !owner.is_zero()));
+            let pending = pending_owners
+                .iter()
+                .map(|(id, owner)| (*id, PendingToken { id: *id, owner }));
+            tx.insert_pending_tokens(chain_id, pending).unwrap()
         }
-        if !pending_tokens.is_empty() {
-            debug_assert!(pending_tokens.values().all(|t| !t.owner.is_zero()));
-            tx.insert_pending_tokens(chain_id, pending_tokens.into_iter())
+        Ok(())
+    })
+    .await
+    .unwrap();
+}
+
+/// Returns the latest block shared by the canonical chain and the recorded chain
+/// if any block in the batch does not extend the recorded chain.
+#[instrument(skip_all)]
+async fn find_fork_block<const N: usize>(
+    nftrout: &NFTroutClient,
+    db: &Db,
+    batch: &[smallvec::SmallVec<[Event; N]>],
+) -> Option<u64> {
+    let chain_id = nftrout.chain_id();
+    for event in batch.iter().flatten() {
+        let header = match event {
+            Event::Block(header) if header.number > 0 => header,
+            _ => continue,
+        };
+        let mut block = header.number - 1;
+        match db
+            .read(move |conn| conn.block_hash(chain_id, block))
+            .await
+            .unwrap()
+        {
+            Some(hash) if hash != header.parent_hash => {}
+            _ => continue,
+        }
+        warn!(block = header.number, "detected reorg");
+        while block > 0 {
+            let canonical = retry_if(|| nftrout.block_header(block), |header| header).await;
+            match db
+                .read(move |conn| conn.block_hash(chain_id, block))
+                .await
                 .unwrap()
+            {
+                Some(hash) if hash != canonical.hash => block -= 1,
+                // Either the hashes agree or the block is too old to have been tracked.
+                _ => break,
+            }
         }
+        return Some(block);
+    }
+    None
+}
+
+/// Discards events recorded after `fork_block` and restores the owners and fees
+/// of the affected tokens to their values as of `fork_block`.
+#[instrument(skip(nftrout, db))]
+async fn roll_back(nftrout: &NFTroutClient, db: &Db, fork_block: u64) {
+    let chain_id = nftrout.chain_id();
+    let changed = db
+        .read(move |conn| conn.tokens_changed_since(chain_id, fork_block))
+        .await
+        .unwrap();
+    let fork_nftrout = nftrout.at_block(fork_block);
+    let (owners, studs) = if changed.is_empty() {
+        Default::default()
+    } else {
+        trace!(count = changed.len(), "fetching fork block owners and fees");
+        (
+            retry(|| fork_nftrout.owners(changed.iter().copied())).await,
+            retry(|| fork_nftrout.studs()).await,
+        )
+    };
+    let (existing, unborn): (Vec<_>, Vec<_>) = changed
+        .into_iter()
+        .zip(owners)
+        .partition(|(_, owner)| !owner.is_zero());
+    db.write(move |tx| {
+        tx.roll_back_events(chain_id, fork_block)?;
+        tx.update_owners(chain_id, existing.iter().map(|(id, owner)| (*id, owner)))?;
+        tx.update_fees(
+            chain_id,
+            existing.iter().map(|(id, _)| (*id, studs.get(id))),
+        )?;
+        tx.remove_pending_tokens(chain_id, unborn.into_iter().map(|(id, _)| id))?;
         Ok(())
     })
+    .await
     .unwrap();
+    debug!(block = fork_block, "rolled back to fork block");
+}
+
+/// Sends the marketplace activity in the newly recorded batch to live subscribers.
+async fn publish_activity<const N: usize>(
+    db: &Db,
+    chain: ChainId,
+    batch: &[smallvec::SmallVec<[Event; N]>],
+    activity: &broadcast::Sender<EventForUi>,
+) {
+    if activity.receiver_count() == 0 {
+        return;
+    }
+    let blocks = batch.iter().flatten().filter_map(|event| match event {
+        Event::Token(TokenEvent { block, .. }) => Some(*block),
+        _ => None,
+    });
+    let (Some(first), Some(last)) = (blocks.clone().min(), blocks.max()) else {
+        return;
+    };
+    let events = db
+        .read(move |conn| conn.activity_in_blocks(chain, first..=last))
+        .await
+        .unwrap();
+    for event in events {
+        activity.send(event).ok();
+    }
+}
+
+/// Compares the stud fees accrued by each owner with their balance in the contract. Since
+/// withdrawals are not evented, only balances exceeding the accrued fees are reported.
+#[instrument(skip_all)]
+async fn reconcile_earnings(nftrout: &NFTroutClient, db: &Db, concurrency: Option<usize>) {
+    let chain_id = nftrout.chain_id();
+    let (block, accrued) = db
+        .read(move |conn| {
+            let block = conn.latest_processed_block(chain_id)?;
+            Ok((block, conn.accrued_earnings(chain_id, block)?))
+        })
+        .await
+        .unwrap();
+    if accrued.is_empty() {
+        return;
+    }
+    let past_nftrout = nftrout.at_block(block);
+    // The contract owner is also paid the matchmaking fees and mint rewards.
+    let contract_owner = retry(|| past_nftrout.contract_state()).await.owner;
+    let past_nftrout = &past_nftrout;
+    let reconciliations = futures::stream::iter(accrued)
+        .map(|(owner, accrued)| async move {
+            let withdrawable = retry(|| past_nftrout.earnings(owner)).await;
+            (owner, accrued, withdrawable)
+        })
+        .buffer_unordered(concurrency.unwrap_or(INDEX_BATCH_SIZE))
+        .collect::<Vec<_>>()
+        .await;
+    for (owner, accrued, withdrawable) in reconciliations.iter() {
+        if withdrawable > accrued && *owner != contract_owner {
+            warn!(%owner, %accrued, %withdrawable, "contract earnings exceed indexed stud fees");
+        }
+    }
+    db.write(move |tx| {
+        tx.record_earnings_reconciliations(chain_id, block, reconciliations.into_iter())
+    })
+    .await
+    .unwrap();
+    debug!(block, "reconciled earnings");
+}
+
+/// Fetches the headers of blocks whose events were recorded without them. Blocks whose headers
+/// stay unavailable are skipped until the next call.
+#[instrument(skip_all)]
+async fn index_block_timestamps(nftrout: &NFTroutClient, db: &Db, concurrency: Option<usize>) {
+    let chain_id = nftrout.chain_id();
+    let concurrency = concurrency.unwrap_or(INDEX_BATCH_SIZE);
+    let mut unavailable = HashSet::new();
+    loop {
+        let limit = unavailable.len() + concurrency;
+        let blocks: Vec<_> = db
+            .read(move |conn| conn.blocks_missing_timestamps(chain_id, limit))
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|block| !unavailable.contains(block))
+            .collect();
+        if blocks.is_empty() {
+            break;
+        }
+        trace!(count = blocks.len(), "fetching block timestamps");
+        let headers = futures::stream::iter(blocks)
+            .map(|block| async move {
+                let header = retry_times(
+                    move || nftrout.block_header(block),
+                    |header| header,
+                    Some(BLOCK_HEADER_RETRIES),
+                );
+                (block, header.await)
+            })
+            .buffer_unordered(concurrency)
+            .filter_map(|(block, header)| {
+                if header.is_err() {
+                    warn!(block, "block header is unavailable");
+                    unavailable.insert(block);
+                }
+                futures::future::ready(header.ok().map(Event::Block))
+            })
+            .collect::<Vec<_>>()
+            .await;
+        db.write(move |tx| tx.record_events(chain_id, headers.iter()))
+            .await
+            .unwrap();
+    }
 }
 
 #[instrument(skip_all)]
 async fn pin_cids(ipfs_client: &IpfsClient, db: &Db, concurrency: Option<usize>) {
-    let cids_to_pin = db.with_conn(|conn| conn.unpinned_cids()).unwrap();
+    let cids_to_pin = db.read(|conn| conn.unpinned_cids()).await.unwrap();
     debug!(count = cids_to_pin.len(), "pinning cids");
     let concurrency = concurrency.unwrap_or(PIN_BATCH_SIZE);
     futures::stream::iter(cids_to_pin)
@@ -212,11 +477,12 @@ async fn pin_cids(ipfs_client: &IpfsClient, db: &Db, concurrency: Option<usize>)
         .ready_chunks(concurrency)
         .for_each(|mut cids| async move {
             let pp = cids.iter_mut().partition_in_place(|c| c.is_ok());
-            db.with_conn(|conn| {
-                conn.mark_pinned(cids[..pp].iter().map(|c| c.as_ref().unwrap()))?;
-                conn.mark_pin_failed(cids[pp..].iter().map(|c| c.as_ref().unwrap_err()))?;
+            db.write(move |tx| {
+                tx.mark_pinned(cids[..pp].iter().map(|c| c.as_ref().unwrap()))?;
+                tx.mark_pin_failed(cids[pp..].iter().map(|c| c.as_ref().unwrap_err()))?;
                 Ok(())
             })
+            .await
             .unwrap()
         })
         .await;
@@ -231,8 +497,10 @@ async fn index_new_tokens(
     g: &RwLock<Ancestors>,
     concurrency: Option<usize>,
 ) {
+    let chain_id = nftrout.chain_id();
     let latest_known_token_id = db
-        .with_conn(|conn| conn.latest_known_token_id(nftrout.chain_id()))
+        .read(move |conn| conn.latest_known_token_id(chain_id))
+        .await
         .unwrap()
         .unwrap_or_default();
     trace!("fetching total supply");
@@ -258,7 +526,8 @@ async fn index_new_versions(
 ) {
     let chain_id = nftrout.chain_id();
     let ids_to_reindex = db
-        .with_conn(|conn| conn.outdated_token_ids(chain_id))
+        .read(move |conn| conn.outdated_token_ids(chain_id))
+        .await
         .unwrap();
     index_tokens(
         ids_to_reindex.into_iter(),
@@ -271,6 +540,51 @@ async fn index_new_versions(
     .await
 }
 
+/// Recovers the earlier generations of tokens from the history in their latest metadata.
+#[instrument(skip_all)]
+async fn index_generation_history(
+    nftrout: &NFTroutClient,
+    ipfs_client: &IpfsClient,
+    db: &Db,
+    concurrency: Option<usize>,
+) {
+    let chain_id = nftrout.chain_id();
+    let incomplete = db
+        .read(move |conn| conn.tokens_missing_generations(chain_id))
+        .await
+        .unwrap();
+    if incomplete.is_empty() {
+        return;
+    }
+    debug!(count = incomplete.len(), "fetching generation history");
+    let histories = futures::stream::iter(incomplete)
+        .map(|(id, cid)| async move {
+            match timeout(IPFS_TIMEOUT, ipfs_client.dag_get::<TroutMetadata>(&cid)).await {
+                Err(_) => {
+                    warn!("failed to get {cid}: timed out");
+                    None
+                }
+                Ok(Err(e)) => {
+                    error!("failed to get {cid}: {e}");
+                    None
+                }
+                Ok(Ok(meta)) => Some((id, meta.properties.generations)),
+            }
+        })
+        .buffer_unordered(concurrency.unwrap_or(INDEX_BATCH_SIZE))
+        .filter_map(|h| async { h })
+        .collect::<Vec<_>>()
+        .await;
+    db.write(move |tx| {
+        for (id, generations) in histories.iter() {
+            tx.insert_generations(*id, generations)?;
+        }
+        Ok(())
+    })
+    .await
+    .unwrap();
+}
+
 #[instrument(skip_all)]
 async fn index_skipped_tokens(
     nftrout: &NFTroutClient,
@@ -279,9 +593,8 @@ async fn index_skipped_tokens(
     g: &RwLock<Ancestors>,
     concurrency: Option<usize>,
 ) {
-    let known_token_ids = db
-        .with_conn(|conn| conn.token_ids(nftrout.chain_id()))
-        .unwrap();
+    let chain_id = nftrout.chain_id();
+    let known_token_ids = db.read(move |conn| conn.token_ids(chain_id)).await.unwrap();
     let latest_known_token_id = match known_token_ids.last() {
         Some(id) => *id,
         None => return,
@@ -391,7 +704,7 @@ async fn index_tokens(
                         );
                         continue;
                     }
-                    g.add_edge(n, l, ());
+                    g.add_edge(n, l);
                 }
                 if let Some(r) = token.meta.properties.right {
                     if !g.contains_node(r) {
@@ -402,15 +715,16 @@ async fn index_tokens(
                         );
                         continue;
                     }
-                    g.add_edge(n, r, ());
+                    g.add_edge(n, r);
                 }
             }
             for token in tokens.iter_mut() {
-                token.coi = algo::inbreeding(&g, token.meta.properties.self_id);
+                token.coi = g.inbreeding(token.meta.properties.self_id);
             }
         }
 
-        db.with_conn(|conn| conn.insert_tokens(tokens.iter()))
+        db.write(move |tx| tx.insert_tokens(tokens.iter()))
+            .await
             .unwrap();
     }
 }
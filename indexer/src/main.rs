@@ -13,10 +13,16 @@ mod ipfs;
 mod nftrout;
 mod utils;
 
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::RwLock;
 use tracing::info;
 
+/// The number of unread activity events buffered for each live subscriber.
+const ACTIVITY_BUFFER_SIZE: usize = 1024;
+
 #[tokio::main]
-async fn main() {
+async fn main() -> anyhow::Result<()> {
     let subscriber = tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .with_target(true);
@@ -26,8 +32,26 @@ async fn main() {
         subscriber.without_time().init();
     }
 
+    // `nftrout-indexer [migrate [--dry-run] [--to VERSION]] [CONFIG]`
+    let mut args = std::env::args().skip(1).peekable();
+    let migrate = args.next_if_eq("migrate").is_some();
+    let (mut dry_run, mut target) = (false, None);
+    while let Some(flag) = args.next_if(|arg| migrate && arg.starts_with("--")) {
+        match flag.as_str() {
+            "--dry-run" => dry_run = true,
+            "--to" => {
+                target = Some(
+                    args.next()
+                        .and_then(|version| version.parse::<usize>().ok())
+                        .expect("--to takes the number of migrations to leave applied"),
+                )
+            }
+            _ => panic!("unknown flag: {flag}"),
+        }
+    }
+
     let cfg = config::Config::builder().add_source(config::Environment::with_prefix("NFT"));
-    let cfg: conf::Config = match std::env::args().nth(1) {
+    let cfg: conf::Config = match args.next() {
         Some(conf_file) => cfg.add_source(config::File::with_name(&conf_file)),
         None => cfg,
     }
@@ -38,20 +62,69 @@ async fn main() {
 
     info!(config = ?cfg, "loaded config");
 
-    let db = db::Db::open(cfg.db_path).unwrap();
+    // The database blocks, so it is opened and migrated off the async runtime.
+    if migrate {
+        let migrated: anyhow::Result<()> = tokio::task::spawn_blocking(move || {
+            let db = db::Db::open_unmigrated(cfg.db_path)?;
+            if !dry_run {
+                let steps = db.migrate(target)?;
+                info!(steps = steps.len(), "migrated database");
+                return Ok(());
+            }
+            for step in db.pending_migrations(target)? {
+                println!("-- {step}\n{}", step.sql.trim_end());
+            }
+            Ok(())
+        })
+        .await?;
+        return migrated;
+    }
+
+    let db_path = cfg.db_path.clone();
+    let db = tokio::task::spawn_blocking(move || db::Db::open(db_path)).await??;
+    let starts: Vec<_> = cfg
+        .chains
+        .iter()
+        .map(|chain| (chain.chain_id, chain.start_block))
+        .collect();
+    db.write(move |tx| {
+        for (chain, start_block) in starts {
+            tx.init_progress(chain, start_block)?;
+        }
+        Ok(())
+    })
+    .await
+    .unwrap();
     let ipfs = ipfs::Client::new(cfg.ipfs_endpoint);
-    let nftrout = match cfg.chain {
-        conf::Chain::SapphireMainnet => nftrout::Client::sapphire_mainnet(),
-        conf::Chain::SapphireTestnet => nftrout::Client::sapphire_testnet(),
-        conf::Chain::Local => nftrout::Client::local(),
-    };
+    let mut chains = HashMap::new();
+    for chain in cfg.chains.iter() {
+        let state = api::ChainState {
+            nftrout: nftrout::Client::new(chain),
+            ancestors: Arc::new(RwLock::new(
+                indexer::load_ancestors(chain.chain_id, &db).await,
+            )),
+            activity: tokio::sync::broadcast::channel(ACTIVITY_BUFFER_SIZE).0,
+            contract_state: Default::default(),
+        };
+        chains.insert(chain.chain_id, state);
+    }
 
     let indexer_db = db.clone();
     let indexer_ipfs = ipfs.clone();
-    let indexer_nftrout = nftrout.clone();
-    let indexer_task = indexer::run(&indexer_nftrout, &indexer_ipfs, &indexer_db);
+    let indexer_chains = chains.clone();
+    let indexer_tasks = futures::future::join_all(indexer_chains.values().map(|chain| {
+        indexer::run(
+            &chain.nftrout,
+            &indexer_ipfs,
+            &indexer_db,
+            &chain.ancestors,
+            &chain.activity,
+        )
+    }));
+    let pin_task = indexer::pin(&indexer_ipfs, &indexer_db);
 
-    let api_task = api::serve(db, ipfs, nftrout, cfg.api_port);
+    let api_task = api::serve(db, ipfs, chains, cfg.api_port);
 
-    tokio::join!(indexer_task, api_task);
+    tokio::join!(indexer_tasks, pin_task, api_task);
+    Ok(())
 }
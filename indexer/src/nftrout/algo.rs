@@ -1,125 +1,562 @@
-use std::collections::HashSet;
+use std::collections::{hash_map::Entry, BinaryHeap, HashMap};
 
-use petgraph::{graphmap::DiGraphMap, prelude::*, visit::Walker as _};
+use petgraph::{graphmap::DiGraphMap, prelude::*};
+use serde::{Deserialize, Serialize};
 
-use super::{TokenForUi, TroutId};
+use super::TroutId;
 
-pub type Ancestors = DiGraphMap<TroutId, ()>;
+/// The pedigree of known trout, along with the kinship coefficients computed from it so far.
+///
+/// Kinship is computed using the recursive definition of coancestry rather than by enumerating
+/// paths, which takes time roughly linear in the number of ancestors being compared.
+#[derive(Clone, Debug, Default)]
+pub struct Ancestors {
+    graph: DiGraphMap<TroutId, ()>,
+    memo: Memo,
+}
 
-pub fn make_graph(tokens: impl Iterator<Item = TokenForUi>) -> Ancestors {
-    let mut g = DiGraphMap::new();
-    for token in tokens {
-        let this = TroutId {
-            chain_id: 23294,
-            token_id: token.id,
-        };
+#[derive(Clone, Debug, Default)]
+struct Memo {
+    /// The length of the longest path from each trout to a founder.
+    depths: HashMap<TroutId, u32>,
+    /// Kinship coefficients keyed by ordered pairs of trout.
+    kinships: HashMap<(TroutId, TroutId), f64>,
+}
+
+/// Builds the pedigree of the trout, each given along with its parents if it has any.
+pub fn make_graph(trout: impl Iterator<Item = (TroutId, Option<(TroutId, TroutId)>)>) -> Ancestors {
+    let mut g = Ancestors::default();
+    for (this, parents) in trout {
         g.add_node(this);
-        if let Some((left, right)) = token.parents {
-            g.add_edge(this, left, ());
-            g.add_edge(this, right, ());
+        if let Some((left, right)) = parents {
+            g.add_edge(this, left);
+            g.add_edge(this, right);
         }
     }
     g
 }
 
-fn get_common_ancestors(
-    graph: &DiGraphMap<TroutId, ()>,
-    parent1: TroutId,
-    parent2: TroutId,
-) -> HashSet<TroutId> {
-    // Create BFS iterators for each parent.
-    let bfs_parent1 = Bfs::new(graph, parent1);
-    let bfs_parent2 = Bfs::new(graph, parent2);
-
-    // Collect all ancestors for each parent in HashSets.
-    let ancestors_parent1: HashSet<_> = bfs_parent1.iter(graph).collect();
-    let ancestors_parent2: HashSet<_> = bfs_parent2.iter(graph).collect();
-
-    // Get the intersection of both HashSets to find common ancestors.
-    ancestors_parent1
-        .intersection(&ancestors_parent2)
-        .cloned()
-        .collect()
+impl Ancestors {
+    pub fn add_node(&mut self, trout: TroutId) -> TroutId {
+        self.graph.add_node(trout)
+    }
+
+    pub fn contains_node(&self, trout: TroutId) -> bool {
+        self.graph.contains_node(trout)
+    }
+
+    pub fn add_edge(&mut self, child: TroutId, parent: TroutId) {
+        let is_new = self.graph.add_edge(child, parent, ()).is_none();
+        // A trout only has a cached depth if something was computed from its ancestry,
+        // which has just changed. New trout have not, so the cache is usually kept.
+        if is_new && self.memo.depths.contains_key(&child) {
+            self.memo = Memo::default();
+        }
+    }
+
+    /// Returns a view that computes from the pedigree and its cache without modifying either,
+    /// so that it can be used from behind a shared reference.
+    pub fn view(&self) -> View<'_> {
+        View {
+            ancestors: self,
+            memo: Memo::default(),
+        }
+    }
+
+    fn computation(&mut self) -> Computation<'_> {
+        Computation {
+            graph: &self.graph,
+            known: None,
+            memo: &mut self.memo,
+        }
+    }
+
+    /// Computes Wright's coefficient of inbreeding of a trout, which is the kinship of its parents.
+    pub fn inbreeding(&mut self, target: TroutId) -> f64 {
+        self.computation().inbreeding(target)
+    }
 }
 
-fn find_path(graph: &DiGraphMap<TroutId, ()>, start: TroutId, end: TroutId) -> Vec<Vec<TroutId>> {
-    let mut paths: Vec<Vec<TroutId>> = Vec::new();
-    let mut visited: HashSet<TroutId> = HashSet::new();
+/// A read-only view of [`Ancestors`], which keeps what it computes beyond the pedigree's own
+/// cache to itself.
+pub struct View<'a> {
+    ancestors: &'a Ancestors,
+    memo: Memo,
+}
 
-    // Recursive DFS function
-    fn dfs(
-        graph: &DiGraphMap<TroutId, ()>,
-        start: TroutId,
-        end: TroutId,
-        path: &mut Vec<TroutId>,
-        paths: &mut Vec<Vec<TroutId>>,
-        visited: &mut HashSet<TroutId>,
-    ) {
-        path.push(start);
-        visited.insert(start);
+impl View<'_> {
+    fn computation(&mut self) -> Computation<'_> {
+        Computation {
+            graph: &self.ancestors.graph,
+            known: Some(&self.ancestors.memo),
+            memo: &mut self.memo,
+        }
+    }
 
-        if start == end {
-            paths.push(path.clone());
-        } else {
-            for neighbor in graph.neighbors_directed(start, Outgoing) {
-                if !visited.contains(&neighbor) {
-                    dfs(graph, neighbor, end, path, paths, visited);
+    pub fn contains_node(&self, trout: TroutId) -> bool {
+        self.ancestors.contains_node(trout)
+    }
+
+    /// Returns the kinship (coancestry) coefficient of two trout, which is also the
+    /// coefficient of inbreeding that an offspring of the two would have.
+    pub fn kinship(&mut self, a: TroutId, b: TroutId) -> f64 {
+        self.computation().kinship(a, b)
+    }
+
+    /// Returns the kinship of two trout along with the share of it due to each common ancestor.
+    pub fn coancestry(&mut self, a: TroutId, b: TroutId) -> Coancestry {
+        self.computation().coancestry(a, b)
+    }
+}
+
+/// Computes from a pedigree, memoizing in `memo` whatever is not already `known`.
+struct Computation<'a> {
+    graph: &'a DiGraphMap<TroutId, ()>,
+    known: Option<&'a Memo>,
+    memo: &'a mut Memo,
+}
+
+impl Computation<'_> {
+    fn cached_depth(&self, trout: TroutId) -> Option<u32> {
+        let cached = self.memo.depths.get(&trout);
+        cached.or_else(|| self.known?.depths.get(&trout)).copied()
+    }
+
+    fn cached_kinship(&self, x: TroutId, y: TroutId) -> Option<f64> {
+        let key = kinship_key(x, y);
+        let cached = self.memo.kinships.get(&key);
+        cached.or_else(|| self.known?.kinships.get(&key)).copied()
+    }
+
+    fn parents(&self, trout: TroutId) -> Option<(TroutId, TroutId)> {
+        if !self.graph.contains_node(trout) {
+            return None;
+        }
+        let mut parents = self.graph.neighbors_directed(trout, Outgoing);
+        let parents = (parents.next()?, parents.next()?);
+        debug_assert!(
+            self.graph.neighbors_directed(trout, Outgoing).count() == 2,
+            "3 parents?"
+        );
+        Some(parents)
+    }
+
+    fn depth(&mut self, trout: TroutId) -> u32 {
+        let mut stack = vec![trout];
+        while let Some(&t) = stack.last() {
+            if self.cached_depth(t).is_some() {
+                stack.pop();
+                continue;
+            }
+            let (left, right) = match self.parents(t) {
+                Some(parents) => parents,
+                None => {
+                    self.memo.depths.insert(t, 0);
+                    stack.pop();
+                    continue;
+                }
+            };
+            match (self.cached_depth(left), self.cached_depth(right)) {
+                (Some(l), Some(r)) => {
+                    let depth = 1 + l.max(r);
+                    self.memo.depths.insert(t, depth);
+                    stack.pop();
+                }
+                (l, r) => {
+                    if l.is_none() {
+                        stack.push(left);
+                    }
+                    if r.is_none() {
+                        stack.push(right);
+                    }
                 }
             }
         }
+        self.cached_depth(trout).unwrap()
+    }
 
-        path.pop();
-        visited.remove(&start);
+    fn kinship(&mut self, a: TroutId, b: TroutId) -> f64 {
+        let mut stack = vec![(a, b)];
+        while let Some(&(x, y)) = stack.last() {
+            if self.cached_kinship(x, y).is_some() {
+                stack.pop();
+                continue;
+            }
+            let (terms, weight) = self.kinship_terms(x, y);
+            let mut missing = terms
+                .iter()
+                .flatten()
+                .filter(|(x, y)| self.cached_kinship(*x, *y).is_none())
+                .peekable();
+            if missing.peek().is_some() {
+                let missing: Vec<_> = missing.copied().collect();
+                stack.extend(missing);
+                continue;
+            }
+            let sum: f64 = terms
+                .iter()
+                .flatten()
+                .map(|(x, y)| self.cached_kinship(*x, *y).unwrap())
+                .sum();
+            let kinship = match x == y {
+                // φ(x, x) = ½ (1 + F_x)
+                true => 0.5 * (1.0 + sum),
+                // φ(x, y) = ½ (φ(left_x, y) + φ(right_x, y))
+                false => weight * sum,
+            };
+            self.memo.kinships.insert(kinship_key(x, y), kinship);
+            stack.pop();
+        }
+        self.cached_kinship(a, b).unwrap()
     }
 
-    // Actual DFS call
-    let mut path: Vec<TroutId> = Vec::new();
-    dfs(graph, start, end, &mut path, &mut paths, &mut visited);
+    /// Returns the pairs whose kinship, summed and multiplied by the returned weight,
+    /// gives the kinship of `x` and `y` (or, when they are equal, the inbreeding of `x`).
+    fn kinship_terms(&mut self, x: TroutId, y: TroutId) -> ([Option<(TroutId, TroutId)>; 2], f64) {
+        if x == y {
+            return ([self.parents(x), None], 1.0);
+        }
+        // An ancestor is never deeper than its descendants, so expanding the deeper of the two
+        // never expands past a trout that is an ancestor of the other.
+        let (deeper, other) = match self.depth(x) >= self.depth(y) {
+            true => (x, y),
+            false => (y, x),
+        };
+        match self.parents(deeper) {
+            Some((left, right)) => ([Some((left, other)), Some((right, other))], 0.5),
+            // Distinct founders are unrelated.
+            None => ([None, None], 0.0),
+        }
+    }
+
+    fn coancestry(&mut self, a: TroutId, b: TroutId) -> Coancestry {
+        let coi = self.kinship(a, b);
+
+        // Distribute the kinship of (a, b) over the pairs reached by recursively expanding it.
+        // Each expansion strictly decreases the pair's total depth, so visiting pairs deepest
+        // first ensures that a pair's weight is complete before it is itself expanded. Pairs
+        // that reach the same trout on both sides stop there, at a common ancestor.
+        let mut weights = HashMap::from([(kinship_key(a, b), 1.0)]);
+        let mut queue = BinaryHeap::from([(self.depth(a) + self.depth(b), a, b)]);
+        let mut contributions: HashMap<TroutId, f64> = HashMap::new();
+        while let Some((_, x, y)) = queue.pop() {
+            let weight = weights.remove(&kinship_key(x, y)).unwrap();
+            if x == y {
+                *contributions.entry(x).or_default() += weight * self.kinship(x, x);
+                continue;
+            }
+            let (terms, term_weight) = self.kinship_terms(x, y);
+            for (px, py) in terms.into_iter().flatten() {
+                match weights.entry(kinship_key(px, py)) {
+                    Entry::Occupied(mut e) => *e.get_mut() += weight * term_weight,
+                    Entry::Vacant(e) => {
+                        e.insert(weight * term_weight);
+                        queue.push((self.depth(px) + self.depth(py), px, py));
+                    }
+                }
+            }
+        }
+
+        let mut ancestors: Vec<_> = contributions
+            .into_iter()
+            .map(|(id, contribution)| AncestorContribution { id, contribution })
+            .collect();
+        ancestors.sort_by(|a, b| b.contribution.total_cmp(&a.contribution));
+        Coancestry { coi, ancestors }
+    }
+
+    fn inbreeding(&mut self, target: TroutId) -> f64 {
+        match self.parents(target) {
+            Some((left, right)) => self.kinship(left, right),
+            None => 0.0,
+        }
+    }
+}
+
+/// The predicted coefficient of inbreeding of an offspring of two trout.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Coancestry {
+    pub coi: f64,
+    /// The common ancestors, most contributing first.
+    pub ancestors: Vec<AncestorContribution>,
+}
 
-    paths
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AncestorContribution {
+    pub id: TroutId,
+    /// The share of the COI due to paths meeting at this ancestor.
+    pub contribution: f64,
 }
 
-pub fn inbreeding(graph: &DiGraphMap<TroutId, ()>, target: TroutId) -> f64 {
-    let mut parents = graph.neighbors_directed(target, Outgoing);
-    let parent1 = match parents.next() {
-        Some(p) => p,
-        None => return 0.0,
-    };
-    let parent2 = match parents.next() {
-        Some(p) => p,
-        None => return 0.0,
-    };
-    assert!(parents.next().is_none(), "3 parents?");
-
-    // 1: Identify common ancestors.
-    let common_ancestors = get_common_ancestors(graph, parent1, parent2);
-
-    // 2: Determine the inbreeding path.
-    let mut sum_inbreeding_paths = 0.0;
-
-    for &ancestor in &common_ancestors {
-        // 3a. get the paths to the common ancestor
-        let paths_parent1 = find_path(graph, parent1, ancestor);
-        let paths_parent2 = find_path(graph, parent2, ancestor);
-
-        // 3b. get the shortest path from all possible paths
-        let empty = vec![];
-        let shortest_parent1 = paths_parent1
-            .iter()
-            .min_by_key(|vec| vec.len())
-            .unwrap_or(&empty);
-        let shortest_parent2 = paths_parent2
-            .iter()
-            .min_by_key(|vec| vec.len())
-            .unwrap_or(&empty);
-
-        let (n_parent1, m_parent2) = (shortest_parent1.len() - 1, shortest_parent2.len() - 1);
-
-        // Step 4: calculate the inbreeding coefficient by summing up (1/2)^(n+m+1) for each path
-        let path_inbreeding_value = 0.5f64.powf((n_parent1 + m_parent2 + 1) as f64);
-
-        sum_inbreeding_paths += path_inbreeding_value;
-    }
-
-    sum_inbreeding_paths
+fn kinship_key(a: TroutId, b: TroutId) -> (TroutId, TroutId) {
+    (a.min(b), a.max(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use petgraph::visit::Walker as _;
+
+    use super::*;
+
+    fn trout(token_id: u32) -> TroutId {
+        TroutId {
+            chain_id: 31337,
+            token_id,
+        }
+    }
+
+    /// Makes a pedigree from `(child, left, right)` trios.
+    fn pedigree(trios: &[(u32, u32, u32)]) -> Ancestors {
+        let mut g = Ancestors::default();
+        for &(child, left, right) in trios {
+            g.add_edge(trout(child), trout(left));
+            g.add_edge(trout(child), trout(right));
+        }
+        g
+    }
+
+    /// Wright's path formula, F = Σ (½)^(n1 + n2 + 1) (1 + F_A), summed over every pair of paths
+    /// from the parents to a common ancestor A that meet only at A. Exponential, but obviously so.
+    fn wright_inbreeding(graph: &DiGraphMap<TroutId, ()>, target: TroutId) -> f64 {
+        let mut parents = graph.neighbors_directed(target, Outgoing);
+        match (parents.next(), parents.next()) {
+            (Some(p1), Some(p2)) => wright_contributions(graph, p1, p2).values().sum(),
+            _ => 0.0,
+        }
+    }
+
+    fn wright_contributions(
+        graph: &DiGraphMap<TroutId, ()>,
+        parent1: TroutId,
+        parent2: TroutId,
+    ) -> HashMap<TroutId, f64> {
+        let ancestors1: HashSet<_> = Bfs::new(graph, parent1).iter(graph).collect();
+        let ancestors2: HashSet<_> = Bfs::new(graph, parent2).iter(graph).collect();
+        let mut contributions = HashMap::new();
+        for &ancestor in ancestors1.intersection(&ancestors2) {
+            let ancestor_coi = wright_inbreeding(graph, ancestor);
+            let mut contribution = 0.0;
+            for path1 in find_paths(graph, parent1, ancestor) {
+                for path2 in find_paths(graph, parent2, ancestor) {
+                    let overlaps = path2[..path2.len() - 1].iter().any(|n| path1.contains(n));
+                    if !overlaps {
+                        let n = path1.len() - 1 + path2.len() - 1;
+                        contribution += 0.5f64.powi(n as i32 + 1) * (1.0 + ancestor_coi);
+                    }
+                }
+            }
+            if contribution > 0.0 {
+                contributions.insert(ancestor, contribution);
+            }
+        }
+        contributions
+    }
+
+    fn find_paths(
+        graph: &DiGraphMap<TroutId, ()>,
+        start: TroutId,
+        end: TroutId,
+    ) -> Vec<Vec<TroutId>> {
+        if start == end {
+            return vec![vec![end]];
+        }
+        graph
+            .neighbors_directed(start, Outgoing)
+            .flat_map(|parent| find_paths(graph, parent, end))
+            .map(|mut path| {
+                path.insert(0, start);
+                path
+            })
+            .collect()
+    }
+
+    fn assert_coi(trios: &[(u32, u32, u32)], target: u32, expected: f64) {
+        let mut g = pedigree(trios);
+        for coi in [
+            g.inbreeding(trout(target)),
+            wright_inbreeding(&g.graph, trout(target)),
+        ] {
+            assert!(
+                (coi - expected).abs() < 1e-12,
+                "expected COI {expected}, got {coi}"
+            );
+        }
+    }
+
+    #[test]
+    fn founder() {
+        let mut g = Ancestors::default();
+        g.add_node(trout(1));
+        assert_eq!(g.inbreeding(trout(1)), 0.0);
+        assert_eq!(g.view().kinship(trout(1), trout(1)), 0.5);
+    }
+
+    #[test]
+    fn unrelated_parents() {
+        assert_coi(&[(3, 1, 2), (6, 4, 5), (7, 3, 6)], 7, 0.0);
+    }
+
+    #[test]
+    fn full_siblings() {
+        assert_coi(&[(3, 1, 2), (4, 1, 2), (5, 3, 4)], 5, 0.25);
+    }
+
+    #[test]
+    fn half_siblings() {
+        assert_coi(&[(4, 1, 2), (5, 1, 3), (6, 4, 5)], 6, 0.125);
+    }
+
+    #[test]
+    fn parent_and_offspring() {
+        assert_coi(&[(3, 1, 2), (4, 3, 1)], 4, 0.25);
+    }
+
+    #[test]
+    fn first_cousins() {
+        assert_coi(
+            &[(3, 1, 2), (4, 1, 2), (6, 3, 5), (8, 4, 7), (9, 6, 8)],
+            9,
+            0.0625,
+        );
+    }
+
+    #[test]
+    fn double_first_cousins() {
+        assert_coi(
+            &[
+                (3, 1, 2),
+                (4, 1, 2),
+                (7, 5, 6),
+                (8, 5, 6),
+                (9, 3, 7),
+                (10, 4, 8),
+                (11, 9, 10),
+            ],
+            11,
+            0.125,
+        );
+    }
+
+    #[test]
+    fn repeated_full_sibling_mating() {
+        let trios = [(3, 1, 2), (4, 1, 2), (5, 3, 4), (6, 3, 4), (7, 5, 6)];
+        assert_coi(&trios, 5, 0.25);
+        assert_coi(&trios, 7, 0.375);
+    }
+
+    #[test]
+    fn inbred_common_ancestor() {
+        // 5 is the offspring of full siblings and is the common grandparent of 10.
+        assert_coi(
+            &[
+                (3, 1, 2),
+                (4, 1, 2),
+                (5, 3, 4),
+                (8, 5, 6),
+                (9, 5, 7),
+                (10, 8, 9),
+            ],
+            10,
+            0.125 * 1.25,
+        );
+    }
+
+    #[test]
+    fn late_parents_invalidate_cache() {
+        let mut g = pedigree(&[(5, 3, 4)]);
+        assert_eq!(g.inbreeding(trout(5)), 0.0);
+        g.add_edge(trout(3), trout(1));
+        g.add_edge(trout(3), trout(2));
+        g.add_edge(trout(4), trout(1));
+        g.add_edge(trout(4), trout(2));
+        assert_eq!(g.inbreeding(trout(5)), 0.25);
+    }
+
+    #[test]
+    fn view_agrees_without_caching() {
+        let trios = [(3, 1, 2), (4, 1, 2), (5, 3, 4), (6, 3, 4), (7, 5, 6)];
+        let mut g = pedigree(&trios);
+        // Half of the pedigree is already cached, and the view computes the rest.
+        g.inbreeding(trout(5));
+        let cached = g.memo.kinships.len();
+        let mut view = g.view();
+        let coancestry = view.coancestry(trout(5), trout(6));
+        assert_eq!(coancestry.coi, 0.375);
+        assert_eq!(g.memo.kinships.len(), cached);
+        assert_eq!(g.inbreeding(trout(7)), coancestry.coi);
+    }
+
+    fn random_trios(
+        rng: &mut impl rand::Rng,
+        founders: u32,
+        children: u32,
+    ) -> Vec<(u32, u32, u32)> {
+        use rand::seq::SliceRandom as _;
+        ((founders + 1)..=(founders + children))
+            .map(|child| {
+                let candidates: Vec<u32> = (1..child).collect();
+                let mut parents = candidates.choose_multiple(rng, 2);
+                (child, *parents.next().unwrap(), *parents.next().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_pedigrees() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..50 {
+            let founders = rand::Rng::gen_range(&mut rng, 2..6);
+            let trios = random_trios(&mut rng, founders, 20);
+            let mut g = pedigree(&trios);
+            for &(child, left, right) in trios.iter() {
+                let coi = g.inbreeding(trout(child));
+                assert!((0.0..1.0).contains(&coi), "COI out of range: {coi}");
+                let expected = wright_inbreeding(&g.graph, trout(child));
+                assert!(
+                    (coi - expected).abs() < 1e-12,
+                    "expected COI {expected}, got {coi}"
+                );
+                assert_eq!(g.view().kinship(trout(left), trout(right)), coi);
+                assert_eq!(g.view().kinship(trout(right), trout(left)), coi);
+                if left <= founders && right <= founders {
+                    assert_eq!(coi, 0.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn coancestry_of_half_siblings() {
+        // 4 and 5 share 1 as a parent; 1's parents 6 and 7 are reached only through 1.
+        let g = pedigree(&[(1, 6, 7), (4, 1, 2), (5, 1, 3)]);
+        let coancestry = g.view().coancestry(trout(4), trout(5));
+        assert_eq!(coancestry.coi, 0.125);
+        assert_eq!(
+            coancestry.ancestors,
+            vec![AncestorContribution {
+                id: trout(1),
+                contribution: 0.125
+            }]
+        );
+    }
+
+    #[test]
+    fn random_coancestries() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..50 {
+            let founders = rand::Rng::gen_range(&mut rng, 2..6);
+            let trios = random_trios(&mut rng, founders, 20);
+            let g = pedigree(&trios);
+            let (a, b) = (trout(founders + 19), trout(founders + 20));
+            let expected = wright_contributions(&g.graph, a, b);
+            let coancestry = g.view().coancestry(a, b);
+            assert!((coancestry.coi - expected.values().sum::<f64>()).abs() < 1e-12);
+            assert_eq!(coancestry.ancestors.len(), expected.len());
+            for AncestorContribution { id, contribution } in coancestry.ancestors {
+                assert!((contribution - expected[&id]).abs() < 1e-12);
+            }
+        }
+    }
 }
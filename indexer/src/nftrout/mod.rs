@@ -1,32 +1,33 @@
 pub mod algo;
 pub mod names;
+mod rpc;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use async_stream::stream;
 use ethers::{
     contract::EthLogDecode as _,
-    providers::{Http, Middleware, Provider},
-    types::{Address, BlockNumber, Filter, Log, ValueOrArray, U256},
+    providers::{Middleware, Provider},
+    types::{Address, BlockNumber, Filter, Log, ValueOrArray, H256, U256},
 };
 use futures::{future::BoxFuture, FutureExt as _, Stream};
 use serde::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
 use tracing::{error, trace, warn};
 
-use crate::{
-    ipfs::Cid,
-    utils::{retry, retry_if},
-};
+use self::rpc::FailoverHttp;
+use crate::{conf::ChainConfig, ipfs::Cid, utils::retry};
 
 pub const CURRENT_VERSION: u32 = 3;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("contract call error: {0}")]
-    Contract(#[from] ethers::contract::ContractError<Provider<Http>>),
+    Contract(#[from] ethers::contract::ContractError<Provider<FailoverHttp>>),
     #[error("provider error: {0}")]
     Provider(#[from] ethers::providers::ProviderError),
+    #[error("block {0} is not available")]
+    MissingBlock(u64),
 }
 
 ethers::contract::abigen!(NFTrout, "src/nftrout/abi.json");
@@ -58,6 +59,119 @@ pub struct TokenForUi {
     pub parents: Option<(TroutId, TroutId)>,
     #[serde(skip_serializing_if = "is_false")]
     pub pending: bool,
+    pub state: Lifecycle,
+    /// The Unix timestamp of the block in which the token was minted, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minted_at: Option<u64>,
+}
+
+/// The stage of a token between being spawned and having its latest metadata indexed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Lifecycle {
+    /// Spawned, but the off-chain worker has not yet had its result accepted.
+    Incubating,
+    /// Incubated, but its metadata has not yet been fetched by the indexer.
+    AwaitingMetadata,
+    #[default]
+    Indexed,
+    /// Indexed with metadata older than [`CURRENT_VERSION`].
+    OutdatedVersion,
+}
+
+impl Lifecycle {
+    pub fn new(version: Option<TokenVersion>, incubated: bool) -> Self {
+        match version {
+            Some(version) if version < CURRENT_VERSION => Self::OutdatedVersion,
+            Some(_) => Self::Indexed,
+            None if incubated => Self::AwaitingMetadata,
+            None => Self::Incubating,
+        }
+    }
+}
+
+/// The progress of a token through its [`Lifecycle`]. Times are Unix timestamps of the blocks in
+/// which each stage happened, or of when the indexer observed it if the block's timestamp is not
+/// yet known, and are unknown for stages that predate their tracking.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenLifecycle {
+    pub id: TokenId,
+    pub state: Lifecycle,
+    pub spawned_at: Option<u64>,
+    pub incubated_at: Option<u64>,
+    pub indexed_at: Option<u64>,
+    /// The number of seconds since the token entered its current state, if it is not indexed.
+    pub waiting_secs: Option<u64>,
+}
+
+/// A version of a token's metadata.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Generation {
+    /// The position of the generation, starting from zero for the original metadata.
+    pub ord: u32,
+    pub cid: Cid,
+    pub pinned: bool,
+}
+
+/// The stud fees paid to an owner, and how they compare with the contract's records.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnerEarnings {
+    /// The total of the stud fees paid to the owner by other breeders.
+    pub accrued: U256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reconciliation: Option<EarningsReconciliation>,
+    pub payments: Vec<StudPayment>,
+}
+
+/// A snapshot of an owner's earnings in the index and in the contract at the same block.
+/// The contract's balance is net of the matchmaking fee and of any withdrawals, so it is
+/// normally less than what has accrued.
+///
+/// The contract's `withdraw` emits no event, so withdrawals are not indexed and the amount
+/// withdrawn cannot be told apart from the matchmaking fee or from fees missing from the index.
+/// Only a balance exceeding what has accrued is known to be a discrepancy.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EarningsReconciliation {
+    pub block: u64,
+    pub accrued: U256,
+    /// The contract's `earnings` of the owner, which excludes anything already withdrawn.
+    pub withdrawable: U256,
+    pub reconciled_at: u64,
+}
+
+/// The fee paid to the owner of a stud for its use in a breeding.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StudPayment {
+    pub block: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    pub stud: TroutId,
+    pub child: TroutId,
+    pub breeder: Address,
+    pub fee: U256,
+}
+
+/// Marketplace statistics for a chain. Breeds, volume, and top studs cover only the requested
+/// window, while the listing figures are current.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarketStats {
+    pub total_trout: u64,
+    pub listed_studs: u64,
+    pub floor_fee: Option<U256>,
+    pub median_fee: Option<U256>,
+    pub max_fee: Option<U256>,
+    pub breeds: u64,
+    /// The total of the stud fees paid by breeders.
+    pub volume: U256,
+    pub top_studs: Vec<StudEarnings>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StudEarnings {
+    pub id: TroutId,
+    pub earnings: U256,
+    /// The number of breedings for which the stud's fee was paid.
+    pub paid_breeds: u64,
 }
 
 fn is_false(tf: &bool) -> bool {
@@ -68,6 +182,9 @@ fn is_false(tf: &bool) -> bool {
 pub struct EventForUi {
     pub id: TroutId,
     pub block: u64,
+    /// The Unix timestamp of the block, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
     #[serde(flatten)]
     pub kind: EventKindForUi,
 }
@@ -75,6 +192,7 @@ pub struct EventForUi {
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase", tag = "kind")]
 pub enum EventKindForUi {
+    /// The token was used as a parent.
     Breed {
         breeder: Address,
         child: TroutId,
@@ -82,6 +200,17 @@ pub enum EventKindForUi {
         price: U256,
         owner: Address,
     },
+    Mint {
+        to: Address,
+    },
+    Transfer {
+        from: Address,
+        to: Address,
+    },
+    Listed {
+        fee: U256,
+    },
+    Delisted,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
@@ -139,49 +268,27 @@ pub struct TroutId {
 pub struct Client {
     addr: Address,
     chain: ChainId,
-    inner: NFTrout<Provider<Http>>,
-    provider: Arc<Provider<Http>>,
+    inner: NFTrout<Provider<FailoverHttp>>,
+    provider: Arc<Provider<FailoverHttp>>,
     block: BlockNumber,
+    polling_interval: Duration,
+    confirmations: u64,
 }
 
 impl Client {
-    pub fn sapphire_mainnet() -> Self {
-        Self::from_static(
-            0x5afe,
-            "0x998633BDF6eE32A9CcA6c9A247F428596e8e65d8",
-            "https://sapphire2.oasis.io",
-        )
-    }
-
-    pub fn sapphire_testnet() -> Self {
-        Self::from_static(
-            0x5aff,
-            "0xF8E3DE55D24D13607A12628E0A113B66BA578bDC",
-            "https://testnet.sapphire.oasis.dev",
-        )
-    }
-
-    pub fn local() -> Self {
-        Self::from_static(
-            31337,
-            "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512",
-            "http://127.0.0.1:8545",
-        )
-    }
-
-    fn from_static(chain: ChainId, addr: &'static str, gw_url: &'static str) -> Self {
-        Self::new(chain, addr.parse().unwrap(), gw_url.parse().unwrap())
-    }
-
-    pub fn new(chain: ChainId, addr: Address, gw_url: url::Url) -> Self {
-        let polling_interval = std::time::Duration::from_millis(3000);
-        let provider = Arc::new(Provider::new(Http::new(gw_url)).interval(polling_interval));
+    pub fn new(config: &ChainConfig) -> Self {
+        let provider = Arc::new(
+            Provider::new(FailoverHttp::new(config.rpc_urls.iter().cloned()))
+                .interval(config.polling_interval),
+        );
         Self {
-            chain,
-            addr,
-            inner: NFTrout::new(addr, provider.clone()),
+            chain: config.chain_id,
+            addr: config.contract,
+            inner: NFTrout::new(config.contract, provider.clone()),
             provider,
             block: BlockNumber::Latest,
+            polling_interval: config.polling_interval,
+            confirmations: config.confirmations,
         }
     }
 
@@ -263,12 +370,91 @@ impl Client {
             .collect())
     }
 
+    /// Returns the total that `breeder` must pay to breed the pair, including the mint reward.
+    pub async fn breeding_fee(
+        &self,
+        breeder: Address,
+        left: TokenId,
+        right: TokenId,
+    ) -> Result<U256, Error> {
+        Ok(self
+            .inner
+            .get_breeding_fee(breeder, left.into(), right.into())
+            .block(self.block)
+            .call()
+            .await?)
+    }
+
+    /// Returns the owner's unwithdrawn stud fees, less the matchmaking fee.
+    pub async fn earnings(&self, owner: Address) -> Result<U256, Error> {
+        Ok(self.inner.earnings(owner).block(self.block).call().await?)
+    }
+
+    pub async fn matchmaking_bps(&self) -> Result<U256, Error> {
+        Ok(self
+            .inner
+            .matchmaking_bps()
+            .block(self.block)
+            .call()
+            .await?)
+    }
+
+    pub async fn mint_reward(&self) -> Result<U256, Error> {
+        Ok(self.inner.mint_reward().block(self.block).call().await?)
+    }
+
+    pub async fn contract_state(&self) -> Result<ContractState, Error> {
+        let matchmaking_bps = self.inner.matchmaking_bps().block(self.block);
+        let mint_reward = self.inner.mint_reward().block(self.block);
+        let paused = self.inner.paused().block(self.block);
+        let task_hub = self.inner.task_hub().block(self.block);
+        let task_acceptor = self.inner.task_acceptor().block(self.block);
+        let owner = self.inner.owner().block(self.block);
+        let state = tokio::try_join!(
+            matchmaking_bps.call(),
+            mint_reward.call(),
+            paused.call(),
+            task_hub.call(),
+            task_acceptor.call(),
+            owner.call(),
+        )?;
+        Ok(ContractState {
+            matchmaking_bps: state.0,
+            mint_reward: state.1,
+            paused: state.2,
+            task_hub: state.3,
+            task_acceptor: state.4,
+            owner: state.5,
+        })
+    }
+
     pub fn chain_id(&self) -> ChainId {
         self.chain
     }
 
+    /// Returns the latest block having the configured number of confirmations.
     pub async fn latest_block(&self) -> Result<u64, Error> {
-        Ok(self.provider.get_block_number().await?.as_u64())
+        Ok(self
+            .provider
+            .get_block_number()
+            .await?
+            .as_u64()
+            .saturating_sub(self.confirmations))
+    }
+
+    pub async fn block_header(&self, block_number: u64) -> Result<Option<BlockHeader>, Error> {
+        Ok(self
+            .provider
+            .get_block(block_number)
+            .await?
+            .and_then(|block| {
+                Some(BlockHeader {
+                    number: block.number?.as_u64(),
+                    hash: block.hash?,
+                    parent_hash: block.parent_hash,
+                    timestamp: block.timestamp.low_u64(),
+                })
+            }))
     }
 
     pub fn events(
@@ -277,20 +463,48 @@ impl Client {
         stop_block: Option<u64>,
     ) -> impl Stream<Item = BoxFuture<SmallVec<[Event; 4]>>> {
         stream!({
-            for await block in self.blocks(start_block).await {
-                yield self.get_block_events(block, self.addr).boxed();
-                yield futures::future::ready(smallvec![Event::ProcessedBlock(block)]).boxed();
-                if Some(block) == stop_block {
-                    break;
+            let init_block = retry(|| self.latest_block()).await;
+            // Blocks near the tip are fetched one at a time so that their hashes are recorded.
+            let ranged_end = stop_block
+                .map_or(init_block, |stop| stop.min(init_block))
+                .saturating_sub(UNRANGED_TIP_BLOCKS);
+            let mut next_block = start_block;
+            let mut range = LogRange::default();
+            while next_block <= ranged_end {
+                let to_block = ranged_end.min(next_block + range.size - 1);
+                match self.get_range_events(next_block, to_block, self.addr).await {
+                    Ok(mut events) => {
+                        if events.is_empty() {
+                            range.grow();
+                        }
+                        events.push(Event::ProcessedBlock(to_block));
+                        yield futures::future::ready(events).boxed();
+                        next_block = to_block + 1;
+                    }
+                    Err(e) if range.size > LogRange::MIN && is_range_limit_error(&e) => {
+                        range.shrink();
+                        trace!(size = range.size, "shrunk log range: {e}");
+                    }
+                    Err(e) => {
+                        warn!("failed to get logs: {e}");
+                        tokio::time::sleep(Duration::from_millis(1500)).await;
+                    }
+                }
+            }
+            if stop_block.map_or(true, |stop| next_block <= stop) {
+                for await block in self.blocks(next_block).await {
+                    yield self.get_block_events(block, self.addr).boxed();
+                    yield futures::future::ready(smallvec![Event::ProcessedBlock(block)]).boxed();
+                    if Some(block) == stop_block {
+                        break;
+                    }
                 }
             }
         })
     }
 
     async fn blocks(&self, start_block: u64) -> impl Stream<Item = u64> + '_ {
-        let init_block =
-            retry(|| async { Ok::<_, Error>(self.provider.get_block_number().await?.as_u64()) })
-                .await;
+        let init_block = retry(|| self.latest_block()).await;
         stream!({
             let mut current_block = start_block;
             loop {
@@ -307,83 +521,709 @@ impl Client {
 
     async fn wait_for_block(&self, block_number: u64) {
         trace!(block = block_number, "waiting for block");
-        retry_if(
-            || async { Ok::<_, Error>(self.provider.get_block_number().await?.as_u64()) },
-            |num| (num >= block_number).then_some(num),
-        )
-        .await;
+        loop {
+            match self.latest_block().await {
+                Ok(num) if num >= block_number => break,
+                Ok(_) => {}
+                Err(e) => warn!("failed: {e}"),
+            }
+            tokio::time::sleep(self.polling_interval).await;
+        }
         trace!(block = block_number, "waited for block");
     }
 
     async fn get_block_events(&self, block_number: u64, addr: Address) -> SmallVec<[Event; 4]> {
-        retry(move || {
-            let provider = self.provider.clone();
+        // The header and logs are fetched together so that a block orphaned between the two
+        // requests is retried against whichever block is canonical at that height.
+        let (header, logs) = retry(move || async move {
+            let header = match self.block_header(block_number).await? {
+                Some(header) => header,
+                None => return Err(Error::MissingBlock(block_number)),
+            };
             let filter = Filter::new()
-                .select(block_number)
+                .at_block_hash(header.hash)
                 .address(ValueOrArray::Value(addr));
-            async move { provider.get_logs(&filter).await }
+            Ok::<_, Error>((header, self.provider.get_logs(&filter).await?))
         })
-        .await
-        .into_iter()
-        .filter_map(|log| self.decode_log(log))
-        .collect::<SmallVec<[Event; 4]>>()
+        .await;
+        std::iter::once(Event::Block(header))
+            .chain(logs.into_iter().flat_map(decode_log))
+            .collect::<SmallVec<[Event; 4]>>()
+    }
+
+    async fn get_range_events(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        addr: Address,
+    ) -> Result<SmallVec<[Event; 4]>, Error> {
+        trace!(from = from_block, to = to_block, "fetching logs");
+        let filter = Filter::new()
+            .from_block(from_block)
+            .to_block(to_block)
+            .address(ValueOrArray::Value(addr));
+        Ok(self
+            .provider
+            .get_logs(&filter)
+            .await?
+            .into_iter()
+            .flat_map(decode_log)
+            .collect())
     }
+}
 
-    fn decode_log(&self, log: Log) -> Option<Event> {
-        if log.block_number.is_none() || log.log_index.is_none() || log.removed == Some(true) {
-            return None;
+/// Decodes a contract log into the events it describes. Most logs describe a single event,
+/// but an ERC-2309 `ConsecutiveTransfer` describes one per token in its range.
+fn decode_log(log: Log) -> SmallVec<[Event; 1]> {
+    let (Some(block), Some(log_index)) = (log.block_number, log.log_index) else {
+        return SmallVec::new();
+    };
+    if log.removed == Some(true) {
+        return SmallVec::new();
+    }
+    let (block, log_index) = (block.as_u64(), log_index.as_u64());
+    let raw_log = (log.topics, log.data.to_vec()).into();
+    let event = match NFTroutEvents::decode_log(&raw_log) {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("failed to decode log: {e}");
+            return SmallVec::new();
         }
-        let raw_log = (log.topics, log.data.to_vec()).into();
-        let event = NFTroutEvents::decode_log(&raw_log)
-            .map_err(|e| warn!("failed to decode log: {e}"))
-            .ok()?;
-        let (token, kind) = match event {
-            NFTroutEvents::DelistedFilter(f) => {
-                (f.token_id.as_u32(), TokenEventKind::Relisted { fee: None })
-            }
-            NFTroutEvents::ListedFilter(f) => (
-                f.token_id.as_u32(),
-                TokenEventKind::Relisted { fee: Some(f.fee) },
-            ),
-            NFTroutEvents::TransferFilter(f) => (
-                f.token_id.as_u32(),
-                if f.from.is_zero() {
-                    TokenEventKind::Spawned { to: f.to }
-                } else {
-                    TokenEventKind::Transfer {
-                        from: f.from,
-                        to: f.to,
-                    }
-                },
-            ),
-            _ => return None,
-        };
-        Some(Event::Token(TokenEvent {
+    };
+    let token_event = |token, kind, batch_index| {
+        Event::Token(TokenEvent {
             token,
             kind,
-            block: log.block_number.unwrap().as_u64(),
-            log_index: log.log_index.unwrap().as_u64(),
-        }))
+            block,
+            log_index,
+            batch_index,
+        })
+    };
+    let transfer = |from: Address, to: Address| {
+        if from.is_zero() {
+            TokenEventKind::Spawned { to }
+        } else {
+            TokenEventKind::Transfer { from, to }
+        }
+    };
+    let contract_event = |kind| {
+        smallvec![Event::Contract(ContractEvent {
+            kind,
+            block,
+            log_index,
+        })]
+    };
+    let (token, kind) = match event {
+        NFTroutEvents::MatchmakingFeeChangedFilter(f) => {
+            return contract_event(ContractEventKind::MatchmakingFeeChanged {
+                bps: f.matchmaking_bps,
+            })
+        }
+        NFTroutEvents::MintRewardChangedFilter(f) => {
+            return contract_event(ContractEventKind::MintRewardChanged {
+                reward: f.mint_reward,
+            })
+        }
+        NFTroutEvents::PausedFilter(f) => {
+            return contract_event(ContractEventKind::Paused { account: f.account })
+        }
+        NFTroutEvents::UnpausedFilter(f) => {
+            return contract_event(ContractEventKind::Unpaused { account: f.account })
+        }
+        NFTroutEvents::TaskAcceptorChangedFilter(f) => {
+            return contract_event(ContractEventKind::TaskAcceptorChanged { to: f.to })
+        }
+        NFTroutEvents::TaskHubChangedFilter(f) => {
+            return contract_event(ContractEventKind::TaskHubChanged { to: f.to })
+        }
+        NFTroutEvents::OwnershipTransferredFilter(f) => {
+            return contract_event(ContractEventKind::OwnershipTransferred {
+                from: f.previous_owner,
+                to: f.new_owner,
+            })
+        }
+        NFTroutEvents::ConsecutiveTransferFilter(f) => {
+            let (Ok(from_token), Ok(to_token)) =
+                (u32::try_from(f.from_token_id), u32::try_from(f.to_token_id))
+            else {
+                warn!(
+                    block,
+                    log_index,
+                    "skipping consecutive transfer of tokens {}..={} beyond u32",
+                    f.from_token_id,
+                    f.to_token_id
+                );
+                return SmallVec::new();
+            };
+            return (from_token..=to_token)
+                .zip(0..)
+                .map(|(token, batch_index)| token_event(token, transfer(f.from, f.to), batch_index))
+                .collect();
+        }
+        NFTroutEvents::DelistedFilter(f) => {
+            (f.token_id.as_u32(), TokenEventKind::Relisted { fee: None })
+        }
+        NFTroutEvents::SpawnedFilter(f) => (
+            f.child.as_u32(),
+            TokenEventKind::Bred {
+                left: f.left.as_u32(),
+                right: f.right.as_u32(),
+            },
+        ),
+        NFTroutEvents::IncubatedFilter(f) => (f.token_id.as_u32(), TokenEventKind::Incubated),
+        NFTroutEvents::ListedFilter(f) => (
+            f.token_id.as_u32(),
+            TokenEventKind::Relisted { fee: Some(f.fee) },
+        ),
+        NFTroutEvents::TransferFilter(f) => (f.token_id.as_u32(), transfer(f.from, f.to)),
+        _ => return SmallVec::new(),
+    };
+    smallvec![token_event(token, kind, 0)]
+}
+
+/// The number of blocks behind the tip below which logs are fetched in ranges.
+const UNRANGED_TIP_BLOCKS: u64 = 32;
+
+/// The adaptively sized block range of a historical logs query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct LogRange {
+    size: u64,
+}
+
+impl Default for LogRange {
+    fn default() -> Self {
+        Self { size: 100 }
+    }
+}
+
+impl LogRange {
+    const MIN: u64 = 1;
+    const MAX: u64 = 10_000;
+
+    fn grow(&mut self) {
+        self.size = (self.size * 2).min(Self::MAX);
+    }
+
+    fn shrink(&mut self) {
+        self.size = (self.size / 2).max(Self::MIN);
     }
 }
 
+/// The messages with which gateways refuse a logs query for spanning too many blocks or returning
+/// too many logs. Error codes are not used, since some providers share them with rate limiting.
+const RANGE_LIMIT_MESSAGES: &[&str] = &[
+    // Oasis Web3 Gateway
+    "max allowed of rounds in logs query",
+    // Geth, Infura
+    "query returned more than",
+    // Alchemy
+    "log response size exceeded",
+    // BSC, Ankr
+    "exceed maximum block range",
+    // Erigon, Nethermind, Anvil
+    "query exceeds max block range",
+    "block range is too wide",
+    "block range too large",
+];
+
+/// Returns whether the error is the gateway refusing a logs query for being too large.
+fn is_range_limit_error(e: &Error) -> bool {
+    let message = e.to_string().to_lowercase();
+    RANGE_LIMIT_MESSAGES.iter().any(|pat| message.contains(pat))
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Event {
     Token(TokenEvent),
+    Contract(ContractEvent),
+    /// The header of a block. Used to detect reorgs and to timestamp the block's events.
+    Block(BlockHeader),
     ProcessedBlock(u64),
 }
 
+/// A change to the configuration of the contract itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContractEvent {
+    pub kind: ContractEventKind,
+    pub block: u64,
+    pub log_index: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContractEventForUi {
+    pub block: u64,
+    /// The Unix timestamp of the block, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(flatten)]
+    pub kind: ContractEventKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum ContractEventKind {
+    MatchmakingFeeChanged { bps: U256 },
+    MintRewardChanged { reward: U256 },
+    Paused { account: Address },
+    Unpaused { account: Address },
+    TaskAcceptorChanged { to: Address },
+    TaskHubChanged { to: Address },
+    OwnershipTransferred { from: Address, to: Address },
+}
+
+/// The current configuration of the contract.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContractState {
+    pub matchmaking_bps: U256,
+    pub mint_reward: U256,
+    pub paused: bool,
+    pub task_hub: Address,
+    pub task_acceptor: Address,
+    pub owner: Address,
+}
+
+impl ContractState {
+    /// Returns the state left by the changes, oldest first, if every part of it has been changed.
+    pub fn from_changes<'a>(
+        changes: impl IntoIterator<Item = &'a ContractEventKind>,
+    ) -> Option<Self> {
+        let (mut bps, mut reward, mut paused, mut task_hub, mut task_acceptor, mut owner) =
+            Default::default();
+        for change in changes {
+            use ContractEventKind::*;
+            match *change {
+                MatchmakingFeeChanged { bps: b } => bps = Some(b),
+                MintRewardChanged { reward: r } => reward = Some(r),
+                Paused { .. } => paused = Some(true),
+                Unpaused { .. } => paused = Some(false),
+                TaskAcceptorChanged { to } => task_acceptor = Some(to),
+                TaskHubChanged { to } => task_hub = Some(to),
+                OwnershipTransferred { to, .. } => owner = Some(to),
+            }
+        }
+        Some(Self {
+            matchmaking_bps: bps?,
+            mint_reward: reward?,
+            paused: paused?,
+            task_hub: task_hub?,
+            task_acceptor: task_acceptor?,
+            owner: owner?,
+        })
+    }
+}
+
+/// The cost of a breeding, as charged by the contract's `breed` method.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BreedingQuote {
+    pub left: StudQuote,
+    pub right: StudQuote,
+    /// The reward paid to the contract owner for minting the child.
+    pub mint_reward: U256,
+    /// The total of the parents' stud fees.
+    pub stud_fee: U256,
+    /// The part of the stud fees taken by the contract owner.
+    pub matchmaking_fee: U256,
+    /// The value that must be sent to `breed`.
+    pub total: U256,
+    /// The contract's own `getBreedingFee`, if it was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub onchain_total: Option<U256>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StudQuote {
+    pub id: TroutId,
+    pub owner: Address,
+    /// The fee paid for the stud, which is zero if the breeder owns it.
+    pub fee: U256,
+    pub matchmaking_fee: U256,
+}
+
+/// A parent is neither listed nor owned by the breeder, so the contract would revert.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("trout {0} is not listed")]
+pub struct NotListed(pub TokenId);
+
+impl BreedingQuote {
+    /// Computes the quote the way the contract does, given each parent's owner and listed fee.
+    pub fn new(
+        breeder: Address,
+        left: (TroutId, Address, Option<U256>),
+        right: (TroutId, Address, Option<U256>),
+        mint_reward: U256,
+        matchmaking_bps: U256,
+    ) -> Result<Self, NotListed> {
+        let stud = |(id, owner, listed_fee): (TroutId, Address, Option<U256>)| {
+            let fee = if id.token_id == 0 || owner == breeder {
+                U256::zero()
+            } else {
+                listed_fee.ok_or(NotListed(id.token_id))?
+            };
+            Ok(StudQuote {
+                id,
+                owner,
+                fee,
+                matchmaking_fee: fee * matchmaking_bps / 10_000,
+            })
+        };
+        let (left, right) = (stud(left)?, stud(right)?);
+        let stud_fee = left.fee + right.fee;
+        Ok(Self {
+            mint_reward,
+            stud_fee,
+            matchmaking_fee: left.matchmaking_fee + right.matchmaking_fee,
+            total: mint_reward + stud_fee,
+            onchain_total: None,
+            left,
+            right,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub number: u64,
+    pub hash: H256,
+    pub parent_hash: H256,
+    pub timestamp: u64,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct TokenEvent {
     pub token: TokenId,
     pub kind: TokenEventKind,
     pub block: u64,
     pub log_index: u64,
+    /// The position of the event among those decoded from the same log.
+    pub batch_index: u32,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TokenEventKind {
-    Relisted { fee: Option<U256> },
-    Spawned { to: Address },
-    Transfer { from: Address, to: Address },
+    Relisted {
+        fee: Option<U256>,
+    },
+    Spawned {
+        to: Address,
+    },
+    Transfer {
+        from: Address,
+        to: Address,
+    },
+    /// The token was bred from the parents, which are on the same chain.
+    Bred {
+        left: TokenId,
+        right: TokenId,
+    },
+    /// The off-chain worker's result for the token was accepted.
+    Incubated,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breeding_quote() {
+        let (breeder, other): (Address, Address) =
+            (Address::repeat_byte(1), Address::repeat_byte(2));
+        let trout = |token_id| TroutId {
+            chain_id: 31337,
+            token_id,
+        };
+        let quote = BreedingQuote::new(
+            breeder,
+            (trout(1), other, Some(1_000.into())),
+            (trout(2), breeder, None),
+            5.into(),
+            250.into(),
+        )
+        .unwrap();
+        assert_eq!(quote.left.fee, 1_000.into());
+        assert_eq!(quote.left.matchmaking_fee, 25.into());
+        assert_eq!(quote.right.fee, 0.into());
+        assert_eq!(quote.stud_fee, 1_000.into());
+        assert_eq!(quote.matchmaking_fee, 25.into());
+        assert_eq!(quote.total, 1_005.into());
+
+        assert_eq!(
+            BreedingQuote::new(
+                breeder,
+                (trout(1), other, Some(1_000.into())),
+                (trout(3), other, None),
+                5.into(),
+                250.into(),
+            ),
+            Err(NotListed(3))
+        );
+    }
+
+    #[test]
+    fn log_range_bounds() {
+        let mut range = LogRange::default();
+        for _ in 0..64 {
+            range.grow();
+        }
+        assert_eq!(range.size, LogRange::MAX);
+        for _ in 0..64 {
+            range.shrink();
+        }
+        assert_eq!(range.size, LogRange::MIN);
+    }
+
+    #[test]
+    fn contract_state_from_changes() {
+        use ContractEventKind::*;
+        let (owner, account) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let mut changes = vec![
+            MatchmakingFeeChanged { bps: 100.into() },
+            MintRewardChanged { reward: 1.into() },
+            MatchmakingFeeChanged { bps: 200.into() },
+            Paused { account },
+            TaskHubChanged { to: account },
+            TaskAcceptorChanged { to: account },
+        ];
+        assert_eq!(ContractState::from_changes(&changes), None);
+        changes.extend([
+            Unpaused { account },
+            OwnershipTransferred {
+                from: Address::zero(),
+                to: owner,
+            },
+        ]);
+        assert_eq!(
+            ContractState::from_changes(&changes),
+            Some(ContractState {
+                matchmaking_bps: 200.into(),
+                mint_reward: 1.into(),
+                paused: false,
+                task_hub: account,
+                task_acceptor: account,
+                owner,
+            })
+        );
+    }
+
+    #[test]
+    fn range_limit_errors() {
+        let limited = |message: &str| {
+            is_range_limit_error(&Error::Provider(
+                ethers::providers::ProviderError::CustomError(message.into()),
+            ))
+        };
+        assert!(limited("max allowed of rounds in logs query is: 100"));
+        assert!(limited(
+            "query returned more than 10000 results: Too many results"
+        ));
+        assert!(limited("exceed maximum block range: 5000"));
+        assert!(limited(
+            "Log response size exceeded. You can make eth_getLogs requests with up to a 2K block \
+             range and no limit on the response size"
+        ));
+        assert!(!limited("connection refused"));
+        assert!(!limited("429 Too Many Requests: rate limit exceeded"));
+        assert!(!limited("project ID request rate exceeded"));
+        assert!(!limited("block number out of range"));
+    }
+
+    fn log(topics: Vec<H256>, data: Vec<ethers::abi::Token>, log_index: u64) -> Log {
+        Log {
+            topics,
+            data: ethers::abi::encode(&data).into(),
+            block_number: Some(7.into()),
+            log_index: Some(log_index.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn contract_events() {
+        use ethers::{abi::Token, contract::EthEvent as _};
+
+        let account: Address = rand::random();
+        let paused = log(
+            vec![PausedFilter::signature()],
+            vec![Token::Address(account)],
+            1,
+        );
+        assert_eq!(
+            decode_log(paused).into_vec(),
+            [Event::Contract(ContractEvent {
+                kind: ContractEventKind::Paused { account },
+                block: 7,
+                log_index: 1,
+            })]
+        );
+
+        let fee_changed = log(
+            vec![MatchmakingFeeChangedFilter::signature()],
+            vec![Token::Uint(250.into())],
+            2,
+        );
+        assert_eq!(
+            decode_log(fee_changed).into_vec(),
+            [Event::Contract(ContractEvent {
+                kind: ContractEventKind::MatchmakingFeeChanged { bps: 250.into() },
+                block: 7,
+                log_index: 2,
+            })]
+        );
+    }
+
+    #[test]
+    fn consecutive_transfer_fan_out() {
+        use ethers::{abi::Token, contract::EthEvent as _};
+
+        // The genesis batch mint of a local deployment.
+        let minter: Address = rand::random();
+        let batch_mint = log(
+            vec![
+                ConsecutiveTransferFilter::signature(),
+                H256::from_low_u64_be(1),
+                H256::zero(),
+                minter.into(),
+            ],
+            vec![Token::Uint(10.into())],
+            3,
+        );
+        let events = decode_log(batch_mint);
+        assert!(events.spilled());
+        assert_eq!(
+            events.into_vec(),
+            (1..=10)
+                .zip(0..)
+                .map(|(token, batch_index)| Event::Token(TokenEvent {
+                    token,
+                    kind: TokenEventKind::Spawned { to: minter },
+                    block: 7,
+                    log_index: 3,
+                    batch_index,
+                }))
+                .collect::<Vec<_>>()
+        );
+
+        let recipient: Address = rand::random();
+        let batch_transfer = log(
+            vec![
+                ConsecutiveTransferFilter::signature(),
+                H256::from_low_u64_be(4),
+                minter.into(),
+                recipient.into(),
+            ],
+            vec![Token::Uint(4.into())],
+            0,
+        );
+        assert_eq!(
+            decode_log(batch_transfer).into_vec(),
+            [Event::Token(TokenEvent {
+                token: 4,
+                kind: TokenEventKind::Transfer {
+                    from: minter,
+                    to: recipient,
+                },
+                block: 7,
+                log_index: 0,
+                batch_index: 0,
+            })]
+        );
+
+        let out_of_range = log(
+            vec![
+                ConsecutiveTransferFilter::signature(),
+                H256::from_low_u64_be(1),
+                H256::zero(),
+                minter.into(),
+            ],
+            vec![Token::Uint(U256::from(u32::MAX) + 1)],
+            1,
+        );
+        assert!(decode_log(out_of_range).is_empty());
+    }
+
+    #[test]
+    fn consecutive_transfer_recorded() {
+        use ethers::{abi::Token, contract::EthEvent as _};
+
+        let (minter, bob, carol): (Address, Address, Address) =
+            (rand::random(), rand::random(), rand::random());
+        let token = |id: u64| H256::from_low_u64_be(id);
+        // A token of the batch mint is listed, sold to Bob and then used as a stud by Carol.
+        let logs = [
+            log(
+                vec![
+                    ConsecutiveTransferFilter::signature(),
+                    token(1),
+                    H256::zero(),
+                    minter.into(),
+                ],
+                vec![Token::Uint(10.into())],
+                0,
+            ),
+            log(
+                vec![ListedFilter::signature(), token(3)],
+                vec![Token::Uint(0x10.into())],
+                1,
+            ),
+            log(
+                vec![
+                    TransferFilter::signature(),
+                    minter.into(),
+                    bob.into(),
+                    token(3),
+                ],
+                vec![],
+                2,
+            ),
+            log(
+                vec![
+                    TransferFilter::signature(),
+                    H256::zero(),
+                    carol.into(),
+                    token(11),
+                ],
+                vec![],
+                3,
+            ),
+            log(
+                vec![SpawnedFilter::signature(), token(3), token(4)],
+                vec![Token::Uint(11.into())],
+                4,
+            ),
+        ];
+        let events = logs.into_iter().flat_map(decode_log).collect::<Vec<_>>();
+
+        let chain = 31337;
+        let db = crate::db::Db::open_for_test().unwrap();
+        db.with_tx(|tx| tx.record_events(chain, events.iter()))
+            .unwrap();
+        db.with_conn(|conn| {
+            assert_eq!(
+                conn.tokens_changed_since(chain, 0)?,
+                (1..=11).collect::<Vec<_>>()
+            );
+            let stud = TroutId {
+                chain_id: chain,
+                token_id: 3,
+            };
+            let (feed, _) = conn.token_events(stud, None, None)?;
+            let kinds = feed.into_iter().map(|e| e.kind).collect::<Vec<_>>();
+            assert!(
+                matches!(
+                    kinds.as_slice(),
+                    [
+                        EventKindForUi::Mint { to: minted },
+                        EventKindForUi::Listed { fee },
+                        EventKindForUi::Transfer { from, to },
+                        EventKindForUi::Breed { owner, breeder, price, .. },
+                    ] if *minted == minter && *fee == 0x10.into() && (*from, *to) == (minter, bob)
+                        && (*owner, *breeder, *price) == (bob, carol, 0x10.into())
+                ),
+                "{kinds:?}"
+            );
+            assert_eq!(conn.stud_earnings(chain, &bob)?, 0x10.into());
+            assert_eq!(conn.stud_earnings(chain, &minter)?, 0.into());
+            Ok(())
+        })
+        .unwrap();
+    }
 }
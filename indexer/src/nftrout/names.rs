@@ -1,10 +1,16 @@
 use ethers::{
     middleware::contract::{Eip712, EthAbiType},
-    types::{transaction::eip712::Eip712, Address, Signature},
+    types::{
+        transaction::eip712::{EIP712Domain, Eip712},
+        Address, Signature,
+    },
+    utils::keccak256,
 };
 
 use super::*;
 
+/// A request signed by a trout's owner to rename it. The derived domain's `chain_id` is
+/// replaced by that of the chain on which the trout lives when hashing.
 #[derive(Clone, Default, EthAbiType, Eip712)]
 #[eip712(
     name = "NameRequest",
@@ -25,11 +31,34 @@ impl NameRequest {
         }
     }
 
-    pub fn hash(&self) -> [u8; 32] {
-        self.encode_eip712().unwrap()
+    pub fn hash(&self, chain_id: ChainId) -> [u8; 32] {
+        let domain = EIP712Domain {
+            chain_id: Some(chain_id.into()),
+            ..self.domain().unwrap()
+        };
+        keccak256(
+            [
+                &[0x19, 0x01][..],
+                &domain.separator(),
+                &self.struct_hash().unwrap(),
+            ]
+            .concat(),
+        )
     }
 
-    pub fn verify(&self, sig: &Signature, whom: Address) -> bool {
-        sig.verify(self.hash(), whom).is_ok()
+    pub fn verify(&self, chain_id: ChainId, sig: &Signature, whom: Address) -> bool {
+        sig.verify(self.hash(chain_id), whom).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_matches_derived_domain() {
+        let req = NameRequest::new(42, "Trouty McTroutface".into());
+        assert_eq!(req.hash(23294), req.encode_eip712().unwrap());
+        assert_ne!(req.hash(23295), req.encode_eip712().unwrap());
     }
 }
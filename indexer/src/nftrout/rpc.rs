@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ethers::providers::{Http, HttpClientError, JsonRpcClient, RpcError as _};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
+
+/// A JSON-RPC transport that moves on to the next gateway whenever the current one is unreachable.
+#[derive(Debug)]
+pub struct FailoverHttp {
+    transports: Vec<Http>,
+    current: AtomicUsize,
+}
+
+impl FailoverHttp {
+    pub fn new(urls: impl IntoIterator<Item = url::Url>) -> Self {
+        let transports: Vec<_> = urls.into_iter().map(Http::new).collect();
+        assert!(!transports.is_empty(), "no RPC URLs");
+        Self {
+            transports,
+            current: Default::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl JsonRpcClient for FailoverHttp {
+    type Error = HttpClientError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: std::fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let first = self.current.load(Ordering::Relaxed);
+        let mut last_error = None;
+        for i in 0..self.transports.len() {
+            let index = (first + i) % self.transports.len();
+            match self.transports[index].request(method, &params).await {
+                Ok(res) => {
+                    self.current.store(index, Ordering::Relaxed);
+                    return Ok(res);
+                }
+                // The gateway is up, but rejected the request.
+                Err(e) if e.as_error_response().is_some() => return Err(e),
+                Err(e) => {
+                    warn!(gateway = index, "RPC request failed: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap())
+    }
+}